@@ -21,16 +21,26 @@
 #endif
 
 #include <atomic>
+#include <chrono>
+#include <condition_variable>
 #include <cstdint>
+#include <cstdio>
 #include <cstring>
 #include <exception>
 #include <functional>
 #include <memory>
 #include <mutex>
 #include <new>
+#include <optional>
 #include <stdexcept>
+#if __has_include(<stop_token>)
+#include <stop_token>
+#endif
+#include <string>
+#include <tuple>
 #include <type_traits>
 #include <utility>
+#include <vector>
 #include "rust/cxx.h"
 
 // Warning! Preprocessor abuse follows!
@@ -185,9 +195,20 @@ template <typename Future, bool YieldResultIsVoid, bool FinalResultIsVoid>
 class RustPromise;
 template <typename Future>
 class RustAwaiter;
+template <typename Future>
+class SpinAwait;
+template <typename Future>
+class RustStreamBatchAwaiter;
 
 struct RustExeclet;
 
+// The size and alignment of one value of a future or stream as Rust lays it
+// out. This must match `CxxAsyncResultLayout` in `repr.rs`.
+struct ResultLayout {
+  size_t size;
+  size_t align;
+};
+
 template <typename Future>
 struct Vtable {
   RustChannel<Future> (*channel)(RustExeclet* execlet);
@@ -199,6 +220,24 @@ struct Vtable {
   void (*sender_drop)(void* self);
   uint32_t (*future_poll)(Future& self, void* result, const void* waker_data);
   void (*future_drop)(Future&& self);
+  // Only used for streams, not futures.
+  uint32_t (*sender_send_batch)(
+      RustSender<Future>& self,
+      const void* values,
+      size_t count,
+      const void* waker_data);
+  void (*sender_on_cancel)(
+      RustSender<Future>& self,
+      void (*callback)(void* data, bool cancelled),
+      void* data);
+  uint32_t (*result_kind)();
+  // Null unless Rust packs this future's values into the return value of each
+  // poll. See `RustFutureReceiver::poll_future()`.
+  uint64_t (*future_poll_packed)(
+      Future& self,
+      void* result,
+      const void* waker_data);
+  ResultLayout (*result_layout)();
 };
 
 // Abstract CRTP base class for all futures.
@@ -241,6 +280,16 @@ class RustFuture {
     // Transfer ownership of the Rust future to the awaiter.
     return RustAwaiter(std::move(*static_cast<Derived*>(this)));
   }
+
+  // Returns an awaitable that polls the future up to `max_spins` times before
+  // suspending: `co_await std::move(future).await_spin(max_spins)`. These
+  // polls don't register a waker, so this avoids the cost of suspending when
+  // the future is expected to finish within a few polls. If it doesn't, the
+  // coroutine suspends as usual. Zero spins is the same as a plain `co_await`.
+  inline SpinAwait<Derived> await_spin(size_t max_spins) && noexcept {
+    return SpinAwait<Derived>(
+        std::move(*static_cast<Derived*>(this)), max_spins);
+  }
 };
 
 template <typename Future, bool YieldResultIsVoid, bool FinalResultIsVoid>
@@ -290,7 +339,7 @@ class SuspendedCoroutine;
 
 // This has to be separate from `rust::Error` because constructing a
 // `rust::Error` is private API.
-class Error final : public std::exception {
+class Error : public std::exception {
   char* m_message;
   void copy_from(const char* message) {
     size_t len = std::strlen(message) + 1;
@@ -302,11 +351,15 @@ class Error final : public std::exception {
       delete[] m_message;
     }
   }
+  template <typename Future>
+  friend class RustFutureReceiver;
+  template <typename Future>
+  friend typename Future::YieldResult blocking_wait(Future future);
+
+ protected:
   explicit Error(const char* message) {
     copy_from(message);
   }
-  template <typename Future>
-  friend class RustFutureReceiver;
 
  public:
   Error(const Error& other) {
@@ -333,6 +386,15 @@ class Error final : public std::exception {
   }
 };
 
+// Thrown instead of `Error` when the awaited Rust future was cancelled rather
+// than failing, for example by being aborted or timing out. Catch this before
+// `Error` to tell the two apart.
+class CxxAsyncCancelled final : public Error {
+  explicit CxxAsyncCancelled(const char* message) : Error(message) {}
+  template <typename Future>
+  friend class RustFutureReceiver;
+};
+
 // Exception customization point. This works just like
 // `rust::behavior::trycatch` [1], except that it allows the `trycatch` behavior
 // to declared anywhere before the future is used, reducing header file ordering
@@ -378,6 +440,16 @@ struct TryCatch {
 
 } // namespace behavior
 
+// Reports a misuse of the bridge that was recovered from. This prints a
+// message in debug builds and does nothing otherwise.
+inline void cxxasync_debug_warn(const char* message) noexcept {
+#ifndef NDEBUG
+  fprintf(stderr, "cxx-async: %s\n", message);
+#else
+  (void)message;
+#endif
+}
+
 void cxxasync_assert(
     bool cond,
     const char* message,
@@ -419,19 +491,177 @@ class Execlet {
   }
 };
 
+// Converts a time point to the count of nanoseconds since the Unix epoch that a
+// Rust `SystemTime` payload is encoded as. See `CxxAsyncPayload`.
+inline int64_t to_epoch_nanos(
+    std::chrono::system_clock::time_point time) noexcept {
+  return std::chrono::duration_cast<std::chrono::nanoseconds>(
+             time.time_since_epoch())
+      .count();
+}
+
+// Converts a count of nanoseconds since the Unix epoch back to a time point,
+// truncated toward the epoch to the precision of the system clock.
+inline std::chrono::system_clock::time_point from_epoch_nanos(
+    int64_t nanos) noexcept {
+  return std::chrono::system_clock::time_point(
+      std::chrono::duration_cast<std::chrono::system_clock::duration>(
+          std::chrono::nanoseconds(nanos)));
+}
+
+// Frees the buffer of a `ForeignBuffer`, given its pointer and length.
+using ForeignBufferDeleter = void (*)(uint8_t* data, size_t len);
+
+// A byte buffer that C++ allocated, freed exactly once by its deleter. A
+// coroutine can `co_return` one to hand a raw buffer from a C API to Rust,
+// which receives a `cxx_async::ForeignBuffer` that frees it on drop. Whichever
+// side ends up owning the buffer frees it, possibly on another thread, so the
+// deleter must be safe to call from any thread.
+//
+// The layout must match `ForeignBuffer` in `foreign.rs`.
+class ForeignBuffer {
+  uint8_t* m_data;
+  size_t m_len;
+  ForeignBufferDeleter m_deleter;
+
+  ForeignBuffer(const ForeignBuffer&) = delete;
+  void operator=(const ForeignBuffer&) = delete;
+
+ public:
+  ForeignBuffer() noexcept : m_data(nullptr), m_len(0), m_deleter(nullptr) {}
+  // Takes ownership of `len` bytes at `data`, to be freed by calling
+  // `deleter(data, len)`.
+  ForeignBuffer(uint8_t* data, size_t len, ForeignBufferDeleter deleter) noexcept
+      : m_data(data), m_len(len), m_deleter(deleter) {}
+  ForeignBuffer(ForeignBuffer&& other) noexcept
+      : m_data(std::exchange(other.m_data, nullptr)),
+        m_len(std::exchange(other.m_len, 0)),
+        m_deleter(std::exchange(other.m_deleter, nullptr)) {}
+  ForeignBuffer& operator=(ForeignBuffer&& other) noexcept {
+    if (this != &other) {
+      reset();
+      m_data = std::exchange(other.m_data, nullptr);
+      m_len = std::exchange(other.m_len, 0);
+      m_deleter = std::exchange(other.m_deleter, nullptr);
+    }
+    return *this;
+  }
+  ~ForeignBuffer() noexcept {
+    reset();
+  }
+
+  const uint8_t* data() const noexcept {
+    return m_data;
+  }
+  size_t size() const noexcept {
+    return m_len;
+  }
+
+ private:
+  void reset() noexcept {
+    if (m_data != nullptr && m_deleter != nullptr) {
+      m_deleter(m_data, m_len);
+    }
+    m_data = nullptr;
+    m_len = 0;
+    m_deleter = nullptr;
+  }
+};
+
+// How the values of a future or stream are handed over between Rust and C++.
+// This must match `CxxAsyncResultKind` in `repr.rs`.
+enum class ResultKind : uint32_t {
+  // Copied bitwise. The type must be trivially copyable.
+  InPlace,
+  // Moved, along with ownership of whatever the value owns.
+  Moved,
+  // Boxed on the Rust heap and held in C++ as a `rust::Box<T>`.
+  Boxed,
+};
+
+// The kind that a C++ type calls for.
+template <typename T>
+struct ResultKindOf
+    : std::integral_constant<
+          ResultKind,
+          std::is_trivially_copyable<T>::value ? ResultKind::InPlace
+                                               : ResultKind::Moved> {};
+template <typename T>
+struct ResultKindOf<rust::Box<T>>
+    : std::integral_constant<ResultKind, ResultKind::Boxed> {};
+template <>
+struct ResultKindOf<void>
+    : std::integral_constant<ResultKind, ResultKind::InPlace> {};
+
+// Returns true if C++ can hold a value that Rust hands over as `rust_kind` as a
+// `T`. Anything can be moved, including trivially copyable values and boxes,
+// but only a trivially copyable type can be copied bitwise, and only a
+// `rust::Box` can take ownership of a boxed value.
+template <typename T>
+constexpr bool result_kind_agrees(ResultKind rust_kind) {
+  return rust_kind == ResultKind::Moved || rust_kind == ResultKindOf<T>::value;
+}
+
+// Returns true if the Rust and C++ sides of a future or stream agree on how its
+// values are handed over. Only the first call for each type asks Rust.
+template <typename Future>
+bool check_result_kind() {
+  static const bool agrees = result_kind_agrees<typename Future::YieldResult>(
+      static_cast<ResultKind>(Future::vtable()->result_kind()));
+  return agrees;
+}
+
+// Returns true if a C++ `T` has the size and alignment that Rust lays its
+// values out with. A mismatch means that the two sides of a future or stream
+// were declared with different types, such as `int64_t` in C++ for an `i32` in
+// Rust, and that one would misread the other's values.
+template <typename T>
+constexpr bool result_layout_agrees(ResultLayout rust_layout) {
+  return sizeof(T) == rust_layout.size && alignof(T) == rust_layout.align;
+}
+template <>
+constexpr bool result_layout_agrees<void>(ResultLayout rust_layout) {
+  return rust_layout.size == 0;
+}
+
+// Returns true if the Rust and C++ sides of a future or stream agree on the
+// layout of its values. Only the first call for each type asks Rust.
+template <typename Future>
+bool check_result_layout() {
+  static const bool agrees = result_layout_agrees<typename Future::YieldResult>(
+      Future::vtable()->result_layout());
+  return agrees;
+}
+
+// True if Rust can hand values of type `T` back in the upper half of the word
+// that a packed poll returns. See `RustFutureReceiver::poll_future()`.
+template <typename T>
+struct PacksResult
+    : std::integral_constant<
+          bool,
+          std::is_trivially_copyable<T>::value &&
+              sizeof(T) <= sizeof(uint32_t)> {};
+template <>
+struct PacksResult<void> : std::true_type {};
+
+// These must match the `FUTURE_STATUS_` constants in `lib.rs`.
 enum class FuturePollStatus {
   Pending,
   Complete,
   Error,
   // Only used for streams, not futures.
   Running,
+  // Like `Error`, but the future was cancelled. Only Rust reports this.
+  Cancelled,
 };
 
+// The statuses shared with `FuturePollStatus` have the same values.
 enum class FutureWakeStatus {
   Pending,
   Complete,
   Error,
   Dead,
+  Cancelled,
 };
 
 // The return value of `sender_send`. These must match the `SEND_RESULT_`
@@ -447,7 +677,8 @@ enum class RustSendResult {
 
 inline bool wake_status_is_done(FutureWakeStatus status) {
   return status == FutureWakeStatus::Complete ||
-      status == FutureWakeStatus::Error;
+      status == FutureWakeStatus::Error ||
+      status == FutureWakeStatus::Cancelled;
 }
 
 // Downstream libraries can customize this by adding template specializations.
@@ -484,6 +715,11 @@ struct RustChannel {
 
 // A temporary place to hold future results or errors that are sent to or
 // returned from Rust.
+//
+// Variable-length values need no extra space here: `rust::String`,
+// `rust::Vec<T>`, and the like are fixed-size handles to heap memory. Whichever
+// side writes a value into this buffer transfers ownership of it, including
+// any heap memory it owns, to the side that reads it.
 template <typename Result>
 union RustFutureResult {
   Result m_result;
@@ -513,6 +749,193 @@ union RustFutureResult<void> {
   void getResult() {}
 };
 
+// A Rust future paired with the means to complete it directly, without a
+// coroutine. This lets one C++ coroutine fill in several results separately,
+// like out-parameters, instead of returning them all at once. See
+// `make_group()`.
+//
+// Move the future out with `take_future()` and hand it to Rust, then call
+// `complete()` or `fail()` once the result is known. If neither is called
+// before this is destroyed, the future fails.
+template <typename Future>
+class RustOutParam {
+  using Result = typename Future::FinalResult;
+
+  std::optional<Future> m_future;
+  RustSender<Future> m_sender;
+  bool m_sent;
+
+  RustOutParam(const RustOutParam&) = delete;
+  RustOutParam& operator=(const RustOutParam&) = delete;
+
+  explicit RustOutParam(RustChannel<Future>&& channel)
+      : m_future(std::move(channel.future)),
+        m_sender(std::move(channel.sender)),
+        m_sent(false) {}
+
+  void send(FuturePollStatus status, const void* value) {
+    if (m_sent) {
+      throw std::logic_error("A `RustOutParam` was completed twice");
+    }
+    m_sent = true;
+    Future::vtable()->sender_send(
+        m_sender, static_cast<uint32_t>(status), value, nullptr);
+  }
+
+ public:
+  RustOutParam() : RustOutParam(make_channel()) {}
+  RustOutParam(RustOutParam&& other) noexcept
+      : m_future(std::move(other.m_future)),
+        m_sender(std::move(other.m_sender)),
+        m_sent(other.m_sent) {
+    other.m_future.reset();
+    other.m_sent = true;
+  }
+
+  ~RustOutParam() {
+    if (!m_sent) {
+      fail("The out-parameter was destroyed before it was completed");
+    }
+  }
+
+  // Moves the future out. This may only be called once.
+  Future take_future() {
+    if (!m_future) {
+      throw std::logic_error("The future was already taken");
+    }
+    Future future(std::move(*m_future));
+    m_future.reset();
+    return future;
+  }
+
+  template <
+      typename R = Result,
+      typename std::enable_if<!std::is_void<R>::value, int>::type = 0>
+  void complete(R&& value) {
+    RustFutureResult<Result> result;
+    new (&result.m_result) Result(std::forward<R>(value));
+    send(
+        FuturePollStatus::Complete,
+        reinterpret_cast<const uint8_t*>(&result));
+  }
+
+  template <
+      typename R = Result,
+      typename std::enable_if<std::is_void<R>::value, int>::type = 0>
+  void complete() {
+    send(FuturePollStatus::Complete, nullptr);
+  }
+
+  void fail(const char* what) {
+    send(FuturePollStatus::Error, what);
+  }
+
+ private:
+  static RustChannel<Future> make_channel() {
+    // Nothing runs on the execlet, because no coroutine is attached to the
+    // channel, so it can be released right away.
+    CXXASYNC_ASSERT(check_result_kind<Future>());
+    CXXASYNC_ASSERT(check_result_layout<Future>());
+    Execlet execlet;
+    return Future::vtable()->channel(execlet.raw());
+  }
+};
+
+// Creates a group of futures, one of each of the given types, that the caller
+// completes separately. For example:
+//
+//     auto [quotient, remainder] =
+//         rust::async::make_group<RustFutureI32, RustFutureI32>();
+//     ...
+//     quotient.complete(a / b);
+//     remainder.complete(a % b);
+//
+// Each future can be awaited independently on the Rust side, and completes as
+// soon as its own result is supplied.
+template <typename... Futures>
+std::tuple<RustOutParam<Futures>...> make_group() {
+  return std::tuple<RustOutParam<Futures>...>();
+}
+
+// An event that Rust can await, for C++ code that signals completion without
+// a coroutine. `Future` must be a future type that yields `void`.
+//
+// `wait()` returns a future that completes once `set()` is called, on the
+// thread that calls `set()`. If the event is already set, the future is
+// complete from the start. If the event is destroyed before being set, its
+// futures fail.
+template <typename Future>
+class RustEvent {
+  static_assert(
+      std::is_void<typename Future::FinalResult>::value,
+      "A `RustEvent` needs a future type that yields `void`");
+
+  std::mutex m_lock;
+  bool m_set;
+  std::vector<RustOutParam<Future>> m_waiters;
+
+  RustEvent(const RustEvent&) = delete;
+  RustEvent& operator=(const RustEvent&) = delete;
+
+ public:
+  RustEvent() : m_set(false) {}
+
+  ~RustEvent() {
+    for (RustOutParam<Future>& waiter : m_waiters) {
+      waiter.fail("The event was destroyed before it was set");
+    }
+  }
+
+  Future wait() {
+    RustOutParam<Future> waiter;
+    Future future = waiter.take_future();
+    {
+      std::lock_guard<std::mutex> guard(m_lock);
+      if (!m_set) {
+        m_waiters.push_back(std::move(waiter));
+        return future;
+      }
+    }
+    waiter.complete();
+    return future;
+  }
+
+  // Sets the event, completing every future waiting on it.
+  void set() {
+    std::vector<RustOutParam<Future>> waiters;
+    {
+      std::lock_guard<std::mutex> guard(m_lock);
+      m_set = true;
+      waiters.swap(m_waiters);
+    }
+    // Completing a future may run Rust code that waits on this event again,
+    // so this happens outside the lock.
+    for (RustOutParam<Future>& waiter : waiters) {
+      waiter.complete();
+    }
+  }
+
+  // Unsets the event, so that futures returned by `wait()` from now on wait
+  // for the next `set()`.
+  void reset() {
+    std::lock_guard<std::mutex> guard(m_lock);
+    m_set = false;
+  }
+
+  bool is_set() {
+    std::lock_guard<std::mutex> guard(m_lock);
+    return m_set;
+  }
+};
+
+// Sets the await site that Rust reports if the future it's polling on this
+// thread panics, and returns the previous one. Defined in Rust.
+extern "C" const char* cxxasync_swap_await_site(const char* site) noexcept;
+
+// Returns true if a bridged future is being polled on this thread. Defined in
+// Rust.
+extern "C" bool cxxasync_is_polling() noexcept;
+
 template <typename Future>
 class RustFutureReceiver {
   using YieldResult = typename Future::YieldResult;
@@ -521,30 +944,131 @@ class RustFutureReceiver {
   Future m_future;
   RustFutureResult<YieldResult> m_result;
   FuturePollStatus m_status;
+  // True if the future was dropped before it completed.
+  bool m_cancelled;
+  // A tag describing where the future is awaited, or null. See
+  // `set_await_site()`.
+  const char* m_await_site;
+  // Run if the await is cancelled, or empty. See `cleanup_on_cancel()`.
+  std::function<void()> m_on_cancel;
+
+  // Polls the Rust future once.
+  //
+  // Completing a future normally means that Rust writes the value into
+  // `m_result` and we read it back out. For tiny values, such as a `bool` or an
+  // `int32_t`, Rust instead offers `future_poll_packed`, which returns a 64-bit
+  // word: the low 32 bits hold the status, and if that's `Complete`, the high
+  // 32 bits hold the value, in the first `sizeof(YieldResult)` bytes of a
+  // `uint32_t`. That keeps the value in registers all the way. Errors are
+  // still written into `m_result`, as `rust::String`s.
+  FuturePollStatus poll_future(const void* waker_data) {
+    if constexpr (PacksResult<YieldResult>::value) {
+      if (Future::vtable()->future_poll_packed != nullptr) {
+        uint64_t packed = Future::vtable()->future_poll_packed(
+            m_future, &m_result, waker_data);
+        FuturePollStatus status =
+            static_cast<FuturePollStatus>(static_cast<uint32_t>(packed));
+        if constexpr (!std::is_void<YieldResult>::value) {
+          if (status == FuturePollStatus::Complete) {
+            uint32_t bits = static_cast<uint32_t>(packed >> 32);
+            std::memcpy(&m_result.m_result, &bits, sizeof(YieldResult));
+          }
+        }
+        return status;
+      }
+    }
+    return static_cast<FuturePollStatus>(
+        Future::vtable()->future_poll(m_future, &m_result, waker_data));
+  }
+
+  // Polls the Rust future, reporting the await site if it panics. The caller
+  // must hold the lock.
+  FuturePollStatus poll(const void* waker_data) {
+    if (m_await_site == nullptr) {
+      return poll_future(waker_data);
+    }
+    const char* previous_site = cxxasync_swap_await_site(m_await_site);
+    FuturePollStatus status = poll_future(waker_data);
+    cxxasync_swap_await_site(previous_site);
+    return status;
+  }
+
+  // Moves the Rust future out if it hasn't completed, so that it can be dropped
+  // without holding the lock, along with the cancellation callback.
+  std::optional<Future> take_pending_future(
+      std::function<void()>& on_cancel) {
+    std::lock_guard<std::mutex> guard(m_lock);
+    if (m_status != FuturePollStatus::Pending || m_cancelled) {
+      return std::nullopt;
+    }
+    m_cancelled = true;
+    on_cancel = std::move(m_on_cancel);
+    return std::optional<Future>(std::move(m_future));
+  }
 
   RustFutureReceiver(const RustFutureReceiver&) = delete;
   void operator=(const RustFutureReceiver&) = delete;
 
  public:
-  explicit RustFutureReceiver(Future&& future)
+  explicit RustFutureReceiver(
+      Future&& future,
+      const char* await_site,
+      std::function<void()> on_cancel)
       : m_lock(),
         m_future(std::move(future)),
-        m_status(FuturePollStatus::Pending) {}
+        m_status(FuturePollStatus::Pending),
+        m_cancelled(false),
+        m_await_site(await_site),
+        m_on_cancel(std::move(on_cancel)) {
+    CXXASYNC_ASSERT(check_result_kind<Future>());
+    CXXASYNC_ASSERT(check_result_layout<Future>());
+  }
+
+  // Drops the Rust future if it hasn't completed yet. This cancels it, and
+  // whatever Rust future or C++ coroutine it's awaiting in turn. The awaiting
+  // coroutine is never resumed; it's destroyed once Rust releases its waker.
+  // Afterward, runs the cancellation callback, if there is one.
+  void cancel() {
+    std::function<void()> on_cancel;
+    {
+      std::optional<Future> future = take_pending_future(on_cancel);
+    }
+    if (on_cancel) {
+      on_cancel();
+    }
+  }
 
   // Consumes the `coroutine` reference (so you probably want to addref it
   // first).
   FutureWakeStatus wake(SuspendedCoroutine* coroutine);
 
+  // Polls the future once without registering a waker, and returns true if it
+  // has finished. See `RustFuture::await_spin()`.
+  bool poll_without_waker() {
+    std::lock_guard<std::mutex> guard(m_lock);
+    if (m_status == FuturePollStatus::Pending && !m_cancelled) {
+      m_status = poll(nullptr);
+    }
+    return m_status != FuturePollStatus::Pending;
+  }
+
   YieldResult get_result() {
     // Safe to use without taking the lock because the caller asserts that the
     // future has already completed.
     switch (m_status) {
       case FuturePollStatus::Complete:
         return m_result.getResult();
-      case FuturePollStatus::Error: {
-        Error error(m_result.m_exception.c_str());
+      case FuturePollStatus::Error:
+      case FuturePollStatus::Cancelled: {
+        std::string message(m_result.m_exception);
         m_result.m_exception.~String();
-        throw std::move(error);
+        if (m_await_site != nullptr) {
+          message = std::string(m_await_site) + ": " + message;
+        }
+        if (m_status == FuturePollStatus::Cancelled) {
+          throw CxxAsyncCancelled(message.c_str());
+        }
+        throw Error(message.c_str());
       }
       case FuturePollStatus::Pending:
       case FuturePollStatus::Running:
@@ -555,6 +1079,20 @@ class RustFutureReceiver {
   }
 };
 
+// Detects whether a coroutine promise accepts cancellation callbacks, which is
+// the case for the promises of all C++ coroutines that are Rust futures.
+template <typename Promise, typename = void>
+struct IsCancellablePromise : std::false_type {};
+template <typename Promise>
+struct IsCancellablePromise<
+    Promise,
+    std::void_t<decltype(std::declval<Promise&>().on_cancel(
+        std::declval<std::function<void()>>()))>> : std::true_type {};
+
+// Runs the function it's given, on whatever thread it chooses. See
+// `resume_via()`.
+using Scheduler = std::function<void(std::function<void()>)>;
+
 template <typename Future>
 class RustAwaiter {
   using YieldResult = typename Future::YieldResult;
@@ -562,28 +1100,178 @@ class RustAwaiter {
   friend class SuspendedCoroutine;
 
   std::shared_ptr<RustFutureReceiver<Future>> m_receiver;
+  // How many times to poll before suspending. See `RustFuture::await_spin()`.
+  size_t m_max_spins;
+  // Resumes the awaiting coroutine, or empty to resume it on whichever thread
+  // completes the future. See `resume_via()`.
+  Scheduler m_schedule;
 
   RustAwaiter(const RustAwaiter&) = delete;
   void operator=(const RustAwaiter&) = delete;
 
  public:
-  explicit RustAwaiter(Future&& future)
-      : m_receiver(
-            std::make_shared<RustFutureReceiver<Future>>(std::move(future))) {}
+  explicit RustAwaiter(
+      Future&& future,
+      const char* await_site = nullptr,
+      size_t max_spins = 0,
+      std::function<void()> on_cancel = nullptr,
+      Scheduler schedule = nullptr)
+      : m_receiver(std::make_shared<RustFutureReceiver<Future>>(
+            std::move(future),
+            await_site,
+            std::move(on_cancel))),
+        m_max_spins(max_spins),
+        m_schedule(std::move(schedule)) {}
 
   bool await_ready() noexcept {
-    // We could poll here, but let's not. Assume that polling is more expensive
-    // than creating the coroutine state.
+    // Unless asked to spin, don't poll here. Assume that polling is more
+    // expensive than creating the coroutine state.
+    for (size_t spin = 0; spin < m_max_spins; spin++) {
+      if (m_receiver->poll_without_waker()) {
+        return true;
+      }
+    }
     return false;
   }
 
   bool await_suspend(std_coroutine::coroutine_handle<void> next);
 
+  // If the awaiting coroutine is itself a Rust future, arranges for our Rust
+  // future to be cancelled when that coroutine is. This propagates
+  // cancellation through chains of nested C++ and Rust futures.
+  template <typename Promise>
+  bool await_suspend(std_coroutine::coroutine_handle<Promise> next) {
+    if constexpr (IsCancellablePromise<Promise>::value) {
+      std::weak_ptr<RustFutureReceiver<Future>> weak_receiver = m_receiver;
+      next.promise().on_cancel([weak_receiver = std::move(weak_receiver)]() {
+        if (std::shared_ptr<RustFutureReceiver<Future>> receiver =
+                weak_receiver.lock()) {
+          receiver->cancel();
+        }
+      });
+    }
+    return await_suspend(std_coroutine::coroutine_handle<void>(next));
+  }
+
   YieldResult await_resume() {
     return m_receiver->get_result();
   }
 };
 
+// A Rust future tagged with the place where it's awaited. See
+// `set_await_site()`.
+template <typename Future>
+class AwaitSite {
+  Future m_future;
+  const char* m_site;
+
+ public:
+  AwaitSite(Future&& future, const char* site) noexcept
+      : m_future(std::move(future)), m_site(site) {}
+
+  RustAwaiter<Future> operator co_await() && noexcept {
+    return RustAwaiter(std::move(m_future), m_site);
+  }
+};
+
+// A Rust future to be polled a few times before suspending. See
+// `RustFuture::await_spin()`.
+template <typename Future>
+class SpinAwait {
+  Future m_future;
+  size_t m_max_spins;
+
+ public:
+  SpinAwait(Future&& future, size_t max_spins) noexcept
+      : m_future(std::move(future)), m_max_spins(max_spins) {}
+
+  RustAwaiter<Future> operator co_await() && noexcept {
+    return RustAwaiter(std::move(m_future), nullptr, m_max_spins);
+  }
+};
+
+// Tags a Rust future with a short description of where it's awaited, for
+// diagnostics: `co_await rust::async::set_await_site(std::move(future), tag)`.
+// If the future fails, the message of the exception thrown from the `co_await`
+// is prefixed with the tag, and so is the message of any `CxxAsyncException`
+// that the exception becomes on its way back to Rust. If the future panics,
+// the tag appears in the message printed before aborting.
+//
+// The tag isn't copied, so it must outlive the `co_await`. A string literal or
+// `__func__` is fine.
+template <typename Future>
+AwaitSite<Future> set_await_site(Future future, const char* tag) noexcept {
+  return AwaitSite<Future>(std::move(future), tag);
+}
+
+// A Rust future paired with a callback to run if awaiting it is cancelled. See
+// `cleanup_on_cancel()`.
+template <typename Future>
+class CleanupOnCancel {
+  Future m_future;
+  std::function<void()> m_callback;
+
+ public:
+  CleanupOnCancel(Future&& future, std::function<void()> callback) noexcept
+      : m_future(std::move(future)), m_callback(std::move(callback)) {}
+
+  RustAwaiter<Future> operator co_await() && noexcept {
+    return RustAwaiter(
+        std::move(m_future), nullptr, 0, std::move(m_callback));
+  }
+};
+
+// Awaits a Rust future and runs `callback` if the await is cancelled before the
+// future completes: `co_await rust::async::cleanup_on_cancel(std::move(future),
+// callback)`. Use it to release resources that the C++ side lent the future,
+// such as a buffer it reads from.
+//
+// An await is cancelled when Rust drops the C++ coroutine doing the awaiting,
+// which must therefore itself be a Rust future. The callback then runs exactly
+// once, after the awaited future has been dropped, on whichever thread dropped
+// the coroutine. If the future completes, successfully or not, the callback is
+// destroyed without running. It must not throw.
+template <typename Future>
+CleanupOnCancel<Future> cleanup_on_cancel(
+    Future future,
+    std::function<void()> callback) noexcept {
+  return CleanupOnCancel<Future>(std::move(future), std::move(callback));
+}
+
+// A Rust future paired with the scheduler that resumes its awaiter. See
+// `resume_via()`.
+template <typename Future>
+class ResumeVia {
+  Future m_future;
+  Scheduler m_schedule;
+
+ public:
+  ResumeVia(Future&& future, Scheduler schedule) noexcept
+      : m_future(std::move(future)), m_schedule(std::move(schedule)) {}
+
+  RustAwaiter<Future> operator co_await() && noexcept {
+    return RustAwaiter(
+        std::move(m_future), nullptr, 0, nullptr, std::move(m_schedule));
+  }
+};
+
+// Awaits a Rust future and resumes the awaiting coroutine through `schedule`:
+// `co_await rust::async::resume_via(std::move(future), schedule)`. Use it when
+// the coroutine must continue on a particular thread, such as its event loop,
+// even though the future runs elsewhere.
+//
+// Normally, the coroutine resumes on whichever thread completes the future,
+// which may be a Rust worker thread. With this, once the future finishes,
+// `schedule` is called with a function that resumes the coroutine, and must
+// run it exactly once, on the thread of its choosing. That happens even if the
+// future is already finished when it's awaited, so the coroutine always
+// continues wherever `schedule` puts it. If the future fails, the exception is
+// thrown there too.
+template <typename Future>
+ResumeVia<Future> resume_via(Future future, Scheduler schedule) noexcept {
+  return ResumeVia<Future>(std::move(future), std::move(schedule));
+}
+
 template <typename Future>
 class RustStreamAwaiter {
   using YieldResult = typename Future::YieldResult;
@@ -610,6 +1298,77 @@ class RustStreamAwaiter {
   void await_resume() {}
 };
 
+// A contiguous batch of values to send to a stream all at once. Yield one of
+// these from a stream coroutine, with `co_yield rust::async::batch(values)`, to
+// cross the language boundary once for the whole batch instead of once per
+// value. Rust still receives the values one at a time.
+template <typename T>
+struct StreamBatch {
+  rust::Slice<const T> m_values;
+};
+
+template <typename T>
+StreamBatch<T> batch(rust::Slice<const T> values) noexcept {
+  return StreamBatch<T>{values};
+}
+
+template <typename Future>
+class RustStreamBatchAwaiter {
+  using YieldResult = typename Future::YieldResult;
+
+  static_assert(
+      std::is_trivially_copyable<YieldResult>::value,
+      "Only trivially copyable values can be sent in a batch");
+
+  friend class SuspendedCoroutine;
+
+  RustSender<Future>& m_sender;
+  rust::Slice<const YieldResult> m_values;
+
+  FutureWakeStatus poll_next(SuspendedCoroutine* coroutine) noexcept;
+
+  RustStreamBatchAwaiter(const RustStreamBatchAwaiter&) = delete;
+  void operator=(const RustStreamBatchAwaiter&) = delete;
+
+ public:
+  RustStreamBatchAwaiter(
+      RustSender<Future>& sender,
+      rust::Slice<const YieldResult> values)
+      : m_sender(sender), m_values(values) {}
+
+  bool await_ready() noexcept {
+    return false;
+  }
+  bool await_suspend(std_coroutine::coroutine_handle<void> next);
+  void await_resume() {}
+};
+
+// A callback to run if Rust drops the future or stream that a coroutine is
+// producing before the coroutine finishes. Register one from inside the
+// coroutine with `co_await rust::async::on_cancel(callback)`, and use it to
+// wake up and abandon whatever the coroutine is waiting on.
+//
+// The callback may run on any thread, possibly after the coroutine has
+// finished, so it must only capture state that it owns or shares ownership of.
+// It must not throw. Once Rust has dropped its end, the coroutine's results are
+// discarded.
+struct OnCancel {
+  std::function<void()> m_callback;
+};
+
+inline OnCancel on_cancel(std::function<void()> callback) {
+  return OnCancel{std::move(callback)};
+}
+
+// Rust calls this exactly once for each registered `OnCancel` callback.
+inline void on_cancel_trampoline(void* data, bool cancelled) noexcept {
+  std::unique_ptr<std::function<void()>> callback(
+      static_cast<std::function<void()>*>(data));
+  if (cancelled) {
+    (*callback)();
+  }
+}
+
 // This is like `std_coroutine::coroutine_handle<void>`, but it doesn't *have*
 // to be a coroutine handle.
 class Continuation {
@@ -640,6 +1399,26 @@ class CoroutineHandleContinuation : public Continuation {
   }
 };
 
+// Resumes a coroutine by handing it to a scheduler, rather than on the thread
+// that wakes it. See `resume_via()`.
+class ScheduledContinuation : public Continuation {
+  std_coroutine::coroutine_handle<void> m_next;
+  Scheduler m_schedule;
+
+ public:
+  ScheduledContinuation(
+      std_coroutine::coroutine_handle<void> next,
+      Scheduler schedule)
+      : m_next(next), m_schedule(std::move(schedule)) {}
+  void resume() override {
+    std_coroutine::coroutine_handle<void> next = m_next;
+    m_schedule([next]() { next.resume(); });
+  }
+  void destroy() override {
+    m_next.destroy();
+  }
+};
+
 // Wrapper object that encapsulates a suspended coroutine. This is the waker
 // that is exposed to Rust.
 //
@@ -654,14 +1433,23 @@ class SuspendedCoroutine {
   std::atomic<uintptr_t> m_refcount;
   std::unique_ptr<Continuation> m_next;
   WakeFn m_wake_fn;
+  // Set once the coroutine has been resumed, or has continued without ever
+  // suspending. Rust may still hold wakers for it after that, for example if
+  // buggy glue wakes it twice, so later wakes must not resume it again or call
+  // the wake function, which may refer to an awaiter that no longer exists.
+  std::atomic<bool> m_finished;
 
   void forget_coroutine_handle() {
+    m_finished.store(true);
     m_next.reset();
   }
 
  public:
   SuspendedCoroutine(std::unique_ptr<Continuation>&& next, WakeFn&& wake_fn)
-      : m_refcount(1), m_next(std::move(next)), m_wake_fn(std::move(wake_fn)) {}
+      : m_refcount(1),
+        m_next(std::move(next)),
+        m_wake_fn(std::move(wake_fn)),
+        m_finished(false) {}
 
   ~SuspendedCoroutine() {
     if (m_next) {
@@ -685,6 +1473,10 @@ class SuspendedCoroutine {
 
   // Does not consume the `this` reference.
   FutureWakeStatus wake() {
+    if (m_finished.load()) {
+      cxxasync_debug_warn("ignoring a wake of a coroutine that was resumed");
+      return FutureWakeStatus::Dead;
+    }
     return m_wake_fn(this);
   }
 
@@ -706,7 +1498,12 @@ class SuspendedCoroutine {
     return !done;
   }
 
+  // Resumes the coroutine, unless it has already been resumed.
   void resume() {
+    if (m_finished.exchange(true)) {
+      cxxasync_debug_warn("ignoring a second resume of a coroutine");
+      return;
+    }
     CXXASYNC_ASSERT(bool(m_next));
     std::unique_ptr<Continuation> next = std::move(m_next);
     forget_coroutine_handle();
@@ -731,7 +1528,10 @@ class RustPromiseBase {
 
  public:
   RustPromiseBase()
-      : m_execlet(), m_channel(Future::vtable()->channel(m_execlet.raw())) {}
+      : m_execlet(), m_channel(Future::vtable()->channel(m_execlet.raw())) {
+    CXXASYNC_ASSERT(check_result_kind<Future>());
+    CXXASYNC_ASSERT(check_result_layout<Future>());
+  }
 
   Future get_return_object() noexcept {
     return std::move(m_channel.future);
@@ -764,6 +1564,20 @@ class RustPromiseBase {
     return m_execlet;
   }
 
+  // Registers a callback to be called if the Rust side drops this coroutine's
+  // future before it completes. See `OnCancel`.
+  void on_cancel(std::function<void()> callback) noexcept {
+    Future::vtable()->sender_on_cancel(
+        m_channel.sender,
+        on_cancel_trampoline,
+        new std::function<void()>(std::move(callback)));
+  }
+
+  std_coroutine::suspend_never await_transform(OnCancel&& on_cancel) noexcept {
+    this->on_cancel(std::move(on_cancel.m_callback));
+    return {};
+  }
+
   // Customization point for library integration (e.g. Folly).
   template <
       typename Awaiter,
@@ -798,6 +1612,11 @@ class RustStreamPromiseBase<Future, false> : public RustPromiseBase<Future> {
       typename Future::YieldResult&& value) noexcept {
     return RustStreamAwaiter(this->m_channel.sender, std::move(value));
   }
+
+  RustStreamBatchAwaiter<Future> yield_value(
+      StreamBatch<YieldResult> batch) noexcept {
+    return RustStreamBatchAwaiter(this->m_channel.sender, batch.m_values);
+  }
 };
 
 // Void specialization.
@@ -851,23 +1670,33 @@ FutureWakeStatus RustFutureReceiver<Future>::wake(
     SuspendedCoroutine* coroutine) {
   std::lock_guard<std::mutex> guard(m_lock);
 
-  // Have we already polled this future to completion? If so, don't poll again.
-  if (m_status != FuturePollStatus::Pending) {
+  // Have we already polled this future to completion, or cancelled it? If so,
+  // don't poll again.
+  if (m_status != FuturePollStatus::Pending || m_cancelled) {
     coroutine->release();
     return FutureWakeStatus::Dead;
   }
 
-  m_status = static_cast<FuturePollStatus>(
-      Future::vtable()->future_poll(m_future, &m_result, coroutine));
+  m_status = poll(coroutine);
   return static_cast<FutureWakeStatus>(m_status);
 }
 
 template <typename Future>
 inline bool RustAwaiter<Future>::await_suspend(
     std_coroutine::coroutine_handle<void> next) {
+  // Once the coroutine might have resumed, this awaiter may be gone, so don't
+  // touch it after scheduling the resumption.
+  Scheduler schedule = std::move(m_schedule);
+  std::unique_ptr<Continuation> continuation;
+  if (schedule) {
+    continuation = std::make_unique<ScheduledContinuation>(next, schedule);
+  } else {
+    continuation = std::make_unique<CoroutineHandleContinuation>(
+        std_coroutine::coroutine_handle<void>(next));
+  }
   std::weak_ptr<RustFutureReceiver<Future>> weak_receiver = m_receiver;
   SuspendedCoroutine* coroutine = new SuspendedCoroutine(
-      std::make_unique<CoroutineHandleContinuation>(std::move(next)),
+      std::move(continuation),
       [weak_receiver =
            std::move(weak_receiver)](SuspendedCoroutine* coroutine) {
         std::shared_ptr<RustFutureReceiver<Future>> receiver =
@@ -878,7 +1707,16 @@ inline bool RustAwaiter<Future>::await_suspend(
         }
         return receiver->wake(coroutine->add_ref());
       });
-  return coroutine->initial_suspend();
+  if (coroutine->initial_suspend()) {
+    return true;
+  }
+  // The future is already finished, but the coroutine must still continue
+  // wherever the scheduler puts it.
+  if (schedule) {
+    schedule([next]() { next.resume(); });
+    return true;
+  }
+  return false;
 }
 
 template <typename Future>
@@ -917,6 +1755,177 @@ inline FutureWakeStatus RustStreamAwaiter<Future>::poll_next(
   }
 }
 
+template <typename Future>
+inline bool RustStreamBatchAwaiter<Future>::await_suspend(
+    std_coroutine::coroutine_handle<void> next) {
+  SuspendedCoroutine* coroutine = new SuspendedCoroutine(
+      std::make_unique<CoroutineHandleContinuation>(std::move(next)),
+      [=](SuspendedCoroutine* coroutine) {
+        return this->poll_next(coroutine);
+      });
+  return coroutine->initial_suspend();
+}
+
+template <typename Future>
+inline FutureWakeStatus RustStreamBatchAwaiter<Future>::poll_next(
+    SuspendedCoroutine* coroutine) noexcept {
+  RustSendResult send_result =
+      static_cast<RustSendResult>(Future::vtable()->sender_send_batch(
+          this->m_sender,
+          m_values.data(),
+          m_values.size(),
+          coroutine->add_ref()));
+
+  switch (send_result) {
+    case RustSendResult::Sent:
+      return FutureWakeStatus::Complete;
+    case RustSendResult::Wait:
+      return FutureWakeStatus::Pending;
+    case RustSendResult::Finished:
+      // Should never get here.
+      CXXASYNC_ASSERT(false);
+      std::terminate();
+  }
+}
+
+// The waker that `blocking_wait()` polls with. Waking it just unblocks the
+// waiting thread, which then polls again itself.
+class BlockingWaiter {
+  std::mutex m_lock;
+  std::condition_variable m_cond;
+  bool m_woken;
+
+ public:
+  BlockingWaiter() : m_woken(false) {}
+
+  void clear() {
+    std::lock_guard<std::mutex> guard(m_lock);
+    m_woken = false;
+  }
+
+  void wake() {
+    {
+      std::lock_guard<std::mutex> guard(m_lock);
+      m_woken = true;
+    }
+    m_cond.notify_all();
+  }
+
+  void wait() {
+    std::unique_lock<std::mutex> guard(m_lock);
+    m_cond.wait(guard, [this] { return m_woken; });
+  }
+};
+
+// Nothing to resume: the thread in `blocking_wait()` notices completion itself.
+class BlockingContinuation : public Continuation {
+ public:
+  void resume() override {}
+  void destroy() override {}
+};
+
+// Drives a Rust future to completion on the current thread from synchronous
+// C++ code, blocking until it finishes: `double value =
+// rust::async::blocking_wait(rust_compute());`. Returns the value, or throws
+// the exception that `co_await` would throw if the future fails.
+//
+// Wakeups from other threads unblock the current thread, which then polls the
+// future again, so the future only ever runs here. Blocking while a future is
+// being polled on this thread would stall that future, and deadlock if it's
+// the one that would wake us, so this throws `rust::async::Error` instead if
+// called from within a poll, such as from synchronous C++ code that the body of
+// a Rust future calls. Await the future there instead.
+template <typename Future>
+typename Future::YieldResult blocking_wait(Future future) {
+  if (cxxasync_is_polling()) {
+    throw Error(
+        "blocking_wait() called while a future is being polled on this thread, "
+        "which could deadlock; use co_await instead");
+  }
+
+  std::shared_ptr<BlockingWaiter> waiter = std::make_shared<BlockingWaiter>();
+  SuspendedCoroutine* waker = new SuspendedCoroutine(
+      std::make_unique<BlockingContinuation>(),
+      [waiter](SuspendedCoroutine*) {
+        waiter->wake();
+        return FutureWakeStatus::Pending;
+      });
+  RustFutureReceiver<Future> receiver(std::move(future), nullptr, nullptr);
+  while (true) {
+    waiter->clear();
+    if (wake_status_is_done(receiver.wake(waker->add_ref()))) {
+      break;
+    }
+    waiter->wait();
+  }
+  waker->release();
+  return receiver.get_result();
+}
+
+#if defined(__cpp_lib_jthread)
+// Wakes the coroutine in `stop_requested()` once, from whichever of its
+// triggers fires first.
+class StopWakeup {
+  enum class Phase { Suspending, Suspended, Woken };
+
+  std::atomic<Phase> m_phase;
+  std_coroutine::coroutine_handle<void> m_next;
+
+ public:
+  StopWakeup() : m_phase(Phase::Suspending) {}
+
+  // Resumes the coroutine if it's suspended. If it's still suspending, it
+  // notices when it tries to suspend and carries on instead.
+  void wake() noexcept {
+    if (m_phase.exchange(Phase::Woken) == Phase::Suspended) {
+      m_next.resume();
+    }
+  }
+
+  // Awaited by the coroutine. This shares ownership of the wakeup, since the
+  // awaiter may be moved, for instance by Folly's `co_viaIfAsync()`.
+  class Awaiter {
+    std::shared_ptr<StopWakeup> m_wakeup;
+
+   public:
+    explicit Awaiter(std::shared_ptr<StopWakeup> wakeup)
+        : m_wakeup(std::move(wakeup)) {}
+
+    bool await_ready() const noexcept {
+      return m_wakeup->m_phase.load() == Phase::Woken;
+    }
+
+    bool await_suspend(std_coroutine::coroutine_handle<void> next) noexcept {
+      m_wakeup->m_next = next;
+      Phase expected = Phase::Suspending;
+      return m_wakeup->m_phase.compare_exchange_strong(
+          expected, Phase::Suspended);
+    }
+
+    void await_resume() noexcept {}
+  };
+};
+
+// Returns a future that completes once stop is requested through `token`, on
+// the thread that calls `request_stop()`, or right away if it already has
+// been. `Future` must be a future type that yields `void`.
+//
+// Pass it to Rust and give it to `fallible_until_stopped()` there, so that
+// `request_stop()` drops the Rust future's body and fails it as cancelled. If
+// Rust drops the future first, the stop callback is deregistered.
+template <typename Future>
+Future stop_requested(std::stop_token token) {
+  static_assert(
+      std::is_void<typename Future::FinalResult>::value,
+      "`stop_requested()` needs a future type that yields `void`");
+  std::shared_ptr<StopWakeup> wakeup = std::make_shared<StopWakeup>();
+  co_await on_cancel([wakeup]() { wakeup->wake(); });
+  std::stop_callback callback(
+      std::move(token), [wakeup]() { wakeup->wake(); });
+  co_await StopWakeup::Awaiter(wakeup);
+}
+#endif
+
 } // namespace async
 } // namespace rust
 
@@ -12,6 +12,21 @@
 #ifndef RUST_CXX_ASYNC_CPPCORO_H
 #define RUST_CXX_ASYNC_CPPCORO_H
 
-// This file intentionally left blank.
+#include <cppcoro/task.hpp>
+#include <utility>
+#include "rust/cxx_async.h"
+
+namespace rust {
+namespace async {
+
+// Converts a Rust future into a `cppcoro::task`, for passing to C++ APIs that
+// expect a native cppcoro task instead of an awaitable.
+template <typename Future>
+cppcoro::task<typename Future::YieldResult> to_task(Future future) {
+  co_return co_await std::move(future);
+}
+
+} // namespace async
+} // namespace rust
 
 #endif // RUST_CXX_ASYNC_CPPCORO_H
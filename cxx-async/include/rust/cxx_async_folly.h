@@ -15,6 +15,8 @@
 #include <folly/Executor.h>
 #include <folly/Try.h>
 #include <folly/executors/ManualExecutor.h>
+#include <folly/futures/Future.h>
+#include <folly/experimental/coro/Invoke.h>
 #include <folly/experimental/coro/Task.h>
 #include <folly/experimental/coro/ViaIfAsync.h>
 #include <atomic>
@@ -93,6 +95,35 @@ class AwaitTransformer<
   }
 };
 
+// Converts a Rust future into a `folly::SemiFuture`, for passing to C++ APIs
+// that expect a native Folly future instead of an awaitable.
+template <typename Future>
+folly::SemiFuture<typename Future::YieldResult> to_semi_future(Future future) {
+  return folly::coro::co_invoke(
+             [future = std::move(future)]() mutable
+             -> folly::coro::Task<typename Future::YieldResult> {
+               co_return co_await std::move(future);
+             })
+      .semi();
+}
+
+// Awaits a Rust future and resumes the awaiting coroutine on `executor`, rather
+// than on whichever thread completes the future: `co_await
+// rust::async::resume_on(std::move(future), executor)`. See `resume_via()`.
+template <typename Future>
+ResumeVia<Future> resume_on(
+    Future future,
+    folly::Executor::KeepAlive<> executor) noexcept {
+  auto shared_executor =
+      std::make_shared<folly::Executor::KeepAlive<>>(std::move(executor));
+  return resume_via(
+      std::move(future),
+      [shared_executor = std::move(shared_executor)](
+          std::function<void()> resume) {
+        (*shared_executor)->add(std::move(resume));
+      });
+}
+
 } // namespace async
 } // namespace rust
 
@@ -0,0 +1,187 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+// cxx-async/src/blocking.rs
+//
+//! A shared executor for running CPU-bound work off of the thread that polls a bridged future.
+//!
+//! See `IntoCxxAsyncFuture::from_blocking()`. By default, this is a thread pool owned by
+//! `cxx-async`; use `set_default_executor()` to supply your own instead.
+//!
+//! An executor that shuts down while it holds work for a bridged future, whether this one or one
+//! passed to `IntoCxxAsyncFuture::spawn_on()`, makes that future fail in the way that
+//! `set_shutdown_policy()` chooses, instead of leaving whatever awaits it hanging.
+
+use crate::CxxAsyncException;
+use crate::CxxAsyncExecutor;
+use crate::CxxAsyncResult;
+use crate::SafeUnwrap;
+use futures::channel::oneshot;
+use futures::executor::ThreadPool;
+use futures::future;
+use futures::future::Either;
+use futures::task::Spawn;
+use futures::task::SpawnExt;
+use futures::FutureExt;
+use once_cell::sync::OnceCell;
+use std::future::Future;
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+
+// The requested number of threads, or 0 to pick a size based on the number of CPUs.
+static POOL_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+static DEFAULT_EXECUTOR: OnceCell<CxxAsyncExecutor> = OnceCell::new();
+
+static CANCEL_ON_SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+/// The message of the error that a future fails with when the executor running its work shuts
+/// down first, under `ShutdownPolicy::Fail`.
+pub const EXECUTOR_SHUTDOWN: &str = "executor shut down";
+
+/// What happens to a bridged future when the executor running its work shuts down before
+/// finishing it. See `set_shutdown_policy()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShutdownPolicy {
+    /// The future fails with an error whose message is `EXECUTOR_SHUTDOWN`. This is the default.
+    Fail,
+    /// The future fails with a cancellation, as from `CxxAsyncException::cancelled()`, which a C++
+    /// coroutine awaiting it sees as a `rust::async::CxxAsyncCancelled` exception.
+    Cancel,
+}
+
+/// Chooses how bridged futures fail when the executor running their work shuts down before
+/// finishing it, either by refusing to take the work or by dropping it unfinished.
+///
+/// This affects futures that find out about the shutdown after the call.
+pub fn set_shutdown_policy(policy: ShutdownPolicy) {
+    CANCEL_ON_SHUTDOWN.store(policy == ShutdownPolicy::Cancel, Ordering::SeqCst);
+}
+
+// The error for a future whose executor shut down, according to the shutdown policy.
+fn shutdown_error() -> CxxAsyncException {
+    let what = EXECUTOR_SHUTDOWN.to_owned().into_boxed_str();
+    if CANCEL_ON_SHUTDOWN.load(Ordering::SeqCst) {
+        CxxAsyncException::cancelled(what)
+    } else {
+        CxxAsyncException::new(what)
+    }
+}
+
+/// Sets the number of threads in the blocking thread pool.
+///
+/// The pool is started the first time a blocking closure runs, so this only has an effect if it's
+/// called before then. Returns false if the pool has already started, or if another executor was
+/// installed with `set_default_executor()`. By default, the pool has one thread per CPU.
+pub fn set_blocking_pool_size(size: usize) -> bool {
+    POOL_SIZE.store(size.max(1), Ordering::SeqCst);
+    DEFAULT_EXECUTOR.get().is_none()
+}
+
+/// Makes `executor` the one that blocking closures run on, in place of the built-in thread pool.
+///
+/// The default executor is chosen the first time it's needed, so this only has an effect if it's
+/// called before any blocking closure runs and before `default_executor()` is first called.
+/// Afterward, this does nothing and returns false, so the executor never changes once chosen.
+pub fn set_default_executor<E>(executor: E) -> bool
+where
+    E: Spawn + Send + Sync + 'static,
+{
+    DEFAULT_EXECUTOR.set(Arc::new(executor)).is_ok()
+}
+
+/// Returns the executor that blocking closures run on, starting the built-in thread pool if no
+/// other executor was installed with `set_default_executor()`.
+///
+/// Bridged futures can spawn their own subtasks onto this executor too.
+pub fn default_executor() -> CxxAsyncExecutor {
+    DEFAULT_EXECUTOR
+        .get_or_init(|| {
+            let size = match POOL_SIZE.load(Ordering::SeqCst) {
+                0 => thread::available_parallelism().map_or(1, |size| size.get()),
+                size => size,
+            };
+            Arc::new(
+                ThreadPool::builder()
+                    .pool_size(size)
+                    .name_prefix("cxx-async-blocking-")
+                    .create()
+                    .safe_unwrap(),
+            )
+        })
+        .clone()
+}
+
+// Runs `future` as a task on `executor` and returns a future that resolves to its output.
+//
+// Dropping the returned future cancels the task. If the executor refuses the task or drops it
+// unfinished, the returned future fails with `shutdown_error()`. If the task panics, the panic
+// resumes wherever the returned future is polled.
+pub(crate) fn spawn_on<E, Fut>(
+    executor: &E,
+    future: Fut,
+) -> impl Future<Output = CxxAsyncResult<Fut::Output>> + Send + 'static
+where
+    E: Spawn + ?Sized,
+    Fut: Future + Send + 'static,
+    Fut::Output: Send + 'static,
+{
+    let (mut sender, receiver) = oneshot::channel();
+    let spawned = executor.spawn(async move {
+        if sender.is_canceled() {
+            return;
+        }
+        let future = AssertUnwindSafe(future).catch_unwind();
+        futures::pin_mut!(future);
+        let output = match future::select(future, sender.cancellation()).await {
+            Either::Left((output, _)) => output,
+            Either::Right(_) => return,
+        };
+        drop(sender.send(output));
+    });
+    async move {
+        // Spawning only fails once the executor has shut down.
+        if spawned.is_err() {
+            return Err(shutdown_error());
+        }
+        match receiver.await {
+            Ok(Ok(output)) => Ok(output),
+            Ok(Err(panic)) => panic::resume_unwind(panic),
+            Err(_) => Err(shutdown_error()),
+        }
+    }
+}
+
+// Runs `f` on the default executor and returns a future that resolves to its result.
+//
+// Once `f` has started, dropping the future doesn't stop it; its result is just discarded. If `f`
+// panics, the future resolves to an error, and if the executor shuts down before running it, the
+// future fails according to the shutdown policy.
+pub(crate) fn spawn_blocking<F, T>(f: F) -> impl Future<Output = CxxAsyncResult<T>> + Send + 'static
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let spawned = spawn_on(&*default_executor(), async move {
+        panic::catch_unwind(AssertUnwindSafe(f))
+    });
+    async move {
+        match spawned.await? {
+            Ok(value) => Ok(value),
+            Err(_) => Err(CxxAsyncException::new(
+                "Blocking closure panicked".to_owned().into_boxed_str(),
+            )),
+        }
+    }
+}
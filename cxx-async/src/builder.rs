@@ -0,0 +1,325 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+// cxx-async/src/builder.rs
+//
+//! Fluent configuration of bridged futures.
+//!
+//! See `IntoCxxAsyncFuture::builder()`.
+
+use crate::timer::Timer;
+use crate::timer::TimerKey;
+use crate::CxxAsyncException;
+use crate::CxxAsyncExecutor;
+use crate::CxxAsyncResult;
+use crate::IntoCxxAsyncFuture;
+use crate::OnExecutor;
+use futures::future::Either;
+use futures::task::Spawn;
+use std::cell::Cell;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::task::Context;
+use std::task::Poll;
+use std::time::Duration;
+
+/// How urgent a bridged future is, as reported by `current_priority()` while it's polled.
+///
+/// `cxx-async` doesn't schedule anything by priority itself. The priority is a hint for the
+/// future's body and for the executors that it spawns work onto.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum CxxAsyncPriority {
+    /// Work that can wait, such as prefetching.
+    Low,
+    /// The priority of futures that weren't given one.
+    #[default]
+    Normal,
+    /// Work that something is waiting on.
+    High,
+}
+
+thread_local! {
+    static CURRENT_PRIORITY: Cell<CxxAsyncPriority> = const { Cell::new(CxxAsyncPriority::Normal) };
+}
+
+/// Returns the priority that the future currently being polled was built with, via
+/// `FutureBuilder::priority()`.
+///
+/// This returns `CxxAsyncPriority::Normal` outside of such a future.
+pub fn current_priority() -> CxxAsyncPriority {
+    CURRENT_PRIORITY.with(Cell::get)
+}
+
+/// Builds a bridged future with any combination of a name, an executor, a priority, and a
+/// timeout. Create one with `IntoCxxAsyncFuture::builder()`.
+///
+/// Every option defaults to the behavior of a future created with `infallible()` or `fallible()`.
+pub struct FutureBuilder<Fut> {
+    name: Option<String>,
+    executor: Option<CxxAsyncExecutor>,
+    priority: Option<CxxAsyncPriority>,
+    timeout: Option<Duration>,
+    phantom: PhantomData<fn() -> Fut>,
+}
+
+impl<Fut> FutureBuilder<Fut>
+where
+    Fut: IntoCxxAsyncFuture,
+    Fut::Output: 'static,
+{
+    pub(crate) fn new() -> Self {
+        Self {
+            name: None,
+            executor: None,
+            priority: None,
+            timeout: None,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Names the future. The name appears when the future is debug-printed and in the error that
+    /// it fails with if it times out.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Makes `executor` available to the future's body through `current_executor()`, like
+    /// `IntoCxxAsyncFuture::from_on()`.
+    pub fn on<E>(mut self, executor: E) -> Self
+    where
+        E: Spawn + Send + Sync + 'static,
+    {
+        self.executor = Some(Arc::new(executor));
+        self
+    }
+
+    /// Makes `priority` available to the future's body through `current_priority()`.
+    pub fn priority(mut self, priority: CxxAsyncPriority) -> Self {
+        self.priority = Some(priority);
+        self
+    }
+
+    /// Fails the future if it hasn't finished within `timeout` of being first polled. The wrapped
+    /// future is dropped, and thereby cancelled, when that happens, and the error is a cancellation.
+    /// See `CxxAsyncException::cancelled()`.
+    ///
+    /// The deadline can be moved while the future runs, with the `reset_timeout()` method that the
+    /// `bridge` macro generates or with a `TimeoutHandle`.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Builds the bridged future from a Rust future that directly returns the output type.
+    pub fn build<F>(self, future: F) -> Fut
+    where
+        F: Future<Output = Fut::Output> + Send + 'static,
+    {
+        self.build_fallible(async move { Ok(future.await) })
+    }
+
+    /// Builds the bridged future from a Rust future that returns a `CxxAsyncResult`.
+    pub fn build_fallible<F>(self, future: F) -> Fut
+    where
+        F: Future<Output = CxxAsyncResult<Fut::Output>> + Send + 'static,
+    {
+        let FutureBuilder {
+            name,
+            executor,
+            priority,
+            timeout,
+            phantom: _,
+        } = self;
+
+        // The priority and executor wrap the future itself, so that only its own body sees them.
+        let future = WithPriority {
+            priority,
+            future: Box::pin(future),
+        };
+        let future: Pin<Box<dyn Future<Output = _> + Send>> = match executor {
+            Some(executor) => Box::pin(OnExecutor { executor, future }),
+            None => Box::pin(future),
+        };
+
+        let (mut bridged, handle) = match timeout {
+            None => (Fut::fallible(future), None),
+            Some(timeout) => {
+                let description = match name {
+                    Some(ref name) => format!("Future `{}`", name),
+                    None => "Future".to_owned(),
+                };
+                let handle = TimeoutHandle::new(timeout);
+                let timer = DeadlineTimer {
+                    handle: handle.clone(),
+                    timer: None,
+                };
+                let bridged = Fut::fallible(async move {
+                    match futures::future::select(future, timer).await {
+                        Either::Left((result, _)) => result,
+                        Either::Right((timeout, _)) => Err(CxxAsyncException::cancelled(
+                            format!("{} timed out after {:?}", description, timeout)
+                                .into_boxed_str(),
+                        )),
+                    }
+                });
+                (bridged, Some(handle))
+            }
+        };
+        if let Some(name) = name {
+            bridged.set_name(name);
+        }
+        if let Some(handle) = handle {
+            bridged.set_timeout_handle(handle);
+        }
+        bridged
+    }
+}
+
+/// Moves the deadline of a future built with `FutureBuilder::timeout()` while it runs.
+///
+/// Get one from the future's `timeout_handle()` method before handing the future off, for instance
+/// to extend the deadline each time a heartbeat shows that the work is still making progress.
+/// Handles can be cloned and used from any thread.
+#[derive(Clone)]
+pub struct TimeoutHandle {
+    shared: Arc<Deadline>,
+}
+
+// The deadline of one future, shared between its handles and the future.
+struct Deadline {
+    state: Mutex<DeadlineState>,
+}
+
+struct DeadlineState {
+    // The most recent timeout, which the error message reports.
+    timeout: Duration,
+    // The timer that fires at the deadline, or `None` if the future hasn't been polled yet.
+    timer: Option<TimerKey>,
+    // Set once the future has seen the deadline pass, since its timer is dropped afterward.
+    expired: bool,
+    // Set once the future has finished or been dropped, so that the deadline no longer matters.
+    finished: bool,
+}
+
+impl TimeoutHandle {
+    fn new(timeout: Duration) -> Self {
+        TimeoutHandle {
+            shared: Arc::new(Deadline {
+                state: Mutex::new(DeadlineState {
+                    timeout,
+                    timer: None,
+                    expired: false,
+                    finished: false,
+                }),
+            }),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, DeadlineState> {
+        self.shared
+            .state
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+    }
+
+    /// Moves the deadline to `timeout` from now, which may be earlier or later than before. If the
+    /// future hasn't been polled yet, `timeout` replaces the original timeout instead, counting
+    /// from the first poll as usual.
+    ///
+    /// Returns false, changing nothing, if the future has already timed out or finished. A reset
+    /// racing with the old deadline either returns true, in which case the future doesn't time
+    /// out until the new deadline, or returns false because the old deadline won.
+    pub fn reset(&self, timeout: Duration) -> bool {
+        let mut state = self.lock();
+        if state.expired || state.finished {
+            return false;
+        }
+        // The shared timer decides the race with the old deadline.
+        if let Some(timer) = state.timer {
+            if !timer.reset(timeout) {
+                return false;
+            }
+        }
+        state.timeout = timeout;
+        true
+    }
+
+    /// Returns true if the future has timed out.
+    pub fn has_expired(&self) -> bool {
+        let state = self.lock();
+        state.expired || state.timer.is_some_and(TimerKey::has_fired)
+    }
+}
+
+// Resolves to the latest timeout once the deadline passes. The clock starts at the first poll.
+struct DeadlineTimer {
+    handle: TimeoutHandle,
+    timer: Option<Timer>,
+}
+
+impl Future for DeadlineTimer {
+    type Output = Duration;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Duration> {
+        let this = &mut *self;
+        let mut state = this.handle.lock();
+        let timer = this.timer.get_or_insert_with(|| {
+            let timer = Timer::after(state.timeout);
+            state.timer = Some(timer.key());
+            timer
+        });
+        match Pin::new(timer).poll(cx) {
+            Poll::Ready(()) => {
+                state.expired = true;
+                Poll::Ready(state.timeout)
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl Drop for DeadlineTimer {
+    fn drop(&mut self) {
+        self.handle.lock().finished = true;
+    }
+}
+
+// A future that makes its priority available through `current_priority()` while it's polled.
+pub(crate) struct WithPriority<Fut> {
+    pub(crate) priority: Option<CxxAsyncPriority>,
+    pub(crate) future: Pin<Box<Fut>>,
+}
+
+// Restores the previous current priority when dropped, even if the poll panics.
+struct RestorePriority(CxxAsyncPriority);
+
+impl Drop for RestorePriority {
+    fn drop(&mut self) {
+        CURRENT_PRIORITY.with(|current| current.set(self.0));
+    }
+}
+
+impl<Fut> Future for WithPriority<Fut>
+where
+    Fut: Future,
+{
+    type Output = Fut::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let _restore = self.priority.map(|priority| {
+            RestorePriority(CURRENT_PRIORITY.with(|current| current.replace(priority)))
+        });
+        self.future.as_mut().poll(cx)
+    }
+}
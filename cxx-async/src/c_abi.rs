@@ -0,0 +1,304 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+// cxx-async/src/c_abi.rs
+//
+//! A plain C interface to bridged futures, for callers that can't use `cxx`.
+//!
+//! Adding `c_abi = prefix` to the `#[cxx_async::bridge]` attribute of a future type generates the
+//! following `#[no_mangle]` functions for it, where `T` is the C type corresponding to the
+//! future's output type:
+//!
+//! * `uint32_t prefix_poll(uint64_t handle, T* result, char** error, CxxAsyncCWaker waker)` polls
+//!   the future behind `handle`. It returns one of the `C_STATUS_` constants. On completion, the
+//!   value is moved into `*result`; on failure, if `error` isn't null, `*error` is set to the
+//!   exception message, which must be freed with `cxxasync_c_string_free()`. Either way, the
+//!   handle is then invalid.
+//!
+//! * `bool prefix_drop(uint64_t handle)` drops, and thereby cancels, the future behind `handle`.
+//!
+//! * `bool prefix_send(uint64_t sender, T* value)` completes the future created along with
+//!   `sender` by `channel()`, moving `*value` into it. If the sender handle is invalid, `*value` is
+//!   left alone.
+//!
+//! * `bool prefix_send_error(uint64_t sender, const char* what)` fails the future created along
+//!   with `sender` with an exception.
+//!
+//! Handles are never reused, and each remembers the future type it was created for, so a handle
+//! that has been consumed, dropped, or used with the wrong future type is rejected instead of
+//! causing undefined behavior. The functions that take a handle return false or
+//! `C_STATUS_INVALID_HANDLE` in that case.
+
+use crate::CxxAsyncException;
+use crate::CxxAsyncResult;
+use crate::IntoCxxAsyncFuture;
+use crate::SafeUnwrap;
+use futures::channel::oneshot;
+use futures::channel::oneshot::Sender;
+use futures::task::ArcWake;
+use once_cell::sync::Lazy;
+use std::any::Any;
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::ffi::CString;
+use std::future::Future;
+use std::os::raw::c_char;
+use std::os::raw::c_void;
+use std::pin::Pin;
+use std::ptr;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::task::Context;
+use std::task::Poll;
+
+/// The future isn't ready yet. The waker will be called when it's worth polling again.
+pub const C_STATUS_PENDING: u32 = 0;
+/// The future completed, and its value was written to the result pointer.
+pub const C_STATUS_COMPLETE: u32 = 1;
+/// The future failed, and its exception message was written to the error pointer.
+pub const C_STATUS_ERROR: u32 = 2;
+/// The handle doesn't refer to a live future of this type.
+pub const C_STATUS_INVALID_HANDLE: u32 = 3;
+
+/// A handle to a future or sender owned by Rust on behalf of a C caller. Zero is never a valid
+/// handle.
+pub type CxxAsyncCHandle = u64;
+
+/// A waker supplied by a C caller.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct CxxAsyncCWaker {
+    /// The pointer passed to `wake` and `release`. It must remain valid until `release` is called.
+    pub data: *mut c_void,
+    /// Called, possibly from another thread, when the future is worth polling again.
+    pub wake: Option<unsafe extern "C" fn(data: *mut c_void)>,
+    /// If not null, called once the future no longer needs the waker passed to one poll.
+    pub release: Option<unsafe extern "C" fn(data: *mut c_void)>,
+}
+
+struct CWaker(CxxAsyncCWaker);
+
+// SAFETY: The C caller promises that its waker can be called and released from any thread.
+unsafe impl Send for CWaker {}
+unsafe impl Sync for CWaker {}
+
+impl ArcWake for CWaker {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        if let Some(wake) = arc_self.0.wake {
+            unsafe { wake(arc_self.0.data) }
+        }
+    }
+}
+
+impl Drop for CWaker {
+    fn drop(&mut self) {
+        if let Some(release) = self.0.release {
+            unsafe { release(self.0.data) }
+        }
+    }
+}
+
+static HANDLES: Lazy<Mutex<HashMap<CxxAsyncCHandle, Arc<dyn Any + Send + Sync>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+static NEXT_HANDLE: AtomicU64 = AtomicU64::new(1);
+
+// The handle table holds futures and senders in these slots, so that a poll in progress doesn't
+// need to hold the table lock.
+type FutureSlot<Fut> = Mutex<Option<Fut>>;
+type SenderSlot<T> = Mutex<Option<Sender<CxxAsyncResult<T>>>>;
+
+fn insert<S>(slot: S) -> CxxAsyncCHandle
+where
+    S: Any + Send + Sync,
+{
+    let handle = NEXT_HANDLE.fetch_add(1, Ordering::Relaxed);
+    HANDLES.lock().safe_unwrap().insert(handle, Arc::new(slot));
+    handle
+}
+
+fn lookup<S>(handle: CxxAsyncCHandle) -> Option<Arc<S>>
+where
+    S: Any + Send + Sync,
+{
+    let slot = HANDLES.lock().safe_unwrap().get(&handle)?.clone();
+    slot.downcast().ok()
+}
+
+// Removes `handle` from the table, but only if it refers to a slot of type `S`.
+fn remove<S>(handle: CxxAsyncCHandle) -> Option<Arc<S>>
+where
+    S: Any + Send + Sync,
+{
+    let mut handles = HANDLES.lock().safe_unwrap();
+    if !handles.get(&handle)?.is::<S>() {
+        return None;
+    }
+    handles.remove(&handle)?.downcast().ok()
+}
+
+/// Hands ownership of a bridged future to a C caller, returning a handle to pass to the poll and
+/// drop functions generated for its type.
+pub fn into_handle<Fut>(future: Fut) -> CxxAsyncCHandle
+where
+    Fut: Future + Send + 'static,
+{
+    insert::<FutureSlot<Fut>>(Mutex::new(Some(future)))
+}
+
+/// Creates a bridged future that a C caller completes, returning the future and a handle to pass
+/// to the send functions generated for its type.
+///
+/// If the sender handle is never used, the future never completes.
+pub fn channel<Fut>() -> (Fut, CxxAsyncCHandle)
+where
+    Fut: IntoCxxAsyncFuture,
+    Fut::Output: Send + 'static,
+{
+    let (sender, receiver) = oneshot::channel::<CxxAsyncResult<Fut::Output>>();
+    let handle = insert::<SenderSlot<Fut::Output>>(Mutex::new(Some(sender)));
+    let future = Fut::fallible(async move {
+        match receiver.await {
+            Ok(result) => result,
+            Err(_) => Err(CxxAsyncException::new(
+                "The C sender was dropped".to_owned().into_boxed_str(),
+            )),
+        }
+    });
+    (future, handle)
+}
+
+/// Frees a string allocated by Rust for a C caller, such as an exception message.
+///
+/// # Safety
+///
+/// `string` must be null or have been returned by Rust through this interface, and must not be
+/// used afterward.
+#[no_mangle]
+pub unsafe extern "C" fn cxxasync_c_string_free(string: *mut c_char) {
+    if !string.is_null() {
+        drop(CString::from_raw(string));
+    }
+}
+
+// The implementation of the generated `poll` function.
+//
+// # Safety
+//
+// If the future completes, `result` must be valid for writes. `error` must be null or valid for
+// writes.
+#[doc(hidden)]
+pub unsafe fn poll<Fut, T>(
+    handle: CxxAsyncCHandle,
+    result: *mut T,
+    error: *mut *mut c_char,
+    waker: CxxAsyncCWaker,
+) -> u32
+where
+    Fut: Future<Output = CxxAsyncResult<T>> + Unpin + Send + 'static,
+{
+    let slot = match lookup::<FutureSlot<Fut>>(handle) {
+        Some(slot) => slot,
+        None => return C_STATUS_INVALID_HANDLE,
+    };
+    let mut future = slot.lock().safe_unwrap();
+    let poll = match *future {
+        Some(ref mut future) => {
+            let waker = futures::task::waker(Arc::new(CWaker(waker)));
+            Pin::new(future).poll(&mut Context::from_waker(&waker))
+        }
+        // The handle was dropped while we were waiting for the lock.
+        None => return C_STATUS_INVALID_HANDLE,
+    };
+    let value = match poll {
+        Poll::Pending => return C_STATUS_PENDING,
+        Poll::Ready(value) => value,
+    };
+    *future = None;
+    drop(future);
+    remove::<FutureSlot<Fut>>(handle);
+
+    match value {
+        Ok(value) => {
+            ptr::write(result, value);
+            C_STATUS_COMPLETE
+        }
+        Err(exception) => {
+            if !error.is_null() {
+                let what = exception.what().replace('\0', "");
+                *error = CString::new(what).safe_unwrap().into_raw();
+            }
+            C_STATUS_ERROR
+        }
+    }
+}
+
+// The implementation of the generated `drop` function.
+#[doc(hidden)]
+pub fn drop_handle<Fut>(handle: CxxAsyncCHandle) -> bool
+where
+    Fut: Send + 'static,
+{
+    match remove::<FutureSlot<Fut>>(handle) {
+        Some(slot) => {
+            let future = slot.lock().safe_unwrap().take();
+            future.is_some()
+        }
+        None => false,
+    }
+}
+
+// The implementation of the generated `send` function.
+//
+// # Safety
+//
+// If `sender` is valid, `value` must point to a value that we can take ownership of.
+#[doc(hidden)]
+pub unsafe fn send<T>(sender: CxxAsyncCHandle, value: *mut T) -> bool
+where
+    T: Send + 'static,
+{
+    complete::<T>(sender, || Ok(ptr::read(value)))
+}
+
+// The implementation of the generated `send_error` function.
+//
+// # Safety
+//
+// If `sender` is valid, `what` must be a valid null-terminated string.
+#[doc(hidden)]
+pub unsafe fn send_error<T>(sender: CxxAsyncCHandle, what: *const c_char) -> bool
+where
+    T: Send + 'static,
+{
+    complete::<T>(sender, || {
+        Err(CxxAsyncException::new(
+            CStr::from_ptr(what).to_string_lossy().into(),
+        ))
+    })
+}
+
+// Completes the future created with `sender`. The value is only produced if the handle is valid,
+// so that an invalid handle doesn't take ownership of it.
+fn complete<T>(sender: CxxAsyncCHandle, getter: impl FnOnce() -> CxxAsyncResult<T>) -> bool
+where
+    T: Send + 'static,
+{
+    let slot = match remove::<SenderSlot<T>>(sender) {
+        Some(slot) => slot,
+        None => return false,
+    };
+    let sender = slot.lock().safe_unwrap().take();
+    match sender {
+        Some(sender) => sender.send(getter()).is_ok(),
+        None => false,
+    }
+}
@@ -0,0 +1,112 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+// cxx-async/src/cache.rs
+//
+//! Coalescing of concurrent requests for the same expensive computation.
+
+use crate::CxxAsyncResult;
+use crate::SafeUnwrap;
+use futures::future::BoxFuture;
+use futures::future::Shared;
+use futures::FutureExt;
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::Mutex;
+
+/// Determines which finished computations a `FutureCache` keeps around.
+///
+/// Finished computations are evicted lazily: when their key is requested again, or when
+/// `FutureCache::purge()` is called.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// Only computations that are still running are shared. Once a computation finishes, the next
+    /// request for its key starts a new one.
+    EvictCompleted,
+    /// Successful results are kept and handed out to every later request for the same key.
+    /// Failed computations are evicted, so that the next request retries.
+    KeepSuccesses,
+}
+
+/// A map from keys to shared futures, so that concurrent callers asking for the same key await a
+/// single computation.
+///
+/// This is useful for idempotent, expensive C++ computations, where several requests for the same
+/// input can be coalesced into one.
+pub struct FutureCache<K, T> {
+    entries: Mutex<HashMap<K, Shared<BoxFuture<'static, CxxAsyncResult<T>>>>>,
+    policy: EvictionPolicy,
+}
+
+impl<K, T> FutureCache<K, T>
+where
+    K: Eq + Hash,
+    T: Clone + Send + Sync + 'static,
+{
+    /// Creates an empty cache that evicts finished computations according to `policy`.
+    pub fn new(policy: EvictionPolicy) -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+            policy,
+        }
+    }
+
+    /// Returns a future that resolves to a clone of the result of the computation for `key`.
+    ///
+    /// If there's no suitable computation for `key` in the cache, this starts one by calling
+    /// `factory`. The factory isn't called until the returned future is first polled, so it's
+    /// never called while the cache is locked.
+    pub fn get_or_compute<F, Fut>(
+        &self,
+        key: K,
+        factory: F,
+    ) -> impl Future<Output = CxxAsyncResult<T>> + Send + 'static
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = CxxAsyncResult<T>> + Send + 'static,
+    {
+        let mut entries = self.entries.lock().safe_unwrap();
+        if let Some(future) = entries.get(&key) {
+            if !self.should_evict(future) {
+                return future.clone();
+            }
+        }
+        let future = async move { factory().await }.boxed().shared();
+        entries.insert(key, future.clone());
+        future
+    }
+
+    /// Evicts every finished computation that the eviction policy doesn't keep.
+    pub fn purge(&self) {
+        self.entries
+            .lock()
+            .safe_unwrap()
+            .retain(|_, future| !self.should_evict(future));
+    }
+
+    /// Removes the computation for `key`, if any, from the cache. Callers that are already
+    /// awaiting it still get its result.
+    pub fn remove(&self, key: &K) {
+        self.entries.lock().safe_unwrap().remove(key);
+    }
+
+    /// Removes every computation from the cache.
+    pub fn clear(&self) {
+        self.entries.lock().safe_unwrap().clear();
+    }
+
+    fn should_evict(&self, future: &Shared<BoxFuture<'static, CxxAsyncResult<T>>>) -> bool {
+        match (self.policy, future.peek()) {
+            (_, None) => false,
+            (EvictionPolicy::EvictCompleted, Some(_)) => true,
+            (EvictionPolicy::KeepSuccesses, Some(result)) => result.is_err(),
+        }
+    }
+}
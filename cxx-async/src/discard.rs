@@ -0,0 +1,63 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+// cxx-async/src/discard.rs
+//
+//! Detection of bridged futures that are dropped without ever being polled.
+//!
+//! Dropping a future that nobody polled usually means that its work was thrown away by accident,
+//! for instance because a returned future was never awaited. In debug builds, every bridged future
+//! that is dropped before its first poll is reported, unless it was marked with `ignore()` first.
+//! By default, the report is a warning on standard error; use `set_hook()` to handle reports
+//! differently. Release builds don't check.
+//!
+//! Only futures with Rust bodies are checked, since their work hasn't even started if they were
+//! never polled. A C++ coroutine runs up to its first suspension before Rust sees its future, and
+//! dropping that future is the usual way to cancel it.
+
+use std::io;
+use std::io::Write;
+use std::sync::RwLock;
+
+type Hook = Box<dyn Fn(&str) + Send + Sync>;
+
+static HOOK: RwLock<Option<Hook>> = RwLock::new(None);
+
+/// Replaces the handler for reports of futures dropped without ever being polled, which receives
+/// a message describing the future.
+///
+/// The hook runs wherever the future is dropped, which may be in C++, so it must not panic. To
+/// fail a test over a discarded future, record the report and check it afterward.
+pub fn set_hook<F>(hook: F)
+where
+    F: Fn(&str) + Send + Sync + 'static,
+{
+    *HOOK.write().unwrap_or_else(|error| error.into_inner()) = Some(Box::new(hook));
+}
+
+// Reports a future that was dropped without ever being polled. The `bridge` macro calls this from
+// the future's `Drop` implementation in debug builds.
+#[doc(hidden)]
+pub fn report(future_type: &str, name: Option<&str>) {
+    let message = match name {
+        Some(name) => format!(
+            "{} `{}` was dropped without ever being polled; call `ignore()` on it if that's \
+             intended",
+            future_type, name
+        ),
+        None => format!(
+            "A {} was dropped without ever being polled; call `ignore()` on it if that's intended",
+            future_type
+        ),
+    };
+    match *HOOK.read().unwrap_or_else(|error| error.into_inner()) {
+        Some(ref hook) => hook(&message),
+        None => drop(writeln!(io::stderr(), "warning: {}", message)),
+    }
+}
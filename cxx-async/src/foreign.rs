@@ -0,0 +1,108 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+// cxx-async/src/foreign.rs
+//
+//! Buffers that C++ allocated and that must be freed by C++.
+//!
+//! Many C and C++ APIs hand out a raw buffer, a pointer and a length, along with a function that
+//! frees it, instead of a `std::vector`. A coroutine can wrap such a buffer in a
+//! `rust::async::ForeignBuffer` and `co_return` it, and Rust receives a `ForeignBuffer` that owns
+//! the bytes and calls the deleter when it's dropped.
+
+use crate::CxxAsyncResultKind;
+use crate::CxxAsyncResultRepr;
+use std::fmt::{self, Debug, Formatter};
+use std::mem;
+use std::ops::Deref;
+use std::slice;
+
+/// Frees the buffer of a `ForeignBuffer`, given its pointer and length.
+pub type ForeignBufferDeleter = unsafe extern "C" fn(data: *mut u8, len: usize);
+
+/// A byte buffer allocated by C++, which its deleter frees exactly once when the buffer is dropped.
+///
+/// This is the Rust side of `rust::async::ForeignBuffer` in `cxx_async.h`, and can be the output
+/// of a bridged future or the item of a bridged stream. It dereferences to the bytes. Whichever
+/// side ends up owning the buffer frees it, including when a future is dropped before anyone
+/// receives its value. Since that may happen on any thread, the deleter must be safe to call from
+/// any thread.
+#[repr(C)]
+pub struct ForeignBuffer {
+    data: *mut u8,
+    len: usize,
+    deleter: Option<ForeignBufferDeleter>,
+}
+
+// SAFETY: The buffer is only read through shared references, and the C++ side promises that the
+// deleter may be called from any thread.
+unsafe impl Send for ForeignBuffer {}
+unsafe impl Sync for ForeignBuffer {}
+
+impl ForeignBuffer {
+    /// Takes ownership of `len` bytes at `data`, to be freed by calling `deleter(data, len)`.
+    ///
+    /// # Safety
+    ///
+    /// `data` must be valid for reads of `len` bytes until `deleter` is called, and nothing else
+    /// may write to or free it in the meantime. If `data` is null, `len` must be zero, and
+    /// `deleter` isn't called.
+    pub unsafe fn from_raw_parts(data: *mut u8, len: usize, deleter: ForeignBufferDeleter) -> Self {
+        ForeignBuffer {
+            data,
+            len,
+            deleter: Some(deleter),
+        }
+    }
+
+    /// Gives up ownership of the buffer, returning its pointer, length, and deleter. The caller
+    /// becomes responsible for freeing it.
+    pub fn into_raw_parts(self) -> (*mut u8, usize, Option<ForeignBufferDeleter>) {
+        let parts = (self.data, self.len, self.deleter);
+        mem::forget(self);
+        parts
+    }
+}
+
+impl Deref for ForeignBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        if self.data.is_null() {
+            return &[];
+        }
+        // SAFETY: `from_raw_parts()` requires the buffer to be valid for reads until it's freed.
+        unsafe { slice::from_raw_parts(self.data, self.len) }
+    }
+}
+
+impl Drop for ForeignBuffer {
+    fn drop(&mut self) {
+        if self.data.is_null() {
+            return;
+        }
+        if let Some(deleter) = self.deleter {
+            unsafe { deleter(self.data, self.len) }
+        }
+    }
+}
+
+impl Debug for ForeignBuffer {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> fmt::Result {
+        formatter
+            .debug_struct("ForeignBuffer")
+            .field("len", &self.len)
+            .finish_non_exhaustive()
+    }
+}
+
+// The C++ type has a destructor, so it's moved across, and whichever side holds it frees it.
+unsafe impl CxxAsyncResultRepr for ForeignBuffer {
+    const KIND: CxxAsyncResultKind = CxxAsyncResultKind::Moved;
+}
@@ -147,8 +147,17 @@ extern crate link_cplusplus;
 use crate::execlet::Execlet;
 use crate::execlet::ExecletReaper;
 use crate::execlet::RustExeclet;
+use crate::task_local::TaskLocals;
+use crate::task_local::WithTaskLocals;
+use futures::future;
+use futures::stream::FuturesUnordered;
+use futures::task::Spawn;
 use futures::Stream;
 use futures::StreamExt;
+use std::any::Any;
+use std::cell::Cell;
+use std::cell::RefCell;
+use std::collections::VecDeque;
 use std::convert::From;
 use std::error::Error;
 use std::ffi::CStr;
@@ -159,12 +168,15 @@ use std::fmt::Result as FmtResult;
 use std::future::Future;
 use std::io;
 use std::io::Write;
+use std::mem;
 use std::os::raw::c_char;
 use std::panic;
 use std::panic::AssertUnwindSafe;
 use std::pin::Pin;
 use std::process;
 use std::ptr;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::task::Context;
@@ -172,17 +184,28 @@ use std::task::Poll;
 use std::task::RawWaker;
 use std::task::RawWakerVTable;
 use std::task::Waker;
+#[cfg(debug_assertions)]
+use std::thread::{self, ThreadId};
+use std::time::Duration;
+use std::time::SystemTime;
 
 const FUTURE_STATUS_PENDING: u32 = 0;
 const FUTURE_STATUS_COMPLETE: u32 = 1;
 const FUTURE_STATUS_ERROR: u32 = 2;
 const FUTURE_STATUS_RUNNING: u32 = 3;
+const FUTURE_STATUS_CANCELLED: u32 = 4;
 
 const SEND_RESULT_WAIT: u32 = 0;
 const SEND_RESULT_SENT: u32 = 1;
 const SEND_RESULT_FINISHED: u32 = 2;
 
+pub use blocking::default_executor;
+pub use blocking::set_default_executor;
 pub use cxx_async_macro::bridge;
+pub use foreign::ForeignBuffer;
+pub use futures::future::AbortHandle;
+pub use repr::CxxAsyncResultKind;
+pub use repr::CxxAsyncResultRepr;
 
 #[doc(hidden)]
 pub use pin_utils::unsafe_pinned;
@@ -226,6 +249,19 @@ macro_rules! safe_unreachable {
     };
 }
 
+// Logs a crossing of the FFI boundary at trace level. This compiles to nothing unless the
+// `trace-boundary` feature is enabled, so the arguments must not have side effects.
+#[cfg(feature = "trace-boundary")]
+macro_rules! trace_boundary {
+    ($($args:tt)*) => {
+        ::log::trace!($($args)*)
+    };
+}
+#[cfg(not(feature = "trace-boundary"))]
+macro_rules! trace_boundary {
+    ($($args:tt)*) => {};
+}
+
 trait SafeExpect {
     type Output;
     fn safe_expect(self, message: &str) -> Self::Output;
@@ -259,8 +295,24 @@ where
     }
 }
 
+pub mod blocking;
+pub mod builder;
+pub mod c_abi;
+pub mod cache;
+pub mod discard;
 #[doc(hidden)]
 pub mod execlet;
+pub mod foreign;
+
+#[cfg(feature = "poll-metrics")]
+pub mod metrics;
+pub mod repr;
+pub mod router;
+pub mod stream;
+pub mod task_local;
+mod timer;
+#[cfg(feature = "pending-watchdog")]
+pub mod watchdog;
 
 // Bridged glue functions.
 extern "C" {
@@ -282,22 +334,77 @@ static CXXASYNC_WAKER_VTABLE: RawWakerVTable = RawWakerVTable::new(
 /// Any exception that a C++ coroutine throws is automatically caught and converted into this error
 /// type.
 ///
-/// This is just a wrapper around the result of `std::exception::what()`.
-#[derive(Debug)]
+/// This is just a wrapper around the result of `std::exception::what()`. It's `Clone`, `Send`, and
+/// `Sync`, so that one error can be delivered to several awaiters.
+#[derive(Clone, PartialEq, Eq)]
 pub struct CxxAsyncException {
     what: Box<str>,
+    cancelled: bool,
 }
 
 impl CxxAsyncException {
     /// Creates a new exception with the given error message.
     pub fn new(what: Box<str>) -> Self {
-        Self { what }
+        Self {
+            what,
+            cancelled: false,
+        }
+    }
+
+    /// Creates an exception reporting that the future was cancelled rather than that it failed.
+    ///
+    /// A C++ coroutine awaiting a future that fails with this exception sees a
+    /// `rust::async::CxxAsyncCancelled` exception instead of a plain `rust::async::Error`. Aborted
+    /// futures and futures that time out fail with one of these.
+    pub fn cancelled(what: Box<str>) -> Self {
+        Self {
+            what,
+            cancelled: true,
+        }
+    }
+
+    /// Returns true if this exception reports a cancellation. See `CxxAsyncException::cancelled()`.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled
     }
 
     /// The value returned by `std::exception::what()`.
     pub fn what(&self) -> &str {
         &self.what
     }
+
+    /// Decodes this exception into the Rust error type `E`, or returns `None` if its message
+    /// wasn't encoded by `E`'s codec. See [`CxxAsyncErrorCodec`].
+    pub fn downcast<E>(&self) -> Option<E>
+    where
+        E: CxxAsyncErrorCodec,
+    {
+        E::decode(&self.what)
+    }
+}
+
+impl From<&str> for CxxAsyncException {
+    fn from(what: &str) -> Self {
+        Self::new(what.into())
+    }
+}
+
+impl From<String> for CxxAsyncException {
+    fn from(what: String) -> Self {
+        Self::new(what.into_boxed_str())
+    }
+}
+
+// Only cancellations mention the flag, so that ordinary failures print just their message.
+impl Debug for CxxAsyncException {
+    fn fmt(&self, formatter: &mut Formatter) -> FmtResult {
+        let mut debug = formatter.debug_struct("CxxAsyncException");
+        debug.field("what", &self.what);
+        if self.cancelled {
+            debug.field("cancelled", &true);
+        }
+        debug.finish()
+    }
 }
 
 impl Display for CxxAsyncException {
@@ -308,9 +415,267 @@ impl Display for CxxAsyncException {
 
 impl Error for CxxAsyncException {}
 
+/// The default for `set_max_exception_message_len()`: 64 KiB.
+pub const DEFAULT_MAX_EXCEPTION_MESSAGE_LEN: usize = 64 * 1024;
+
+static MAX_EXCEPTION_MESSAGE_LEN: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_EXCEPTION_MESSAGE_LEN);
+
+/// Sets the maximum length, in bytes, of the message of a C++ exception that Rust copies into a
+/// `CxxAsyncException`.
+///
+/// Longer messages are cut off at that length, and a note of their original length is appended, so
+/// that C++ code throwing pathologically large exceptions can't make Rust allocate without bound.
+/// This affects exceptions thrown after the call.
+pub fn set_max_exception_message_len(len: usize) {
+    MAX_EXCEPTION_MESSAGE_LEN.store(len, Ordering::Relaxed);
+}
+
+// Errors are shared between awaiters (for example, by `FutureCache`), so they must stay thread-safe.
+const _: fn() = || {
+    fn check<T>()
+    where
+        T: Send + Sync,
+    {
+    }
+    check::<CxxAsyncException>();
+};
+
+/// A Rust error type that can be encoded into a [`CxxAsyncException`] and decoded back out of one.
+///
+/// Only the exception message crosses the language boundary, so a codec survives the error being
+/// caught and rethrown on the C++ side, as long as the message is preserved. Implementing this
+/// trait is all that's needed to use a type with the codec: `?` converts it into a
+/// `CxxAsyncException` via `From`, `IntoCxxAsyncFuture::fallible_with_codec` wraps futures that
+/// fail with it, and [`CxxAsyncException::downcast`] recovers it.
+///
+/// ```
+/// use cxx_async::{CxxAsyncErrorCodec, CxxAsyncException};
+///
+/// #[derive(Debug, PartialEq)]
+/// enum LookupError {
+///     NotFound(String),
+///     PermissionDenied,
+/// }
+///
+/// impl CxxAsyncErrorCodec for LookupError {
+///     fn encode(&self) -> String {
+///         match self {
+///             LookupError::NotFound(key) => format!("not found: {}", key),
+///             LookupError::PermissionDenied => "permission denied".to_owned(),
+///         }
+///     }
+///
+///     fn decode(what: &str) -> Option<Self> {
+///         match what.strip_prefix("not found: ") {
+///             Some(key) => Some(LookupError::NotFound(key.to_owned())),
+///             None if what == "permission denied" => Some(LookupError::PermissionDenied),
+///             None => None,
+///         }
+///     }
+/// }
+///
+/// let exception = CxxAsyncException::from(LookupError::NotFound("key".to_owned()));
+/// assert_eq!(exception.what(), "not found: key");
+/// assert_eq!(exception.downcast(), Some(LookupError::NotFound("key".to_owned())));
+/// assert_eq!(CxxAsyncException::from("kapow").downcast::<LookupError>(), None);
+/// ```
+pub trait CxxAsyncErrorCodec: Sized {
+    /// Encodes this error as the message of a `CxxAsyncException`.
+    fn encode(&self) -> String;
+
+    /// Decodes an error from the message of a `CxxAsyncException`, or returns `None` if the
+    /// message wasn't produced by `encode()`.
+    fn decode(what: &str) -> Option<Self>;
+}
+
+impl<E> From<E> for CxxAsyncException
+where
+    E: CxxAsyncErrorCodec,
+{
+    fn from(error: E) -> Self {
+        Self::from(error.encode())
+    }
+}
+
+/// A Rust type that crosses the language boundary encoded as another type, by default a byte
+/// buffer.
+///
+/// This is an escape hatch for payloads that `cxx` can't share directly. Declare a future whose
+/// output is the encoded type, such as `Vec<u8>` (a `rust::Vec<uint8_t>` on the C++ side), and
+/// name the payload type in its attribute with `#[cxx_async::bridge(payload = MyType)]`. The
+/// future then gains `from_payload()`, which encodes the value of a Rust future, and
+/// `into_payload()`, which decodes the value of a C++ one. The C++ side must read and write the
+/// same format.
+///
+/// `SystemTime` is a payload encoded as an `i64` count of nanoseconds since the Unix epoch, which
+/// is what `rust::async::to_epoch_nanos()` makes of a `std::chrono::system_clock::time_point` in
+/// C++. Encoding saturates at the limits of that range, about the years 1677 and 2262, and
+/// decoding fails if the platform can't represent the time.
+///
+/// ```
+/// use cxx_async::{CxxAsyncPayload, CxxAsyncResult};
+///
+/// #[derive(Debug, PartialEq)]
+/// struct Point {
+///     x: i16,
+///     y: i16,
+/// }
+///
+/// impl CxxAsyncPayload for Point {
+///     fn encode(self) -> Vec<u8> {
+///         [self.x.to_le_bytes(), self.y.to_le_bytes()].concat()
+///     }
+///
+///     fn decode(bytes: Vec<u8>) -> CxxAsyncResult<Self> {
+///         match bytes[..] {
+///             [x0, x1, y0, y1] => Ok(Point {
+///                 x: i16::from_le_bytes([x0, x1]),
+///                 y: i16::from_le_bytes([y0, y1]),
+///             }),
+///             _ => Err("expected 4 bytes".into()),
+///         }
+///     }
+/// }
+///
+/// let point = Point { x: 1, y: -2 };
+/// assert_eq!(Point::decode(Point { x: 1, y: -2 }.encode()).unwrap(), point);
+/// assert!(Point::decode(vec![0]).is_err());
+/// ```
+pub trait CxxAsyncPayload<Encoded = Vec<u8>>: Sized {
+    /// Serializes this value into what's sent across the bridge.
+    fn encode(self) -> Encoded;
+
+    /// Deserializes a value received from across the bridge. An error fails the future that
+    /// delivered it.
+    fn decode(encoded: Encoded) -> CxxAsyncResult<Self>;
+}
+
+impl CxxAsyncPayload<i64> for SystemTime {
+    fn encode(self) -> i64 {
+        match self.duration_since(SystemTime::UNIX_EPOCH) {
+            Ok(after) => i64::try_from(after.as_nanos()).unwrap_or(i64::MAX),
+            Err(before) => i64::try_from(before.duration().as_nanos())
+                .map(|nanos| -nanos)
+                .unwrap_or(i64::MIN),
+        }
+    }
+
+    fn decode(nanos: i64) -> CxxAsyncResult<Self> {
+        let offset = Duration::from_nanos(nanos.unsigned_abs());
+        let time = if nanos >= 0 {
+            SystemTime::UNIX_EPOCH.checked_add(offset)
+        } else {
+            SystemTime::UNIX_EPOCH.checked_sub(offset)
+        };
+        time.ok_or_else(|| {
+            format!(
+                "{}ns from the Unix epoch is out of range for SystemTime",
+                nanos
+            )
+            .into()
+        })
+    }
+}
+
 /// A convenient shorthand for `Result<T, CxxAsyncException>`.
 pub type CxxAsyncResult<T> = Result<T, CxxAsyncException>;
 
+/// Returns early from a fallible function or async block with a [`CxxAsyncException`].
+///
+/// The arguments are formatted as with `format!`, and the result becomes the string returned by
+/// `std::exception::what()` on the C++ side. This is the analogue of `anyhow::bail!`:
+///
+/// ```
+/// use cxx_async::{cxx_async_bail, CxxAsyncResult};
+///
+/// fn checked_sqrt(x: f64) -> CxxAsyncResult<f64> {
+///     if x < 0.0 {
+///         cxx_async_bail!("can't take the square root of {}", x);
+///     }
+///     if x.is_nan() {
+///         return Err("kapow".into());
+///     }
+///     Ok(x.sqrt())
+/// }
+///
+/// assert_eq!(checked_sqrt(4.0).unwrap(), 2.0);
+/// assert_eq!(checked_sqrt(-1.0).unwrap_err().what(), "can't take the square root of -1");
+/// assert_eq!(checked_sqrt(f64::NAN).unwrap_err().what(), "kapow");
+/// ```
+#[macro_export]
+macro_rules! cxx_async_bail {
+    ($($args:tt)*) => {
+        return ::std::result::Result::Err($crate::CxxAsyncException::from(::std::format!($($args)*)))
+    };
+}
+
+/// A way out of a bridged future's body before it reaches its end, carrying either the future's
+/// value or an exception.
+///
+/// Helpers that return `Result<_, CxxAsyncExit<T>>` can finish the enclosing future from any depth
+/// with `?`: `complete_early()` resolves it with a value and `fail_early()` fails it. Ordinary
+/// errors propagate through `?` as well, because `CxxAsyncException` converts into this type. Wrap
+/// the body with `IntoCxxAsyncFuture::fallible_with_early_exit()` to turn an early exit back into
+/// the future's result.
+///
+/// ```
+/// use cxx_async::{complete_early, fail_early, CxxAsyncExit};
+///
+/// fn lookup(key: &str) -> Result<(), CxxAsyncExit<f64>> {
+///     match key {
+///         "pi" => complete_early(3.14),
+///         "" => fail_early("empty key"),
+///         _ => Ok(()),
+///     }
+/// }
+///
+/// let body = |key| -> Result<f64, CxxAsyncExit<f64>> {
+///     lookup(key)?;
+///     Ok(0.0)
+/// };
+/// assert_eq!(body("pi").or_else(CxxAsyncExit::into_result).unwrap(), 3.14);
+/// assert_eq!(body("e").or_else(CxxAsyncExit::into_result).unwrap(), 0.0);
+/// assert!(body("").or_else(CxxAsyncExit::into_result).is_err());
+/// ```
+#[derive(Debug)]
+pub enum CxxAsyncExit<T> {
+    /// Resolve the future with this value.
+    Complete(T),
+    /// Fail the future with this exception.
+    Fail(CxxAsyncException),
+}
+
+impl<T> CxxAsyncExit<T> {
+    /// Converts the exit into the result that the enclosing future resolves to.
+    pub fn into_result(self) -> CxxAsyncResult<T> {
+        match self {
+            CxxAsyncExit::Complete(value) => Ok(value),
+            CxxAsyncExit::Fail(exception) => Err(exception),
+        }
+    }
+}
+
+impl<T> From<CxxAsyncException> for CxxAsyncExit<T> {
+    fn from(exception: CxxAsyncException) -> Self {
+        CxxAsyncExit::Fail(exception)
+    }
+}
+
+/// Finishes the enclosing bridged future with `value` when propagated with `?`. See
+/// [`CxxAsyncExit`].
+pub fn complete_early<T, U>(value: T) -> Result<U, CxxAsyncExit<T>> {
+    Err(CxxAsyncExit::Complete(value))
+}
+
+/// Fails the enclosing bridged future with `error` when propagated with `?`. See
+/// [`CxxAsyncExit`].
+pub fn fail_early<T, U, E>(error: E) -> Result<U, CxxAsyncExit<T>>
+where
+    E: Into<CxxAsyncException>,
+{
+    Err(CxxAsyncExit::Fail(error.into()))
+}
+
 // A table of functions that the `bridge` macro emits for the C++ bridge to use.
 //
 // This must match the definition in `cxx_async.h`.
@@ -322,6 +687,11 @@ pub struct CxxAsyncVtable {
     pub sender_drop: *mut u8,
     pub future_poll: *mut u8,
     pub future_drop: *mut u8,
+    pub sender_send_batch: *mut u8,
+    pub sender_on_cancel: *mut u8,
+    pub result_kind: *mut u8,
+    pub future_poll_packed: *mut u8,
+    pub result_layout: *mut u8,
 }
 
 unsafe impl Send for CxxAsyncVtable {}
@@ -363,6 +733,16 @@ where
 // channel, if we try to send a value when the buffer is full and the receiving end is woken up and
 // then tries to receive the value, a deadlock occurs, as the MPSC channel doesn't drop locks before
 // calling the waker.
+//
+// For the same reason, every method here must release the lock before waking the waiter. Waking a
+// suspended C++ coroutine resumes it synchronously on the current stack, and that coroutine may
+// immediately complete a future, and so re-enter this channel, while we're still inside `poll`.
+//
+// Every transfer of a value, an exception, or the closing of the channel happens under the lock,
+// and the receiving end takes the same lock to observe it. Unlocking the mutex is a release and
+// locking it is an acquire, so everything that the sending thread wrote before sending happens
+// before everything that the receiving thread does after receiving. Users rely on this (see the
+// README), so keep it that way if this ever stops using a mutex.
 struct SpscChannel<T>(Arc<Mutex<SpscChannelImpl<T>>>);
 
 // Data for each SPSC channel.
@@ -374,10 +754,38 @@ struct SpscChannelImpl<T> {
     waiter: Option<Waker>,
     // The value waiting to be read.
     value: Option<T>,
+    // Values waiting to be read after `value`, if the sending end sent several at once.
+    batch: VecDeque<T>,
     // An exception from the C++ side that is to be delivered over to the Rust side.
     exception: Option<CxxAsyncException>,
     // True if the channel is closed; false otherwise.
     closed: bool,
+    // True if the receiving end has been dropped; false otherwise.
+    disconnected: bool,
+    // True if the receiving end has asked the sending end to finish early; false otherwise.
+    cancel_requested: bool,
+    // Callbacks that the sending end registered to learn that the receiving end was dropped.
+    cancel_callbacks: Vec<CancelCallback>,
+}
+
+// A callback that a C++ coroutine registers in order to be told when Rust drops the future or
+// stream that the coroutine is producing.
+//
+// The callback is called exactly once: with `cancelled` set to true if the receiving end is dropped
+// first, or with `cancelled` set to false if the sending end is dropped first. Either way, the
+// callback takes ownership of `data`.
+struct CancelCallback {
+    callback: unsafe extern "C" fn(data: *mut u8, cancelled: bool),
+    data: *mut u8,
+}
+
+// SAFETY: The C++ side promises that the callback may be called from any thread.
+unsafe impl Send for CancelCallback {}
+
+impl CancelCallback {
+    fn call(self, cancelled: bool) {
+        unsafe { (self.callback)(self.data, cancelled) }
+    }
 }
 
 impl<T> SpscChannel<T> {
@@ -386,8 +794,12 @@ impl<T> SpscChannel<T> {
         SpscChannel(Arc::new(Mutex::new(SpscChannelImpl {
             waiter: None,
             value: None,
+            batch: VecDeque::new(),
             exception: None,
             closed: false,
+            disconnected: false,
+            cancel_requested: false,
+            cancel_callbacks: vec![],
         })))
     }
 
@@ -415,6 +827,10 @@ impl<T> SpscChannel<T> {
     //
     // This callback-based design eliminates the requirement to return the original value if the
     // send fails.
+    //
+    // If the receiving end has been dropped, a send without a context consumes the value and drops
+    // it, while a send with a context fails without registering the waker. See
+    // `SpscChannel::disconnect()`.
     fn try_send_value_with<F>(&self, context: Option<&Context>, getter: F) -> bool
     where
         F: FnOnce() -> T,
@@ -423,7 +839,14 @@ impl<T> SpscChannel<T> {
         let waiter;
         {
             let mut this = self.0.lock().safe_unwrap();
-            if this.value.is_none() {
+            if this.disconnected {
+                drop(this);
+                if context.is_some() {
+                    return false;
+                }
+                drop(getter());
+                return true;
+            } else if this.value.is_none() {
                 this.value = Some(getter());
                 waiter = this.waiter.take();
             } else if context.is_some() && this.waiter.is_some() {
@@ -442,6 +865,116 @@ impl<T> SpscChannel<T> {
         true
     }
 
+    // Attempts to send `count` values at once. If this channel has any values yet to be read, this
+    // function returns false. Otherwise, it calls the provided closure with each index from 0 to
+    // `count` to retrieve the values, in order, and returns true.
+    //
+    // If the receiving end has been dropped, this behaves like `try_send_value_with()`.
+    fn try_send_values_with<F>(&self, context: Option<&Context>, count: usize, getter: F) -> bool
+    where
+        F: FnMut(usize) -> T,
+    {
+        // Drop the lock before possibly calling the waiter because we could deadlock otherwise.
+        let waiter;
+        {
+            let mut this = self.0.lock().safe_unwrap();
+            if this.disconnected {
+                drop(this);
+                if context.is_some() {
+                    return false;
+                }
+                (0..count).map(getter).for_each(drop);
+                return true;
+            } else if this.value.is_none() {
+                safe_debug_assert!(this.batch.is_empty());
+                this.batch.extend((0..count).map(getter));
+                this.value = this.batch.pop_front();
+                waiter = this.waiter.take();
+            } else if context.is_some() && this.waiter.is_some() {
+                safe_panic!("Only one task may block on a `SpscChannel`!")
+            } else {
+                if let Some(context) = context {
+                    this.waiter = Some((*context.waker()).clone());
+                }
+                return false;
+            }
+        }
+
+        if let Some(waiter) = waiter {
+            waiter.wake();
+        }
+        true
+    }
+
+    // Detaches the receiving end from this channel. Only the receiving end may call this, when it's
+    // dropped.
+    //
+    // This first calls any cancel callbacks that the sending end registered, and then hands any
+    // values that were sent but never received to `drain`, in order, or drops them if there's no
+    // `drain`. Finally, it drops the waiter. If the waiter is a C++ stream coroutine waiting for
+    // room to yield a value, it will never be woken, so this drops the last reference to it, which
+    // destroys the coroutine and runs its destructors. Later sends from a stream coroutine likewise
+    // fail without registering a waker, which destroys the coroutine at its next yield.
+    fn disconnect(&self, drain: Option<&mut Box<dyn FnMut(T) + Send>>) {
+        // Drop the lock before dropping the values and the waiter, since either may run arbitrary
+        // code.
+        let (cancel_callbacks, value, batch, waiter);
+        {
+            let mut this = self.0.lock().safe_unwrap();
+            this.disconnected = true;
+            cancel_callbacks = mem::take(&mut this.cancel_callbacks);
+            value = this.value.take();
+            batch = mem::take(&mut this.batch);
+            waiter = this.waiter.take();
+        }
+        for cancel_callback in cancel_callbacks {
+            cancel_callback.call(true);
+        }
+        if let Some(drain) = drain {
+            value.into_iter().chain(batch).for_each(drain);
+        }
+        drop(waiter);
+    }
+
+    // Asks the sending end to finish early by calling its cancel callbacks, without disconnecting.
+    // Any value that the sending end sends afterward is still delivered.
+    fn request_cancel(&self) {
+        let cancel_callbacks = {
+            let mut this = self.0.lock().safe_unwrap();
+            this.cancel_requested = true;
+            mem::take(&mut this.cancel_callbacks)
+        };
+        for cancel_callback in cancel_callbacks {
+            cancel_callback.call(true);
+        }
+    }
+
+    // Registers a callback to be called when the receiving end is dropped or requests
+    // cancellation. If either has already happened, calls it immediately.
+    fn add_cancel_callback(&self, cancel_callback: CancelCallback) {
+        let mut this = self.0.lock().safe_unwrap();
+        if this.disconnected || this.cancel_requested {
+            drop(this);
+            cancel_callback.call(true);
+            return;
+        }
+        this.cancel_callbacks.push(cancel_callback);
+    }
+
+    // Tells any cancel callbacks that are still registered that the sending end has gone away
+    // without being cancelled. Only the sending end may call this, when it's dropped.
+    fn release_cancel_callbacks(&self) {
+        let cancel_callbacks = mem::take(&mut self.0.lock().safe_unwrap().cancel_callbacks);
+        for cancel_callback in cancel_callbacks {
+            cancel_callback.call(false);
+        }
+    }
+
+    // Returns true if the channel is closed.
+    fn is_closed(&self) -> bool {
+        self.0.lock().safe_unwrap().closed
+    }
+
     // Raises an exception. This is synchronous and thus should never fail. It must only be called
     // once, or not at all, for a given `SpscSender`.
     fn send_exception(&self, exception: CxxAsyncException) {
@@ -469,7 +1002,12 @@ impl<T> SpscChannel<T> {
             match this.value.take() {
                 Some(value) => {
                     result = Ok(value);
-                    waiter = this.waiter.take();
+                    // Don't wake a sending end waiting for room until the whole batch is read.
+                    this.value = this.batch.pop_front();
+                    waiter = match this.value {
+                        None => this.waiter.take(),
+                        Some(_) => None,
+                    };
                 }
                 None => match this.exception.take() {
                     Some(exception) => {
@@ -492,6 +1030,28 @@ impl<T> SpscChannel<T> {
     }
 }
 
+#[cfg(feature = "trace-boundary")]
+impl<T> SpscChannel<T> {
+    // Returns an address that identifies this channel in boundary traces. Both the sending and the
+    // receiving end report the same address.
+    fn id(&self) -> *const () {
+        Arc::as_ptr(&self.0) as *const ()
+    }
+}
+
+// Returns a human-readable name for a `FUTURE_STATUS_` constant for boundary traces.
+#[cfg(feature = "trace-boundary")]
+fn status_name(status: u32) -> &'static str {
+    match status {
+        FUTURE_STATUS_PENDING => "Pending",
+        FUTURE_STATUS_COMPLETE => "Complete",
+        FUTURE_STATUS_ERROR => "Error",
+        FUTURE_STATUS_RUNNING => "Running",
+        FUTURE_STATUS_CANCELLED => "Cancelled",
+        _ => "<invalid>",
+    }
+}
+
 impl<T> Clone for SpscChannel<T> {
     fn clone(&self) -> Self {
         Self(self.0.clone())
@@ -510,6 +1070,9 @@ pub struct CxxAsyncReceiver<Item> {
     receiver: SpscChannel<Item>,
     // Any execlet that must be driven when receiving.
     execlet: Option<Execlet>,
+    // Receives the items still buffered when a stream is dropped. See
+    // `IntoCxxAsyncStream::on_drain`.
+    drain: Option<Box<dyn FnMut(Item) + Send>>,
 }
 
 // The concrete type of the sending end of a stream.
@@ -521,7 +1084,10 @@ pub struct CxxAsyncSender<Item>(*mut SpscChannel<Item>);
 
 impl<Item> Drop for CxxAsyncSender<Item> {
     fn drop(&mut self) {
-        unsafe { drop(Box::from_raw(self.0)) }
+        let channel = unsafe { Box::from_raw(self.0) };
+        trace_boundary!("channel {:p}: sender dropped", channel.id());
+        channel.release_cancel_callbacks();
+        drop(channel)
     }
 }
 
@@ -533,10 +1099,22 @@ impl<Item> Stream for CxxAsyncReceiver<Item> {
     type Item = CxxAsyncResult<Item>;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        trace_boundary!("channel {:p}: poll called", self.receiver.id());
         if let Some(ref execlet) = self.execlet {
             execlet.run(cx);
         }
-        self.receiver.recv(cx)
+        let result = self.receiver.recv(cx);
+        trace_boundary!(
+            "channel {:p}: returned {}",
+            self.receiver.id(),
+            match result {
+                Poll::Ready(Some(Ok(_))) => "Running",
+                Poll::Ready(Some(Err(_))) => "Error",
+                Poll::Ready(None) => "Complete",
+                Poll::Pending => "Pending",
+            }
+        );
+        result
     }
 }
 
@@ -544,10 +1122,21 @@ impl<Output> Future for CxxAsyncReceiver<Output> {
     type Output = CxxAsyncResult<Output>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        trace_boundary!("channel {:p}: poll called", self.receiver.id());
         if let Some(ref execlet) = self.execlet {
             execlet.run(cx);
         }
-        match self.receiver.recv(cx) {
+        let result = self.receiver.recv(cx);
+        trace_boundary!(
+            "channel {:p}: returned {}",
+            self.receiver.id(),
+            match result {
+                Poll::Ready(Some(Ok(_))) => "Complete",
+                Poll::Ready(Some(Err(_))) | Poll::Ready(None) => "Error",
+                Poll::Pending => "Pending",
+            }
+        );
+        match result {
             Poll::Ready(Some(Ok(value))) => Poll::Ready(Ok(value)),
             Poll::Ready(Some(Err(exception))) => Poll::Ready(Err(exception)),
             Poll::Ready(None) => {
@@ -565,116 +1154,1018 @@ impl<Item> From<SpscChannel<Item>> for CxxAsyncReceiver<Item> {
         CxxAsyncReceiver {
             receiver,
             execlet: None,
+            drain: None,
         }
     }
 }
 
 impl<Item> Drop for CxxAsyncReceiver<Item> {
     fn drop(&mut self) {
+        trace_boundary!("channel {:p}: receiver dropped", self.receiver.id());
+        self.receiver.disconnect(self.drain.as_mut());
         let execlet = match self.execlet {
             Some(ref execlet) => execlet,
             None => return,
         };
-        let receiver = self.receiver.0.lock().safe_unwrap();
-        if receiver.closed {
+        if self.receiver.is_closed() {
             return;
         }
         ExecletReaper::get().add((*execlet).clone());
     }
 }
 
-// The sending end that the C++ bridge uses to return a value to a Rust future.
+// Wraps a future that isn't `Send` so that it can be boxed inside a bridged future.
 //
-// This is an implementation detail.
-#[doc(hidden)]
-pub trait RustSender {
-    type Output;
-    fn send(&mut self, value: CxxAsyncResult<Self::Output>);
+// In debug builds, this records the thread that created the future and panics if the future is
+// polled or dropped on any other thread. In release builds, this check is omitted.
+struct LocalFuture<Fut> {
+    future: Fut,
+    #[cfg(debug_assertions)]
+    thread: ThreadId,
+    #[cfg(debug_assertions)]
+    name: &'static str,
 }
 
-/// Wraps an arbitrary Rust Future in a boxed `cxx-async` future so that it can be returned to C++.
-///
-/// You should not need to implement this manually; it's automatically implemented by the `bridge`
-/// macro.
-pub trait IntoCxxAsyncFuture: Sized {
-    /// The type of the value yielded by the future.
-    type Output;
-
-    /// Wraps a Rust Future that directly returns the output type.
-    ///
-    /// Use this when you aren't interested in propagating errors to C++ as exceptions.
-    fn infallible<Fut>(future: Fut) -> Self
-    where
-        Fut: Future<Output = Self::Output> + Send + 'static,
-    {
-        Self::fallible(async move { Ok(future.await) })
+// SAFETY: The creator of a `LocalFuture` promises that it will only be polled and dropped on the
+// thread that created it.
+unsafe impl<Fut> Send for LocalFuture<Fut> {}
+
+impl<Fut> LocalFuture<Fut> {
+    unsafe_pinned!(future: Fut);
+
+    #[cfg_attr(not(debug_assertions), allow(unused_variables))]
+    fn new(future: Fut, name: &'static str) -> Self {
+        Self {
+            future,
+            #[cfg(debug_assertions)]
+            thread: thread::current().id(),
+            #[cfg(debug_assertions)]
+            name,
+        }
     }
-
-    /// Wraps a Rust Future that returns the output type, wrapped in a `CxxAsyncResult`.
-    ///
-    /// Use this when you have error values that you want to turn into exceptions on the C++ side.
-    fn fallible<Fut>(future: Fut) -> Self
-    where
-        Fut: Future<Output = CxxAsyncResult<Self::Output>> + Send + 'static;
 }
 
-/// Wraps an arbitrary Rust Stream in a boxed `cxx-async` stream so that it can be returned to C++.
-///
-/// You should not need to implement this manually; it's automatically implemented by the
-/// `bridge_stream` macro.
-pub trait IntoCxxAsyncStream: Sized {
-    /// The type of the values yielded by the stream.
-    type Item;
+impl<Fut> Future for LocalFuture<Fut>
+where
+    Fut: Future,
+{
+    type Output = Fut::Output;
 
-    /// Wraps a Rust Stream that directly yields items of the output type.
-    ///
-    /// Use this when you aren't interested in propagating errors to C++ as exceptions.
-    fn infallible<Stm>(stream: Stm) -> Self
-    where
-        Stm: Stream<Item = Self::Item> + Send + 'static,
-        Stm::Item: 'static,
-    {
-        Self::fallible(stream.map(Ok))
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        #[cfg(debug_assertions)]
+        if let Some(message) = self.check_thread("polled") {
+            panic!("{}", message);
+        }
+        self.future().poll(cx)
     }
+}
 
-    /// Wraps a Rust Stream that yields items of the output type, wrapped in `CxxAsyncResult`s.
-    ///
-    /// Use this when you have error values that you want to turn into exceptions on the C++ side.
-    fn fallible<Stm>(stream: Stm) -> Self
-    where
-        Stm: Stream<Item = CxxAsyncResult<Self::Item>> + Send + 'static;
+#[cfg(debug_assertions)]
+impl<Fut> LocalFuture<Fut> {
+    // Describes the misuse if the current thread isn't the one that created the future.
+    fn check_thread(&self, action: &str) -> Option<String> {
+        if thread::current().id() == self.thread {
+            return None;
+        }
+        Some(format!(
+            "`{}` is a single-threaded future created on thread {:?}, but it was {} on thread {:?}",
+            self.name,
+            self.thread,
+            action,
+            thread::current().id()
+        ))
+    }
 }
 
-// Creates a new oneshot sender/receiver pair for a future.
-//
-// SAFETY: This is a raw FFI function called by our C++ code.
-//
-// This needs an out pointer because of https://github.com/rust-lang/rust-bindgen/issues/778
-#[doc(hidden)]
-pub unsafe extern "C" fn future_channel<Fut, Out>(
-    out_oneshot: *mut CxxAsyncFutureChannel<Fut, Out>,
-    execlet: *mut RustExeclet,
-) where
-    Fut: From<CxxAsyncReceiver<Out>> + Future<Output = CxxAsyncResult<Out>>,
-{
-    let channel = SpscChannel::new();
-    let oneshot = CxxAsyncFutureChannel {
-        sender: CxxAsyncSender(Box::into_raw(Box::new(channel.clone()))),
-        future: CxxAsyncReceiver::<Out> {
-            receiver: channel,
-            execlet: Some(Execlet::from_raw_ref(execlet)),
+#[cfg(debug_assertions)]
+impl<Fut> Drop for LocalFuture<Fut> {
+    fn drop(&mut self) {
+        if let Some(message) = self.check_thread("dropped") {
+            // A future that panicked on the wrong thread is dropped there as the panic unwinds,
+            // and panicking again would abort, so just report it then.
+            if thread::panicking() {
+                eprintln!("{}", message);
+            } else {
+                panic!("{}", message);
+            }
         }
-        .into(),
-    };
-    ptr::write(out_oneshot, oneshot);
+    }
 }
 
-// Creates a new multi-shot sender/receiver pair for a stream.
-//
-// SAFETY: This is a raw FFI function called by our C++ code.
-//
-// This needs an out pointer because of https://github.com/rust-lang/rust-bindgen/issues/778
-#[doc(hidden)]
+/// A handle to the executor that a bridged future's body should spawn its subtasks onto.
+///
+/// See `IntoCxxAsyncFuture::from_on()` and `current_executor()`.
+pub type CxxAsyncExecutor = Arc<dyn Spawn + Send + Sync>;
+
+thread_local! {
+    static CURRENT_EXECUTOR: RefCell<Option<CxxAsyncExecutor>> = RefCell::new(None);
+}
+
+/// Returns the executor that the future currently being polled was created with, via
+/// `IntoCxxAsyncFuture::from_on()`.
+///
+/// This returns `None` outside of such a future. It isn't inherited by subtasks spawned onto the
+/// executor; pass it along explicitly if they need it.
+pub fn current_executor() -> Option<CxxAsyncExecutor> {
+    CURRENT_EXECUTOR.with(|current| current.borrow().clone())
+}
+
+// A future that makes its executor available through `current_executor()` while it's polled.
+struct OnExecutor<Fut> {
+    executor: CxxAsyncExecutor,
+    future: Fut,
+}
+
+impl<Fut> OnExecutor<Fut> {
+    unsafe_pinned!(future: Fut);
+}
+
+// Restores the previous current executor when dropped, even if the poll panics.
+struct RestoreExecutor(Option<CxxAsyncExecutor>);
+
+impl Drop for RestoreExecutor {
+    fn drop(&mut self) {
+        CURRENT_EXECUTOR.with(|current| *current.borrow_mut() = self.0.take());
+    }
+}
+
+impl<Fut> Future for OnExecutor<Fut>
+where
+    Fut: Future,
+{
+    type Output = Fut::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let executor = self.executor.clone();
+        let _restore =
+            RestoreExecutor(CURRENT_EXECUTOR.with(|current| current.replace(Some(executor))));
+        self.future().poll(cx)
+    }
+}
+
+// Runs a cleanup closure exactly once, when dropped.
+struct Finally<F>(Option<F>)
+where
+    F: FnOnce();
+
+impl<F> Drop for Finally<F>
+where
+    F: FnOnce(),
+{
+    fn drop(&mut self) {
+        if let Some(cleanup) = self.0.take() {
+            cleanup()
+        }
+    }
+}
+
+/// A bridged future with an opaque Rust-side context value attached.
+///
+/// This is created with `IntoCxxAsyncFuture::with_context()`. It resolves to the same value as the
+/// wrapped future. The context never crosses into C++; it stays here so that it can be recovered
+/// with `take_context()` once control returns to Rust.
+pub struct WithContext<Fut> {
+    future: Fut,
+    context: Option<Box<dyn Any + Send>>,
+}
+
+impl<Fut> WithContext<Fut> {
+    /// Removes and returns the attached context, if there is one and it has type `C`.
+    ///
+    /// If the context has a different type, it's left in place and this returns `None`.
+    pub fn take_context<C>(&mut self) -> Option<C>
+    where
+        C: 'static,
+    {
+        match self.context.take()?.downcast::<C>() {
+            Ok(context) => Some(*context),
+            Err(context) => {
+                self.context = Some(context);
+                None
+            }
+        }
+    }
+
+    /// Returns the wrapped future, discarding the context.
+    pub fn into_inner(self) -> Fut {
+        self.future
+    }
+}
+
+impl<Fut> Future for WithContext<Fut>
+where
+    Fut: Future + Unpin,
+{
+    type Output = Fut::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.future).poll(cx)
+    }
+}
+
+/// A bridged future that was started with `IntoCxxAsyncFuture::start()` and is running on the
+/// default executor.
+///
+/// Dropping this before joining it cancels the future.
+pub struct Started<T> {
+    future: Pin<Box<dyn Future<Output = CxxAsyncResult<T>> + Send>>,
+}
+
+impl<T> Started<T>
+where
+    T: 'static,
+{
+    /// Does nothing, since the future is already running.
+    pub fn start(self) -> Self {
+        self
+    }
+
+    /// Waits for the future to finish and returns its result.
+    ///
+    /// If the executor shut down before the future finished, this fails according to
+    /// `blocking::set_shutdown_policy()`.
+    pub async fn join(self) -> CxxAsyncResult<T> {
+        self.future.await
+    }
+}
+
+/// A bridged future with its output type erased, so that futures of different types can be kept
+/// in one collection and awaited together when only their completion matters.
+///
+/// Create one with `IntoCxxAsyncFuture::into_dyn()`. `Box<dyn DynCxxFuture>` is itself a future
+/// that resolves to `Ok(())` when the wrapped future succeeds, or to its error, so it works with
+/// `join_all()` and friends. The value is kept, and can be recovered by downcasting the result of
+/// `take_output()` to the output type.
+pub trait DynCxxFuture: Send + Unpin {
+    /// Polls the wrapped future, reporting only whether it has finished and whether it failed.
+    fn poll_done(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), CxxAsyncException>>;
+
+    /// Takes the value of a future that has finished successfully. Returns `None` if it hasn't
+    /// finished, if it failed, or if the value has already been taken.
+    fn take_output(&mut self) -> Option<Box<dyn Any + Send>>;
+}
+
+impl Future for dyn DynCxxFuture + '_ {
+    type Output = Result<(), CxxAsyncException>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.poll_done(cx)
+    }
+}
+
+// A bridged future behind `dyn DynCxxFuture`, along with its value once it has one.
+struct ErasedFuture<Fut, T> {
+    future: Fut,
+    output: Option<T>,
+}
+
+// The value is never pinned.
+impl<Fut, T> Unpin for ErasedFuture<Fut, T> where Fut: Unpin {}
+
+impl<Fut, T> DynCxxFuture for ErasedFuture<Fut, T>
+where
+    Fut: Future<Output = CxxAsyncResult<T>> + Send + Unpin,
+    T: Send + 'static,
+{
+    fn poll_done(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), CxxAsyncException>> {
+        let output = &mut self.output;
+        Pin::new(&mut self.future)
+            .poll(cx)
+            .map(|result| result.map(|value| *output = Some(value)))
+    }
+
+    fn take_output(&mut self) -> Option<Box<dyn Any + Send>> {
+        let output = self.output.take()?;
+        Some(Box::new(output))
+    }
+}
+
+/// The outcome of the most recent poll of a bridged future, as reported by the `last_status()`
+/// method that the `bridge` macro generates.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CxxAsyncPollStatus {
+    /// The future wasn't ready yet.
+    Pending,
+    /// The future resolved to a value.
+    Complete,
+    /// The future resolved to an error.
+    Error,
+}
+
+// A future that counts how many times it has been polled and remembers the outcome of the last
+// poll, to help diagnose futures that seem to be stuck. Every bridged future boxes one of these.
+//
+// The `future` field must be last so that this can be unsized into a trait object.
+#[doc(hidden)]
+pub struct PollTracked<Out, Fut>
+where
+    Fut: ?Sized,
+{
+    poll_count: u64,
+    last_status: Option<CxxAsyncPollStatus>,
+    // Asks the producer of the value to finish early, if the producer is C++. See
+    // `cancel_with_grace()`.
+    cancel_signal: Option<Box<dyn FnOnce() + Send>>,
+    // The waker from the last poll, so that `try_peek()` doesn't replace it with one that does
+    // nothing.
+    last_waker: Option<Waker>,
+    // The result, if `try_peek()` has found it, held until the next poll.
+    peeked: Option<Out>,
+    // A name for diagnostics. See `FutureBuilder::name()`.
+    name: Option<Box<str>>,
+    // Whether dropping the future unpolled is intended. See `IntoCxxAsyncFuture::ignore()`.
+    ignored: bool,
+    // Moves the deadline, if the future has one. See `FutureBuilder::timeout()`.
+    timeout: Option<builder::TimeoutHandle>,
+    #[cfg(feature = "pending-watchdog")]
+    watchdog: watchdog::Watchdog,
+    future: Fut,
+}
+
+impl<Out, Fut> PollTracked<Out, Fut> {
+    fn new(future: Fut, cancel_signal: Option<Box<dyn FnOnce() + Send>>) -> Self {
+        PollTracked {
+            poll_count: 0,
+            last_status: None,
+            cancel_signal,
+            last_waker: None,
+            peeked: None,
+            name: None,
+            ignored: false,
+            timeout: None,
+            #[cfg(feature = "pending-watchdog")]
+            watchdog: watchdog::Watchdog::new(),
+            future,
+        }
+    }
+}
+
+impl<Out, Fut> PollTracked<Out, Fut>
+where
+    Fut: ?Sized,
+{
+    pub fn poll_count(&self) -> u64 {
+        self.poll_count
+    }
+
+    pub fn last_status(&self) -> Option<CxxAsyncPollStatus> {
+        self.last_status
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    pub fn set_name(self: Pin<&mut Self>, name: Box<str>) {
+        // SAFETY: `name` isn't pinned.
+        unsafe { self.get_unchecked_mut() }.name = Some(name);
+    }
+
+    pub fn set_ignored(self: Pin<&mut Self>) {
+        // SAFETY: `ignored` isn't pinned.
+        unsafe { self.get_unchecked_mut() }.ignored = true;
+    }
+
+    pub fn timeout_handle(&self) -> Option<&builder::TimeoutHandle> {
+        self.timeout.as_ref()
+    }
+
+    pub fn set_timeout_handle(self: Pin<&mut Self>, handle: builder::TimeoutHandle) {
+        // SAFETY: `timeout` isn't pinned.
+        unsafe { self.get_unchecked_mut() }.timeout = Some(handle);
+    }
+
+    // Returns true if the future is being dropped by accident. See the `discard` module.
+    pub fn is_discarded(&self) -> bool {
+        self.poll_count == 0 && !self.ignored
+    }
+
+    pub fn signal_cancel(self: Pin<&mut Self>) {
+        // SAFETY: `cancel_signal` isn't pinned.
+        let cancel_signal = unsafe { self.get_unchecked_mut() }.cancel_signal.take();
+        if let Some(cancel_signal) = cancel_signal {
+            cancel_signal();
+        }
+    }
+}
+
+impl<Fut, T> PollTracked<CxxAsyncResult<T>, Fut>
+where
+    Fut: Future<Output = CxxAsyncResult<T>> + ?Sized,
+{
+    fn poll_future(&mut self, cx: &mut Context<'_>) -> Poll<CxxAsyncResult<T>> {
+        self.poll_count += 1;
+        // SAFETY: Our callers never move `future` out of `self`.
+        let poll = unsafe { Pin::new_unchecked(&mut self.future) }.poll(cx);
+        self.last_status = Some(match poll {
+            Poll::Pending => CxxAsyncPollStatus::Pending,
+            Poll::Ready(Ok(_)) => CxxAsyncPollStatus::Complete,
+            Poll::Ready(Err(_)) => CxxAsyncPollStatus::Error,
+        });
+        poll
+    }
+
+    pub fn try_peek(self: Pin<&mut Self>) -> Option<Result<&T, &CxxAsyncException>> {
+        // SAFETY: We never move `future` out of `self`, and the other fields aren't pinned.
+        let this = unsafe { self.get_unchecked_mut() };
+        if this.peeked.is_none() {
+            let waker = this
+                .last_waker
+                .clone()
+                .unwrap_or_else(futures::task::noop_waker);
+            match this.poll_future(&mut Context::from_waker(&waker)) {
+                Poll::Ready(result) => this.peeked = Some(result),
+                Poll::Pending => return None,
+            }
+        }
+        this.peeked.as_ref().map(Result::as_ref)
+    }
+}
+
+impl<Fut, T> Future for PollTracked<CxxAsyncResult<T>, Fut>
+where
+    Fut: Future<Output = CxxAsyncResult<T>> + ?Sized,
+{
+    type Output = CxxAsyncResult<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // SAFETY: We never move `future` out of `self`, and the other fields aren't pinned.
+        let this = unsafe { self.get_unchecked_mut() };
+        if let Some(result) = this.peeked.take() {
+            return Poll::Ready(result);
+        }
+        #[cfg(feature = "pending-watchdog")]
+        let waker = this.watchdog.before_poll(cx.waker());
+        #[cfg(feature = "pending-watchdog")]
+        let cx = &mut Context::from_waker(&waker);
+        if !this
+            .last_waker
+            .as_ref()
+            .is_some_and(|waker| waker.will_wake(cx.waker()))
+        {
+            this.last_waker = Some(cx.waker().clone());
+        }
+        let poll = {
+            let _polling = Polling::enter();
+            this.poll_future(cx)
+        };
+        #[cfg(feature = "pending-watchdog")]
+        {
+            let address = &this.future as *const Fut as *const ();
+            let name = &this.name;
+            this.watchdog.after_poll(poll.is_pending(), || match name {
+                Some(name) => format!("Future `{}`", name),
+                None => format!("Future {:p}", address),
+            });
+        }
+        poll
+    }
+}
+
+// The sending end that the C++ bridge uses to return a value to a Rust future.
+//
+// This is an implementation detail.
+#[doc(hidden)]
+pub trait RustSender {
+    type Output;
+    fn send(&mut self, value: CxxAsyncResult<Self::Output>);
+}
+
+/// Wraps an arbitrary Rust Future in a boxed `cxx-async` future so that it can be returned to C++.
+///
+/// You should not need to implement this manually; it's automatically implemented by the `bridge`
+/// macro.
+pub trait IntoCxxAsyncFuture: Sized {
+    /// The type of the value yielded by the future.
+    type Output;
+
+    /// Wraps a Rust Future that directly returns the output type.
+    ///
+    /// Use this when you aren't interested in propagating errors to C++ as exceptions.
+    fn infallible<Fut>(future: Fut) -> Self
+    where
+        Fut: Future<Output = Self::Output> + Send + 'static,
+    {
+        Self::fallible(async move { Ok(future.await) })
+    }
+
+    /// Wraps a Rust Future that returns the output type, wrapped in a `CxxAsyncResult`.
+    ///
+    /// Use this when you have error values that you want to turn into exceptions on the C++ side.
+    fn fallible<Fut>(future: Fut) -> Self
+    where
+        Fut: Future<Output = CxxAsyncResult<Self::Output>> + Send + 'static;
+
+    /// Wraps a Rust Future that returns the output type or an error with a codec, encoding any
+    /// error into a `CxxAsyncException`.
+    ///
+    /// See `CxxAsyncErrorCodec`.
+    fn fallible_with_codec<Fut, E>(future: Fut) -> Self
+    where
+        Fut: Future<Output = Result<Self::Output, E>> + Send + 'static,
+        E: CxxAsyncErrorCodec,
+    {
+        Self::fallible(async move { future.await.map_err(CxxAsyncException::from) })
+    }
+
+    /// Wraps a Rust Future whose body may finish early through a [`CxxAsyncExit`], resolving to
+    /// the value or exception that it exits with.
+    ///
+    /// See `complete_early()` and `fail_early()`.
+    fn fallible_with_early_exit<Fut>(future: Fut) -> Self
+    where
+        Fut: Future<Output = Result<Self::Output, CxxAsyncExit<Self::Output>>> + Send + 'static,
+    {
+        Self::fallible(async move { future.await.or_else(CxxAsyncExit::into_result) })
+    }
+
+    /// Wraps a Rust Future that resolves to another future, producing a single future that awaits
+    /// both levels.
+    ///
+    /// Use this to collapse a bridged future whose value is itself a bridged future (for example,
+    /// a `RustFutureFutureF64` yielding a `RustFutureF64`). An error at either level is propagated.
+    fn flatten<Fut, Inner>(future: Fut) -> Self
+    where
+        Fut: Future<Output = CxxAsyncResult<Inner>> + Send + 'static,
+        Inner: Future<Output = CxxAsyncResult<Self::Output>> + Send + 'static,
+    {
+        Self::fallible(async move { future.await?.await })
+    }
+
+    /// Wraps a Rust Future that directly returns the output type, capturing the task-locals `L`
+    /// now and re-establishing them around each poll.
+    ///
+    /// Use this when the future's body, or Rust code nested in it across C++ hops, reads
+    /// task-local context that would otherwise be missing on whichever thread polls it. Bridged
+    /// futures created inside the body need this too, in order to carry the context further.
+    fn with_task_locals<L, Fut>(future: Fut) -> Self
+    where
+        L: TaskLocals,
+        Fut: Future<Output = Self::Output> + Send + 'static,
+    {
+        Self::fallible_with_task_locals::<L, _>(async move { Ok(future.await) })
+    }
+
+    /// Like `with_task_locals`, but for a Rust Future that returns the output type wrapped in a
+    /// `CxxAsyncResult`.
+    fn fallible_with_task_locals<L, Fut>(future: Fut) -> Self
+    where
+        L: TaskLocals,
+        Fut: Future<Output = CxxAsyncResult<Self::Output>> + Send + 'static,
+    {
+        Self::fallible(WithTaskLocals {
+            locals: L::capture(),
+            future: Box::pin(future),
+        })
+    }
+
+    /// Creates a future that has already completed with `value`.
+    ///
+    /// This skips the state machine of an `async` block, so the first poll always returns the
+    /// value, and a C++ coroutine that awaits the future never suspends.
+    fn ready(value: Self::Output) -> Self
+    where
+        Self::Output: Send + 'static,
+    {
+        Self::fallible(future::ready(Ok(value)))
+    }
+
+    /// Creates a future that has already failed with `error`. Like `ready()`, the first poll always
+    /// returns the error.
+    fn failed<E>(error: E) -> Self
+    where
+        E: Into<CxxAsyncException>,
+        Self::Output: Send + 'static,
+    {
+        Self::fallible(future::ready(Err(error.into())))
+    }
+
+    /// Wraps the Rust Future that the closure `f` returns, which directly returns the output type.
+    ///
+    /// `f` isn't called until the bridged future is first polled, so none of its side effects,
+    /// including those of any code that runs before its future's first `await`, happen before
+    /// then. Capture the arguments that the body needs with `move`.
+    fn from_fn<F, Fut>(f: F) -> Self
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = Self::Output> + Send + 'static,
+    {
+        Self::infallible(async move { f().await })
+    }
+
+    /// Like `from_fn`, but for a closure whose Rust Future returns the output type wrapped in a
+    /// `CxxAsyncResult`.
+    fn fallible_from_fn<F, Fut>(f: F) -> Self
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = CxxAsyncResult<Self::Output>> + Send + 'static,
+    {
+        Self::fallible(async move { f().await })
+    }
+
+    /// Wraps a Rust Future that directly returns the output type, making `executor` available to it
+    /// through `current_executor()`.
+    ///
+    /// Use this to choose, per call, which executor the future's body spawns its subtasks onto.
+    fn from_on<E, Fut>(executor: E, future: Fut) -> Self
+    where
+        E: Spawn + Send + Sync + 'static,
+        Fut: Future<Output = Self::Output> + Send + 'static,
+    {
+        Self::infallible(OnExecutor {
+            executor: Arc::new(executor),
+            future,
+        })
+    }
+
+    /// Runs `future` as a task on `executor`, rather than on whatever polls the returned future,
+    /// which resolves to the task's output. Dropping the returned future cancels the task.
+    ///
+    /// If the executor shuts down before the task finishes, by refusing to take it or by dropping
+    /// it unfinished, the returned future fails as `blocking::set_shutdown_policy()` chooses.
+    fn spawn_on<E, Fut>(executor: &E, future: Fut) -> Self
+    where
+        E: Spawn + ?Sized,
+        Fut: Future<Output = Self::Output> + Send + 'static,
+        Self::Output: Send + 'static,
+    {
+        Self::fallible(blocking::spawn_on(executor, future))
+    }
+
+    /// Returns a builder that configures a future's name, executor, priority, and timeout before
+    /// wrapping it. See `builder::FutureBuilder`.
+    fn builder() -> builder::FutureBuilder<Self>
+    where
+        Self::Output: 'static,
+    {
+        builder::FutureBuilder::new()
+    }
+
+    // Attaches a name for diagnostics. The `bridge` macro overrides this to store the name in the
+    // future's box.
+    #[doc(hidden)]
+    fn set_name(&mut self, _name: String) {}
+
+    /// Marks the future as one that may be dropped without ever being polled, so that debug builds
+    /// don't report it. See the `discard` module.
+    fn ignore(mut self) -> Self {
+        self.set_ignored();
+        self
+    }
+
+    // Records that the future may be dropped unpolled. The `bridge` macro overrides this to store
+    // the mark in the future's box.
+    #[doc(hidden)]
+    fn set_ignored(&mut self) {}
+
+    // Attaches the handle that moves the deadline of a future built with a timeout. The `bridge`
+    // macro overrides this to store the handle in the future's box.
+    #[doc(hidden)]
+    fn set_timeout_handle(&mut self, _handle: builder::TimeoutHandle) {}
+
+    /// Runs a CPU-bound closure on the shared blocking thread pool, resolving once it returns.
+    ///
+    /// Use this instead of `infallible` for heavy synchronous work, so that the C++ thread that
+    /// awaits the future isn't stalled while polling it. If the closure panics, the future fails.
+    /// See the `blocking` module for how to size the pool.
+    fn from_blocking<F>(f: F) -> Self
+    where
+        F: FnOnce() -> Self::Output + Send + 'static,
+        Self::Output: Send + 'static,
+    {
+        Self::fallible(blocking::spawn_blocking(f))
+    }
+
+    /// Wraps a Rust Future that isn't `Send` and directly returns the output type.
+    ///
+    /// # Safety
+    ///
+    /// The resulting future must only be polled and dropped on the thread that called this
+    /// function. In debug builds, polling or dropping it on another thread panics; in release
+    /// builds, doing so is undefined behavior.
+    unsafe fn infallible_local<Fut>(future: Fut) -> Self
+    where
+        Fut: Future<Output = Self::Output> + 'static,
+    {
+        Self::fallible_local(async move { Ok(future.await) })
+    }
+
+    /// Wraps a Rust Future that isn't `Send` and returns the output type, wrapped in a
+    /// `CxxAsyncResult`.
+    ///
+    /// # Safety
+    ///
+    /// The same requirements apply as for `infallible_local`.
+    unsafe fn fallible_local<Fut>(future: Fut) -> Self
+    where
+        Fut: Future<Output = CxxAsyncResult<Self::Output>> + 'static,
+    {
+        Self::fallible(LocalFuture::new(future, std::any::type_name::<Self>()))
+    }
+
+    /// Wraps a Rust Future that directly returns the output type, and returns a handle that can be
+    /// used to abort it.
+    ///
+    /// Calling `abort()` on the handle, from any thread, drops the wrapped future and causes the
+    /// returned future to resolve with a cancellation `CxxAsyncException` whose message is
+    /// `"future aborted"`. See `CxxAsyncException::cancelled()`.
+    fn infallible_abortable<Fut>(future: Fut) -> (Self, AbortHandle)
+    where
+        Fut: Future<Output = Self::Output> + Send + 'static,
+    {
+        Self::fallible_abortable(async move { Ok(future.await) })
+    }
+
+    /// Wraps a Rust Future that returns the output type, wrapped in a `CxxAsyncResult`, and returns
+    /// a handle that can be used to abort it.
+    ///
+    /// See `infallible_abortable` for details.
+    fn fallible_abortable<Fut>(future: Fut) -> (Self, AbortHandle)
+    where
+        Fut: Future<Output = CxxAsyncResult<Self::Output>> + Send + 'static,
+    {
+        let (future, handle) = future::abortable(future);
+        let future = Self::fallible(async move {
+            future
+                .await
+                .unwrap_or_else(|_| Err(CxxAsyncException::cancelled("future aborted".into())))
+        });
+        (future, handle)
+    }
+
+    /// Wraps a Rust Future that returns the output type, wrapped in a `CxxAsyncResult`, racing it
+    /// against `stop`.
+    ///
+    /// If `stop` resolves first, however it resolves, the wrapped future is dropped where it
+    /// stands and the returned future fails with a cancellation `CxxAsyncException` whose message
+    /// is `"stop requested"`. To stop a Rust future through a C++ `std::stop_token`, pass the
+    /// future that `rust::async::stop_requested()` makes from the token as `stop`.
+    fn fallible_until_stopped<S, Fut>(stop: S, future: Fut) -> Self
+    where
+        S: Future + Send + 'static,
+        Fut: Future<Output = CxxAsyncResult<Self::Output>> + Send + 'static,
+    {
+        Self::fallible(async move {
+            match future::select(Box::pin(future), Box::pin(stop)).await {
+                future::Either::Left((result, _)) => result,
+                future::Either::Right(_) => {
+                    Err(CxxAsyncException::cancelled("stop requested".into()))
+                }
+            }
+        })
+    }
+
+    /// Attaches a cleanup closure to this future that runs exactly once, however the future ends.
+    ///
+    /// The closure runs when the future completes, when it fails, and when it's dropped before
+    /// completing, including when C++ drops it mid-flight.
+    fn finally<F>(mut self, cleanup: F) -> Self
+    where
+        Self:
+            Future<Output = CxxAsyncResult<<Self as IntoCxxAsyncFuture>::Output>> + Send + 'static,
+        F: FnOnce() + Send + 'static,
+    {
+        // Here and in the other adapters below, only the new future is reported if it's dropped
+        // unpolled. See the `discard` module.
+        self.set_ignored();
+        let guard = Finally(Some(cleanup));
+        Self::fallible(async move {
+            let result = self.await;
+            drop(guard);
+            result
+        })
+    }
+
+    /// Makes `priority` available to this future's body through `builder::current_priority()`
+    /// while it's polled, like `FutureBuilder::priority()`.
+    ///
+    /// The priority is a best-effort hint. `cxx-async` doesn't schedule by it, but an executor that
+    /// does can read `current_priority()` when the body spawns work onto it, and executors that
+    /// don't simply never look.
+    fn with_priority(mut self, priority: builder::CxxAsyncPriority) -> Self
+    where
+        Self:
+            Future<Output = CxxAsyncResult<<Self as IntoCxxAsyncFuture>::Output>> + Send + 'static,
+    {
+        self.set_ignored();
+        Self::fallible(builder::WithPriority {
+            priority: Some(priority),
+            future: Box::pin(self),
+        })
+    }
+
+    /// Converts this future into another bridged future type by applying `f` to its value.
+    ///
+    /// Errors are passed through unchanged. For example, `future.map_into::<RustFutureF64, _>(|i|
+    /// i as f64)` adapts a `RustFutureI32` into a `RustFutureF64`.
+    fn map_into<Target, F>(mut self, f: F) -> Target
+    where
+        Self:
+            Future<Output = CxxAsyncResult<<Self as IntoCxxAsyncFuture>::Output>> + Send + 'static,
+        Target: IntoCxxAsyncFuture,
+        F: FnOnce(<Self as IntoCxxAsyncFuture>::Output) -> Target::Output + Send + 'static,
+    {
+        self.set_ignored();
+        Target::fallible(async move { self.await.map(f) })
+    }
+
+    /// Attaches an opaque context value to `future`, to be recovered later with
+    /// `WithContext::take_context()`.
+    ///
+    /// Await the result by mutable reference (`(&mut future).await`) so that the context is still
+    /// available afterward. This avoids having to correlate requests with thread-local or global
+    /// maps across a round trip through C++.
+    fn with_context<C>(context: C, future: Self) -> WithContext<Self>
+    where
+        C: Send + 'static,
+    {
+        WithContext {
+            future,
+            context: Some(Box::new(context)),
+        }
+    }
+
+    /// Starts driving this future on the default executor right away, instead of waiting for it
+    /// to be awaited, and returns a handle to collect its result with later.
+    ///
+    /// This lets several futures make progress concurrently while their results are collected in
+    /// whatever order suits the caller. See `default_executor()`.
+    fn start(self) -> Started<<Self as IntoCxxAsyncFuture>::Output>
+    where
+        Self:
+            Future<Output = CxxAsyncResult<<Self as IntoCxxAsyncFuture>::Output>> + Send + 'static,
+        <Self as IntoCxxAsyncFuture>::Output: Send + 'static,
+    {
+        let future = blocking::spawn_on(&*default_executor(), self);
+        Started {
+            future: Box::pin(async move { future.await? }),
+        }
+    }
+
+    /// Erases the output type of this future, for keeping it in a collection alongside futures of
+    /// other types. See `DynCxxFuture`.
+    fn into_dyn(self) -> Box<dyn DynCxxFuture>
+    where
+        Self: Future<Output = CxxAsyncResult<<Self as IntoCxxAsyncFuture>::Output>>
+            + Send
+            + Unpin
+            + 'static,
+        <Self as IntoCxxAsyncFuture>::Output: Send + 'static,
+    {
+        Box::new(ErasedFuture {
+            future: self,
+            output: None,
+        })
+    }
+
+    /// Converts this future into one that resolves to `default` instead of failing.
+    fn unwrap_or(mut self, default: <Self as IntoCxxAsyncFuture>::Output) -> Self
+    where
+        Self:
+            Future<Output = CxxAsyncResult<<Self as IntoCxxAsyncFuture>::Output>> + Send + 'static,
+        <Self as IntoCxxAsyncFuture>::Output: Send + 'static,
+    {
+        self.set_ignored();
+        Self::infallible(async move { self.await.unwrap_or(default) })
+    }
+
+    /// Converts this future into one that, instead of failing, resolves to the value that `f`
+    /// computes from the exception.
+    fn unwrap_or_else<F>(mut self, f: F) -> Self
+    where
+        Self:
+            Future<Output = CxxAsyncResult<<Self as IntoCxxAsyncFuture>::Output>> + Send + 'static,
+        F: FnOnce(CxxAsyncException) -> <Self as IntoCxxAsyncFuture>::Output + Send + 'static,
+    {
+        self.set_ignored();
+        Self::infallible(async move { self.await.unwrap_or_else(f) })
+    }
+}
+
+/// Polls all of the given futures concurrently and resolves to the value of the first one that
+/// succeeds, dropping (and thereby cancelling) the rest.
+///
+/// Errors are discarded as long as some future may still succeed. If every future fails, this
+/// resolves to all of the errors, in the order in which the futures failed. An empty set of
+/// futures resolves to an empty list of errors.
+///
+/// This is useful for hedged requests, where the same request is sent to several C++ backends
+/// and only one answer is needed.
+pub async fn select_ok<I, T>(futures: I) -> Result<T, Vec<CxxAsyncException>>
+where
+    I: IntoIterator,
+    I::Item: Future<Output = CxxAsyncResult<T>>,
+{
+    let mut futures: FuturesUnordered<_> = futures.into_iter().collect();
+    let mut errors = vec![];
+    while let Some(result) = futures.next().await {
+        match result {
+            Ok(value) => return Ok(value),
+            Err(error) => errors.push(error),
+        }
+    }
+    Err(errors)
+}
+
+/// Creates one future per input with `factory` and runs at most `concurrency` of them at a time,
+/// yielding their results in input order.
+///
+/// This is the bounded fan-out of work across C++ coroutines. Errors are yielded in place of the
+/// corresponding values; use `TryStreamExt::try_collect()` to stop at the first one.
+pub fn buffered<I, F, Fut, T>(
+    inputs: I,
+    concurrency: usize,
+    factory: F,
+) -> impl Stream<Item = CxxAsyncResult<T>>
+where
+    I: IntoIterator,
+    F: FnMut(I::Item) -> Fut,
+    Fut: Future<Output = CxxAsyncResult<T>>,
+{
+    futures::stream::iter(inputs)
+        .map(factory)
+        .buffered(concurrency)
+}
+
+/// Wraps an arbitrary Rust Stream in a boxed `cxx-async` stream so that it can be returned to C++.
+///
+/// You should not need to implement this manually; it's automatically implemented by the
+/// `bridge_stream` macro.
+pub trait IntoCxxAsyncStream: Sized {
+    /// The type of the values yielded by the stream.
+    type Item;
+
+    /// Wraps a Rust Stream that directly yields items of the output type.
+    ///
+    /// Use this when you aren't interested in propagating errors to C++ as exceptions.
+    fn infallible<Stm>(stream: Stm) -> Self
+    where
+        Stm: Stream<Item = Self::Item> + Send + 'static,
+        Stm::Item: 'static,
+    {
+        Self::fallible(stream.map(Ok))
+    }
+
+    /// Wraps a Rust Stream that yields items of the output type, wrapped in `CxxAsyncResult`s.
+    ///
+    /// Use this when you have error values that you want to turn into exceptions on the C++ side.
+    fn fallible<Stm>(stream: Stm) -> Self
+    where
+        Stm: Stream<Item = CxxAsyncResult<Self::Item>> + Send + 'static;
+
+    /// Registers a closure that receives the items still buffered when the stream is dropped, in
+    /// order, instead of letting them be dropped with it.
+    ///
+    /// This is for flushing undelivered items on close, for instance to a log. The items are the
+    /// ones that a C++ coroutine has already yielded but Rust hasn't received, which is at most one
+    /// item or one batch. Dropping the stream doesn't resume the coroutine to produce more, and it
+    /// still cancels the coroutine as usual. Streams with Rust bodies buffer nothing, so the
+    /// closure never runs for them. The closure runs wherever the stream is dropped.
+    fn on_drain<F>(mut self, drain: F) -> Self
+    where
+        F: FnMut(Self::Item) + Send + 'static,
+    {
+        self.set_drain(Box::new(drain));
+        self
+    }
+
+    // Attaches the closure that `on_drain()` registers. The `bridge` macro overrides this to store
+    // it in the stream's receiving end.
+    #[doc(hidden)]
+    fn set_drain(&mut self, _drain: Box<dyn FnMut(Self::Item) + Send>) {}
+}
+
+// Creates a new oneshot sender/receiver pair for a future.
+//
+// SAFETY: This is a raw FFI function called by our C++ code.
+//
+// This needs an out pointer because of https://github.com/rust-lang/rust-bindgen/issues/778
+#[doc(hidden)]
+pub unsafe extern "C" fn future_channel<Fut, Out>(
+    out_oneshot: *mut CxxAsyncFutureChannel<Fut, Out>,
+    execlet: *mut RustExeclet,
+) where
+    Fut: From<CxxAsyncReceiver<Out>> + Future<Output = CxxAsyncResult<Out>>,
+{
+    let channel = SpscChannel::new();
+    let oneshot = CxxAsyncFutureChannel {
+        sender: CxxAsyncSender(Box::into_raw(Box::new(channel.clone()))),
+        future: CxxAsyncReceiver::<Out> {
+            receiver: channel,
+            execlet: Some(Execlet::from_raw_ref(execlet)),
+            drain: None,
+        }
+        .into(),
+    };
+    ptr::write(out_oneshot, oneshot);
+}
+
+// Creates a new multi-shot sender/receiver pair for a stream.
+//
+// SAFETY: This is a raw FFI function called by our C++ code.
+//
+// This needs an out pointer because of https://github.com/rust-lang/rust-bindgen/issues/778
+#[doc(hidden)]
 pub unsafe extern "C" fn stream_channel<Stm, Item>(
     out_stream: *mut CxxAsyncStreamChannel<Stm, Item>,
     execlet: *mut RustExeclet,
@@ -687,6 +2178,7 @@ pub unsafe extern "C" fn stream_channel<Stm, Item>(
         future: CxxAsyncReceiver {
             receiver: channel,
             execlet: Some(Execlet::from_raw_ref(execlet)),
+            drain: None,
         }
         .into(),
     };
@@ -716,14 +2208,18 @@ pub unsafe extern "C" fn sender_future_send<Item>(
     status: u32,
     value: *const u8,
     waker_data: *const u8,
-) -> u32 {
+) -> u32
+where
+    Item: CxxAsyncResultRepr,
+{
     safe_debug_assert!(waker_data.is_null());
 
     let this = this.0.as_mut().safe_expect("Where's the SPSC sender?");
+    trace_boundary!("channel {:p}: send({})", this.id(), status_name(status));
     match status {
         FUTURE_STATUS_COMPLETE => {
             // This is a one-shot sender, so sending must always succeed.
-            let sent = this.try_send_value_with(None, || ptr::read(value as *const Item));
+            let sent = this.try_send_value_with(None, || Item::read_result(value));
             safe_debug_assert!(sent);
         }
         FUTURE_STATUS_ERROR => this.send_exception(unpack_exception(value)),
@@ -761,7 +2257,10 @@ pub unsafe extern "C" fn sender_stream_send<Item>(
     status: u32,
     value: *const u8,
     waker_data: *const u8,
-) -> u32 {
+) -> u32
+where
+    Item: CxxAsyncResultRepr,
+{
     let (waker, context);
     if waker_data.is_null() {
         context = None;
@@ -774,14 +2273,14 @@ pub unsafe extern "C" fn sender_stream_send<Item>(
     }
 
     let this = this.0.as_mut().safe_expect("Where's the SPSC sender?");
+    trace_boundary!("channel {:p}: send({})", this.id(), status_name(status));
     match status {
         FUTURE_STATUS_COMPLETE => {
             this.close();
             SEND_RESULT_FINISHED
         }
         FUTURE_STATUS_RUNNING => {
-            let sent =
-                this.try_send_value_with(context.as_ref(), || ptr::read(value as *const Item));
+            let sent = this.try_send_value_with(context.as_ref(), || Item::read_result(value));
             if sent {
                 SEND_RESULT_SENT
             } else {
@@ -797,6 +2296,70 @@ pub unsafe extern "C" fn sender_stream_send<Item>(
     }
 }
 
+// C++ calls this to send a contiguous batch of `count` values to a stream in one call.
+//
+// This is like calling `sender_stream_send` with `FUTURE_STATUS_RUNNING` once per value, except
+// that either all of the values are sent or, if the channel still holds values that haven't been
+// read, none are and `SEND_RESULT_WAIT` is returned. The receiving end still receives the values one
+// at a time. The values are copied bitwise, so the C++ type must be trivially copyable.
+//
+// If `waker_data` is present, this identifies the coroutine handle that will be awakened if the
+// channel is currently full.
+//
+// SAFETY: This is a low-level function called by our C++ code.
+#[doc(hidden)]
+pub unsafe extern "C" fn sender_stream_send_batch<Item>(
+    this: &mut CxxAsyncSender<Item>,
+    values: *const u8,
+    count: usize,
+    waker_data: *const u8,
+) -> u32
+where
+    Item: CxxAsyncResultRepr,
+{
+    safe_debug_assert!(Item::KIND == CxxAsyncResultKind::InPlace);
+    let (waker, context);
+    if waker_data.is_null() {
+        context = None;
+    } else {
+        waker = Waker::from_raw(RawWaker::new(
+            waker_data as *const (),
+            &CXXASYNC_WAKER_VTABLE,
+        ));
+        context = Some(Context::from_waker(&waker));
+    }
+
+    let this = this.0.as_mut().safe_expect("Where's the SPSC sender?");
+    trace_boundary!("channel {:p}: send_batch({})", this.id(), count);
+    if count == 0 {
+        return SEND_RESULT_SENT;
+    }
+    if this.try_send_values_with(context.as_ref(), count, |index| {
+        Item::read_result(values.add(index * Item::STRIDE))
+    }) {
+        SEND_RESULT_SENT
+    } else {
+        SEND_RESULT_WAIT
+    }
+}
+
+// C++ calls this to register a callback that's called when Rust drops the receiving end of the
+// channel, so that a coroutine can stop work whose result nobody will see.
+//
+// SAFETY: This is a low-level function called by our C++ code.
+//
+// `callback` takes ownership of `data` and is called exactly once. See `CancelCallback`.
+#[doc(hidden)]
+pub unsafe extern "C" fn sender_on_cancel<Item>(
+    this: &mut CxxAsyncSender<Item>,
+    callback: unsafe extern "C" fn(data: *mut u8, cancelled: bool),
+    data: *mut u8,
+) {
+    let this = this.0.as_mut().safe_expect("Where's the SPSC sender?");
+    trace_boundary!("channel {:p}: cancel callback registered", this.id());
+    this.add_cancel_callback(CancelCallback { callback, data });
+}
+
 // C++ calls this to destroy a sender.
 //
 // SAFETY: This is a low-level function called by our C++ code.
@@ -806,16 +2369,91 @@ pub unsafe extern "C" fn sender_drop<Item>(_: CxxAsyncSender<Item>) {
 }
 
 unsafe fn unpack_exception(value: *const u8) -> CxxAsyncException {
-    let string = CStr::from_ptr(value as *const c_char);
-    CxxAsyncException::new(string.to_string_lossy().into_owned().into_boxed_str())
+    let bytes = CStr::from_ptr(value as *const c_char).to_bytes();
+    let max_len = MAX_EXCEPTION_MESSAGE_LEN.load(Ordering::Relaxed);
+    if bytes.len() <= max_len {
+        return CxxAsyncException::new(String::from_utf8_lossy(bytes).into());
+    }
+
+    // Back up to the start of a character, so that we don't split one.
+    let mut end = max_len;
+    while end > 0 && bytes[end] & 0xc0 == 0x80 {
+        end -= 1;
+    }
+    let mut what = String::from_utf8_lossy(&bytes[..end]).into_owned();
+    what.push_str(&format!("... (truncated from {} bytes)", bytes.len()));
+    CxxAsyncException::new(what.into_boxed_str())
+}
+
+thread_local! {
+    // The await site that C++ attached to the Rust future it's polling on this thread, or null.
+    // See `rust::async::set_await_site()`.
+    static AWAIT_SITE: Cell<*const c_char> = const { Cell::new(ptr::null()) };
+}
+
+// C++ calls this to set the await site around a poll, and again to restore the previous one.
+//
+// SAFETY: `site` must be null or a null-terminated string that outlives the poll.
+#[doc(hidden)]
+#[no_mangle]
+pub extern "C" fn cxxasync_swap_await_site(site: *const c_char) -> *const c_char {
+    AWAIT_SITE.with(|await_site| await_site.replace(site))
+}
+
+thread_local! {
+    // How many bridged futures are being polled on this thread, counting nested polls.
+    static POLL_DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+// Counts a bridged future as being polled on this thread until dropped, even if the poll panics.
+struct Polling;
+
+impl Polling {
+    fn enter() -> Self {
+        POLL_DEPTH.with(|depth| depth.set(depth.get() + 1));
+        Polling
+    }
+}
+
+impl Drop for Polling {
+    fn drop(&mut self) {
+        POLL_DEPTH.with(|depth| depth.set(depth.get() - 1));
+    }
+}
+
+// C++ calls this to refuse to block the thread while a future is being polled on it, since the
+// future being polled can't make progress until the block ends. See `rust::async::blocking_wait()`.
+#[doc(hidden)]
+#[no_mangle]
+pub extern "C" fn cxxasync_is_polling() -> bool {
+    POLL_DEPTH.with(Cell::get) > 0
+}
+
+// Returns a suffix naming the current await site for panic messages, or an empty string.
+unsafe fn await_site_suffix() -> String {
+    let site = AWAIT_SITE.with(Cell::get);
+    if site.is_null() {
+        String::new()
+    } else {
+        format!(" at {}", CStr::from_ptr(site).to_string_lossy())
+    }
 }
 
 // C++ calls this to poll a wrapped Rust future.
 //
+// `result` points to a C++ `RustFutureResult`, which is always sized and aligned for both the
+// output type and `rust::String`. No size query is needed for variable-length values such as
+// `String` or `Vec<T>`, because their C++ counterparts `rust::String` and `rust::Vec<T>` are
+// fixed-size handles to heap memory. The buffer is only written to when this function returns
+// `FUTURE_STATUS_COMPLETE`, in which case it holds the output value, or `FUTURE_STATUS_ERROR` or
+// `FUTURE_STATUS_CANCELLED`, in which case it holds the error message as a `rust::String`. Either
+// way, ownership of the value, including any heap memory that it owns, passes to C++, which must
+// eventually destroy it.
+//
 // SAFETY:
 // * This is a low-level function called by our C++ code.
 // * `Pin<&mut Future>` is marked `#[repr(transparent)]`, so it's FFI-safe.
-// * We catch all panics inside `poll` so that they don't unwind into C++.
+// * `poll_from_cpp()` catches all panics so that they don't unwind into C++.
 #[doc(hidden)]
 pub unsafe extern "C" fn future_poll<Fut, Out>(
     this: Pin<&mut Fut>,
@@ -823,35 +2461,116 @@ pub unsafe extern "C" fn future_poll<Fut, Out>(
     waker_data: *const u8,
 ) -> u32
 where
-    Fut: Future<Output = CxxAsyncResult<Out>>,
+    Fut: Future<Output = CxxAsyncResult<Out>> + 'static,
+    Out: CxxAsyncResultRepr,
 {
-    let waker = Waker::from_raw(RawWaker::new(
-        waker_data as *const (),
-        &CXXASYNC_WAKER_VTABLE,
-    ));
-
-    let result = panic::catch_unwind(AssertUnwindSafe(move || {
-        let mut context = Context::from_waker(&waker);
-        match this.poll(&mut context) {
-            Poll::Ready(Ok(value)) => {
-                ptr::write(result as *mut Out, value);
+    #[cfg(feature = "trace-boundary")]
+    let id = &*this as *const Fut;
+    trace_boundary!("future {:p}: poll called", id);
+
+    let status = match poll_from_cpp(this, waker_data) {
+        Poll::Ready(Ok(value)) => match value.check_result() {
+            Ok(()) => {
+                value.write_result(result);
                 FUTURE_STATUS_COMPLETE
             }
-            Poll::Ready(Err(error)) => {
-                let error = error.what().to_owned();
-                ptr::write(result as *mut String, error);
-                FUTURE_STATUS_ERROR
+            Err(error) => write_error(result, error),
+        },
+        Poll::Ready(Err(error)) => write_error(result, error),
+        Poll::Pending => FUTURE_STATUS_PENDING,
+    };
+
+    trace_boundary!("future {:p}: returned {}", id, status_name(status));
+    status
+}
+
+// C++ calls this instead of `future_poll()` to poll a future whose output is small enough to
+// come back in the return value, saving the write to and read from `result` on completion. The
+// `bridge` macro only offers it for output types whose `CxxAsyncResultRepr::PACKED` is true, and
+// C++ only uses it when the C++ type qualifies too; see `RustFutureReceiver::poll_future()` in
+// `cxx_async.h`.
+//
+// The low 32 bits of the return value hold the status, as `future_poll()` returns it. When that's
+// `FUTURE_STATUS_COMPLETE`, the high 32 bits hold the output value: its bytes are the first
+// `size_of::<Out>()` bytes of a `u32` in native byte order, and the rest are zero. `result` is
+// only written to when the future fails, in which case it holds the error message as a
+// `rust::String`, just as with `future_poll()`.
+//
+// SAFETY:
+// * This is a low-level function called by our C++ code.
+// * `Out` must be `InPlace` and no larger than a `u32`, as `PACKED` requires.
+#[doc(hidden)]
+pub unsafe extern "C" fn future_poll_packed<Fut, Out>(
+    this: Pin<&mut Fut>,
+    result: *mut u8,
+    waker_data: *const u8,
+) -> u64
+where
+    Fut: Future<Output = CxxAsyncResult<Out>> + 'static,
+    Out: CxxAsyncResultRepr,
+{
+    safe_debug_assert!(
+        Out::KIND == CxxAsyncResultKind::InPlace && mem::size_of::<Out>() <= mem::size_of::<u32>()
+    );
+
+    #[cfg(feature = "trace-boundary")]
+    let id = &*this as *const Fut;
+    trace_boundary!("future {:p}: packed poll called", id);
+
+    let packed = match poll_from_cpp(this, waker_data) {
+        Poll::Ready(Ok(value)) => match value.check_result() {
+            Ok(()) => {
+                let mut bits = 0u32;
+                ptr::write(&mut bits as *mut u32 as *mut Out, value);
+                (u64::from(bits) << 32) | u64::from(FUTURE_STATUS_COMPLETE)
             }
-            Poll::Pending => FUTURE_STATUS_PENDING,
-        }
+            Err(error) => u64::from(write_error(result, error)),
+        },
+        Poll::Ready(Err(error)) => u64::from(write_error(result, error)),
+        Poll::Pending => u64::from(FUTURE_STATUS_PENDING),
+    };
+
+    trace_boundary!("future {:p}: returned {}", id, status_name(packed as u32));
+    packed
+}
+
+// Polls a Rust future on behalf of C++, aborting if it panics, since the panic mustn't unwind into
+// C++.
+unsafe fn poll_from_cpp<Fut, Out>(
+    this: Pin<&mut Fut>,
+    waker_data: *const u8,
+) -> Poll<CxxAsyncResult<Out>>
+where
+    Fut: Future<Output = CxxAsyncResult<Out>> + 'static,
+{
+    // A null waker means that C++ is spinning on the future instead of going to sleep. See
+    // `RustFuture::await_spin()` in `cxx_async.h`.
+    let waker = if waker_data.is_null() {
+        futures::task::noop_waker()
+    } else {
+        Waker::from_raw(RawWaker::new(
+            waker_data as *const (),
+            &CXXASYNC_WAKER_VTABLE,
+        ))
+    };
+
+    let poll = panic::catch_unwind(AssertUnwindSafe(move || {
+        let mut context = Context::from_waker(&waker);
+        #[cfg(feature = "poll-metrics")]
+        let start = std::time::Instant::now();
+        let poll = this.poll(&mut context);
+        #[cfg(feature = "poll-metrics")]
+        metrics::record_poll::<Fut>(start.elapsed());
+        poll
     }));
 
-    match result {
-        Ok(result) => result,
+    match poll {
+        Ok(poll) => poll,
         Err(error) => {
             drop(writeln!(
                 io::stderr(),
-                "Rust async code panicked when awaited from C++: {:?}",
+                "Rust async code panicked when awaited from C++{}: {:?}",
+                await_site_suffix(),
                 error
             ));
             process::abort();
@@ -859,12 +2578,24 @@ where
     }
 }
 
+// Writes the message of a failed future into `result` for C++, and returns the matching status.
+unsafe fn write_error(result: *mut u8, error: CxxAsyncException) -> u32 {
+    let status = if error.is_cancelled() {
+        FUTURE_STATUS_CANCELLED
+    } else {
+        FUTURE_STATUS_ERROR
+    };
+    ptr::write(result as *mut String, error.what().to_owned());
+    status
+}
+
 // C++ calls this to drop a Rust future.
 //
 // SAFETY:
 // * This is a low-level function called by our C++ code.
 #[doc(hidden)]
 pub unsafe extern "C" fn future_drop<Fut>(future: *mut Fut) {
+    trace_boundary!("future {:p}: dropped", future);
     ptr::drop_in_place(future);
 }
 
@@ -903,6 +2634,114 @@ unsafe fn rust_suspended_coroutine_drop(address: *const ()) {
 // they should import the `futures` crate directly.
 #[doc(hidden)]
 pub mod private {
-    pub use futures::future::BoxFuture;
-    pub use futures::stream::BoxStream;
+    use crate::PollTracked;
+    use futures::Stream;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::task::Context;
+    use std::task::Poll;
+
+    pub use futures::task::Spawn;
+
+    use crate::timer::Timer;
+    use crate::CxxAsyncReceiver;
+    use crate::CxxAsyncResult;
+    use futures::future;
+    use futures::future::Either;
+    use std::time::Duration;
+
+    // The body of a bridged stream: either the receiving end of a C++ coroutine or a Rust stream.
+    pub trait StreamBody<T>: Stream<Item = CxxAsyncResult<T>> + Send {
+        // Stores the closure that `IntoCxxAsyncStream::on_drain()` registers. Only the receiving
+        // end of a C++ coroutine buffers items, so this drops it for everything else.
+        fn set_drain(self: Pin<&mut Self>, _drain: Box<dyn FnMut(T) + Send>) {}
+    }
+
+    impl<T> StreamBody<T> for CxxAsyncReceiver<T>
+    where
+        T: Send,
+    {
+        fn set_drain(self: Pin<&mut Self>, drain: Box<dyn FnMut(T) + Send>) {
+            self.get_mut().drain = Some(drain);
+        }
+    }
+
+    // A stream with a Rust body.
+    struct RustStreamBody<Stm>(Stm);
+
+    impl<Stm, T> Stream for RustStreamBody<Stm>
+    where
+        Stm: Stream<Item = CxxAsyncResult<T>>,
+    {
+        type Item = CxxAsyncResult<T>;
+
+        fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            // SAFETY: The stream is pinned whenever its wrapper is, and never moved out of it.
+            unsafe { self.map_unchecked_mut(|this| &mut this.0) }.poll_next(cx)
+        }
+
+        fn size_hint(&self) -> (usize, Option<usize>) {
+            self.0.size_hint()
+        }
+    }
+
+    impl<Stm, T> StreamBody<T> for RustStreamBody<Stm>
+    where
+        Stm: Stream<Item = CxxAsyncResult<T>> + Send,
+    {
+        // A Rust stream buffers nothing of its own, so there's nothing to drain.
+    }
+
+    // The type of the field that holds the underlying stream in a bridged stream.
+    pub type BoxStreamBody<T> = Pin<Box<dyn StreamBody<T> + 'static>>;
+
+    // Boxes a Rust stream for storage in a bridged stream.
+    pub fn box_stream<Stm, T>(stream: Stm) -> BoxStreamBody<T>
+    where
+        Stm: Stream<Item = CxxAsyncResult<T>> + Send + 'static,
+    {
+        Box::pin(RustStreamBody(stream))
+    }
+
+    // The type of the field that holds the underlying future in a bridged future.
+    pub type TrackedBoxFuture<T> =
+        Pin<Box<PollTracked<T, dyn Future<Output = T> + Send + 'static>>>;
+
+    // Boxes a future for storage in a bridged future.
+    pub fn box_future<Fut>(future: Fut) -> TrackedBoxFuture<Fut::Output>
+    where
+        Fut: Future + Send + 'static,
+    {
+        Box::pin(PollTracked::new(future, None))
+    }
+
+    // Boxes the receiving end of a C++ future for storage in a bridged future, so that
+    // `cancel_with_grace()` can ask the C++ coroutine to finish early.
+    pub fn box_receiver<T>(receiver: CxxAsyncReceiver<T>) -> TrackedBoxFuture<CxxAsyncResult<T>>
+    where
+        T: Send + 'static,
+    {
+        let channel = receiver.receiver.clone();
+        let mut future =
+            PollTracked::new(receiver, Some(Box::new(move || channel.request_cancel())));
+        // The coroutine has already started, and dropping its future is how Rust cancels it, so
+        // it isn't reported if it's dropped unpolled. See the `discard` module.
+        future.ignored = true;
+        Box::pin(future)
+    }
+
+    // Waits up to `grace` for a future whose cancellation has been requested to finish, and drops
+    // it if it doesn't. See `cancel_with_grace()`.
+    pub async fn finish_within<Fut, T>(future: Fut, grace: Duration) -> Option<CxxAsyncResult<T>>
+    where
+        Fut: Future<Output = CxxAsyncResult<T>> + Unpin,
+    {
+        match future::select(future, Timer::after(grace)).await {
+            Either::Left((result, _)) => Some(result),
+            Either::Right((_, future)) => {
+                drop(future);
+                None
+            }
+        }
+    }
 }
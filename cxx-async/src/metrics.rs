@@ -0,0 +1,121 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+// cxx-async/src/metrics.rs
+//
+//! Per-type histograms of how long each poll of a Rust future from C++ takes.
+//!
+//! This module is only available when the `poll-metrics` feature is enabled. A poll that takes a
+//! long time usually means that the future is doing heavy synchronous work instead of yielding.
+
+use crate::SafeUnwrap;
+use once_cell::sync::Lazy;
+use std::any::TypeId;
+use std::collections::HashMap;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// The number of buckets in a poll histogram.
+///
+/// Bucket `i` counts polls that took at least `2^i` and less than `2^(i + 1)` nanoseconds, except
+/// that bucket 0 also counts polls that took less than a nanosecond.
+pub const BUCKET_COUNT: usize = 64;
+
+static HISTOGRAMS: Lazy<Mutex<HashMap<TypeId, Arc<AtomicHistogram>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// The live histogram for one future type, which polls record into.
+struct AtomicHistogram {
+    buckets: [AtomicU64; BUCKET_COUNT],
+    total_nanos: AtomicU64,
+}
+
+impl AtomicHistogram {
+    fn new() -> Self {
+        AtomicHistogram {
+            buckets: [(); BUCKET_COUNT].map(|_| AtomicU64::new(0)),
+            total_nanos: AtomicU64::new(0),
+        }
+    }
+
+    fn record(&self, duration: Duration) {
+        let nanos = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+        let bucket = (u64::BITS - 1).saturating_sub(nanos.leading_zeros()) as usize;
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.total_nanos.fetch_add(nanos, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> PollHistogram {
+        PollHistogram {
+            buckets: self
+                .buckets
+                .iter()
+                .map(|bucket| bucket.load(Ordering::Relaxed))
+                .collect(),
+            total: Duration::from_nanos(self.total_nanos.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// A snapshot of the poll durations recorded for one future type.
+#[derive(Clone, Debug, Default)]
+pub struct PollHistogram {
+    buckets: Vec<u64>,
+    total: Duration,
+}
+
+impl PollHistogram {
+    /// Returns the number of polls in each bucket. See `BUCKET_COUNT` for the bucket boundaries.
+    ///
+    /// If no polls have been recorded for the future type, this is empty.
+    pub fn buckets(&self) -> &[u64] {
+        &self.buckets
+    }
+
+    /// Returns the total number of polls recorded.
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// Returns the sum of the durations of all polls recorded.
+    pub fn total(&self) -> Duration {
+        self.total
+    }
+}
+
+/// Returns a snapshot of the poll durations recorded so far for the bridged future type `Fut`.
+///
+/// Only polls made by C++ on a Rust future are recorded.
+pub fn poll_histogram<Fut>() -> PollHistogram
+where
+    Fut: 'static,
+{
+    let histograms = HISTOGRAMS.lock().safe_unwrap();
+    match histograms.get(&TypeId::of::<Fut>()) {
+        Some(histogram) => histogram.snapshot(),
+        None => PollHistogram::default(),
+    }
+}
+
+// Records the duration of one poll of a future of type `Fut`.
+pub(crate) fn record_poll<Fut>(duration: Duration)
+where
+    Fut: 'static,
+{
+    let histogram = HISTOGRAMS
+        .lock()
+        .safe_unwrap()
+        .entry(TypeId::of::<Fut>())
+        .or_insert_with(|| Arc::new(AtomicHistogram::new()))
+        .clone();
+    histogram.record(duration);
+}
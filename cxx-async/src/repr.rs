@@ -0,0 +1,288 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+// cxx-async/src/repr.rs
+//
+//! How the values of bridged futures and streams are laid out when they cross the language
+//! boundary.
+//!
+//! Every future output and stream item type implements `CxxAsyncResultRepr`, which declares how
+//! the value is handed over. The C++ side works out the same thing from the type named in
+//! `CXXASYNC_DEFINE_FUTURE` or `CXXASYNC_DEFINE_STREAM`, and asserts that the two agree the first
+//! time a future or stream of that type is created.
+
+use crate::CxxAsyncException;
+use crate::CxxAsyncResult;
+use std::mem;
+use std::ptr;
+
+/// How a value is handed over between Rust and C++. See `CxxAsyncResultRepr`.
+///
+/// This must match `rust::async::ResultKind` in `cxx_async.h`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum CxxAsyncResultKind {
+    /// The value is copied bitwise into the result buffer, and neither side runs a destructor for
+    /// the copy it gives away. The C++ type must be trivially copyable. This is the kind of
+    /// scalars and arrays of them.
+    InPlace = 0,
+    /// The value is moved into the result buffer, and ownership of it, including any heap memory
+    /// it owns, passes to the side that reads it. The C++ type is the one that `cxx` maps the Rust
+    /// type to, such as `rust::String` for `String`. This is the kind of `cxx` owning types and of
+    /// shared structs and enums that contain them.
+    Moved = 1,
+    /// The value is boxed on the Rust heap, and the result buffer holds the pointer. The C++ type
+    /// is `rust::Box<T>`, which frees the value when it goes out of scope. This is how opaque Rust
+    /// types, which C++ can't hold by value, cross the boundary.
+    Boxed = 2,
+}
+
+/// A type that can be the output of a bridged future or the item of a bridged stream.
+///
+/// This is implemented for scalars, arrays, and the owning types that `cxx` supports, and the
+/// `#[cxx_async::bridge]` attribute implements it for the future types it defines, so that futures
+/// can resolve to other futures. Implement it yourself for the shared structs and enums and the
+/// opaque Rust types that your futures yield:
+///
+/// ```
+/// use cxx_async::{CxxAsyncResultKind, CxxAsyncResultRepr};
+///
+/// // An opaque Rust type, declared in an `extern "Rust"` block, that C++ receives as a
+/// // `rust::Box<Counter>`.
+/// struct Counter(u64);
+///
+/// unsafe impl CxxAsyncResultRepr for Counter {
+///     const KIND: CxxAsyncResultKind = CxxAsyncResultKind::Boxed;
+/// }
+///
+/// assert_eq!(<f64 as CxxAsyncResultRepr>::KIND, CxxAsyncResultKind::InPlace);
+/// assert_eq!(<String as CxxAsyncResultRepr>::KIND, CxxAsyncResultKind::Moved);
+/// ```
+///
+/// # Safety
+///
+/// `KIND` must describe the C++ type that the future or stream is defined with on the C++ side.
+/// An `InPlace` type must have the same layout as a trivially copyable C++ type, and a `Moved` type
+/// the same layout as a C++ type that takes ownership of whatever the Rust value owns.
+pub unsafe trait CxxAsyncResultRepr: Sized {
+    /// How values of this type are handed over.
+    const KIND: CxxAsyncResultKind;
+
+    /// The number of bytes that one value occupies in a result buffer.
+    const STRIDE: usize = match Self::KIND {
+        CxxAsyncResultKind::InPlace | CxxAsyncResultKind::Moved => mem::size_of::<Self>(),
+        CxxAsyncResultKind::Boxed => mem::size_of::<*mut Self>(),
+    };
+
+    /// Whether a poll that completes can hand the value back to C++ in the same machine word as
+    /// the poll status, instead of writing it into the result buffer.
+    ///
+    /// This is a micro-optimization for the tiniest futures, such as those that resolve to a `bool`
+    /// or an `i32`. By default, it applies to `InPlace` types no larger than a `u32`; set it to
+    /// false to always write values into the buffer. C++ only packs values whose C++ type is
+    /// trivially copyable and no larger than a `uint32_t`, and falls back to the buffer for the
+    /// rest.
+    ///
+    /// It must never be true for types that aren't `InPlace` or are larger than a `u32`.
+    const PACKED: bool = matches!(Self::KIND, CxxAsyncResultKind::InPlace)
+        && mem::size_of::<Self>() <= mem::size_of::<u32>();
+
+    /// Checks that C++ can safely hold this value, before it's handed over. If not, the future
+    /// fails with the returned error instead.
+    ///
+    /// This accepts every value by default. Vectors check their length with
+    /// `check_payload_len()`.
+    fn check_result(&self) -> CxxAsyncResult<()> {
+        Ok(())
+    }
+
+    /// Writes this value into a result buffer that C++ reads, giving up ownership of it.
+    ///
+    /// # Safety
+    ///
+    /// `result` must be valid for writes of `STRIDE` bytes and suitably aligned.
+    unsafe fn write_result(self, result: *mut u8) {
+        match Self::KIND {
+            CxxAsyncResultKind::InPlace | CxxAsyncResultKind::Moved => {
+                ptr::write(result as *mut Self, self)
+            }
+            CxxAsyncResultKind::Boxed => {
+                ptr::write(result as *mut *mut Self, Box::into_raw(Box::new(self)))
+            }
+        }
+    }
+
+    /// Reads a value out of a result buffer that C++ wrote, taking ownership of it.
+    ///
+    /// # Safety
+    ///
+    /// `value` must point to a value of this type laid out as `KIND` describes, and the caller
+    /// must make sure that C++ doesn't destroy it afterward.
+    unsafe fn read_result(value: *const u8) -> Self {
+        match Self::KIND {
+            CxxAsyncResultKind::InPlace | CxxAsyncResultKind::Moved => {
+                ptr::read(value as *const Self)
+            }
+            CxxAsyncResultKind::Boxed => *Box::from_raw(ptr::read(value as *const *mut Self)),
+        }
+    }
+}
+
+// C++ calls this to find out how the values of a future or stream are handed over, so that it
+// can check that it agrees. See `rust::async::check_result_kind()` in `cxx_async.h`.
+#[doc(hidden)]
+pub extern "C" fn result_kind<T>() -> u32
+where
+    T: CxxAsyncResultRepr,
+{
+    T::KIND as u32
+}
+
+/// The size and alignment of one value of a future or stream in a result buffer.
+///
+/// This must match `rust::async::ResultLayout` in `cxx_async.h`.
+#[repr(C)]
+#[doc(hidden)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CxxAsyncResultLayout {
+    pub size: usize,
+    pub align: usize,
+}
+
+// C++ calls this to find out the layout of the values of a future or stream, so that it can check
+// that the C++ type has the same one. See `rust::async::check_result_layout()` in `cxx_async.h`.
+#[doc(hidden)]
+pub extern "C" fn result_layout<T>() -> CxxAsyncResultLayout
+where
+    T: CxxAsyncResultRepr,
+{
+    CxxAsyncResultLayout {
+        size: T::STRIDE,
+        align: match T::KIND {
+            CxxAsyncResultKind::InPlace | CxxAsyncResultKind::Moved => mem::align_of::<T>(),
+            CxxAsyncResultKind::Boxed => mem::align_of::<*mut T>(),
+        },
+    }
+}
+
+/// Fails to compile if an implementation of `CxxAsyncResultRepr` contradicts itself, for instance
+/// by packing a value too large for a packed poll.
+///
+/// The `#[cxx_async::bridge]` attribute calls this for every future output and stream item type,
+/// in a `const` item, so that such a mismatch is a compile error instead of a misread value:
+///
+/// ```compile_fail
+/// use cxx_async::{CxxAsyncResultKind, CxxAsyncResultRepr};
+///
+/// struct Wide(u64);
+///
+/// unsafe impl CxxAsyncResultRepr for Wide {
+///     const KIND: CxxAsyncResultKind = CxxAsyncResultKind::InPlace;
+///     const PACKED: bool = true;
+/// }
+///
+/// const _: () = cxx_async::repr::assert_result_repr::<Wide>();
+/// ```
+///
+/// Whether the C++ type agrees is checked when the first future or stream of each type is created,
+/// since Rust can't see C++ types.
+pub const fn assert_result_repr<T>()
+where
+    T: CxxAsyncResultRepr,
+{
+    let size = match T::KIND {
+        CxxAsyncResultKind::InPlace | CxxAsyncResultKind::Moved => mem::size_of::<T>(),
+        CxxAsyncResultKind::Boxed => mem::size_of::<*mut T>(),
+    };
+    assert!(
+        T::STRIDE == size,
+        "`CxxAsyncResultRepr::STRIDE` doesn't match the size of the value as `KIND` hands it over"
+    );
+    assert!(
+        !T::PACKED
+            || (matches!(T::KIND, CxxAsyncResultKind::InPlace)
+                && mem::size_of::<T>() <= mem::size_of::<u32>()),
+        "`CxxAsyncResultRepr::PACKED` is only allowed for `InPlace` types no larger than a `u32`"
+    );
+}
+
+macro_rules! impl_result_repr {
+    ($kind:ident: $($ty:ty),+) => {
+        $(
+            unsafe impl CxxAsyncResultRepr for $ty {
+                const KIND: CxxAsyncResultKind = CxxAsyncResultKind::$kind;
+            }
+        )+
+    };
+}
+
+impl_result_repr!(InPlace: (), bool, u8, u16, u32, u64, usize, i8, i16, i32, i64, isize, f32, f64);
+impl_result_repr!(Moved: String);
+
+unsafe impl<T> CxxAsyncResultRepr for Vec<T> {
+    const KIND: CxxAsyncResultKind = CxxAsyncResultKind::Moved;
+
+    fn check_result(&self) -> CxxAsyncResult<()> {
+        check_payload_len::<T>(self.len())
+    }
+}
+
+/// Checks that `len` elements of type `T` fit in a `rust::Vec<T>` on the C++ side, failing with a
+/// "payload too large" error if they don't.
+///
+/// C++ code indexes and iterates over a `rust::Vec<T>` with pointer arithmetic, which is only
+/// defined within `PTRDIFF_MAX` bytes, so that's the limit: `isize::MAX` bytes, the same one that
+/// Rust places on allocations. A `Vec` that C++ could misread therefore fails cleanly instead of
+/// crossing the bridge.
+pub fn check_payload_len<T>(len: usize) -> CxxAsyncResult<()> {
+    match len.checked_mul(mem::size_of::<T>()) {
+        Some(bytes) if bytes <= isize::MAX as usize => Ok(()),
+        _ => Err(CxxAsyncException::new(
+            format!(
+                "payload too large: {} elements of {} bytes each exceed the {} bytes that C++ can \
+                 address",
+                len,
+                mem::size_of::<T>(),
+                isize::MAX
+            )
+            .into_boxed_str(),
+        )),
+    }
+}
+
+// `Box<T>` becomes a `rust::Box<T>` that C++ owns, just as a boxed value does.
+unsafe impl<T> CxxAsyncResultRepr for Box<T> {
+    const KIND: CxxAsyncResultKind = CxxAsyncResultKind::Moved;
+}
+
+unsafe impl<T> CxxAsyncResultRepr for cxx::UniquePtr<T>
+where
+    T: cxx::memory::UniquePtrTarget,
+{
+    const KIND: CxxAsyncResultKind = CxxAsyncResultKind::Moved;
+}
+
+unsafe impl<T> CxxAsyncResultRepr for cxx::SharedPtr<T>
+where
+    T: cxx::memory::SharedPtrTarget,
+{
+    const KIND: CxxAsyncResultKind = CxxAsyncResultKind::Moved;
+}
+
+// An array is handed over like its elements. Arrays of boxed values have no C++ equivalent.
+unsafe impl<T, const N: usize> CxxAsyncResultRepr for [T; N]
+where
+    T: CxxAsyncResultRepr,
+{
+    const KIND: CxxAsyncResultKind = match T::KIND {
+        CxxAsyncResultKind::InPlace => CxxAsyncResultKind::InPlace,
+        CxxAsyncResultKind::Moved => CxxAsyncResultKind::Moved,
+        CxxAsyncResultKind::Boxed => panic!("arrays of boxed values can't cross the bridge"),
+    };
+}
@@ -0,0 +1,177 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+// cxx-async/src/router.rs
+//
+//! Correlation of responses with outstanding requests that share one transport.
+
+use crate::CxxAsyncException;
+use crate::CxxAsyncResult;
+use crate::SafeUnwrap;
+use futures::channel::oneshot;
+use futures::channel::oneshot::Sender;
+use futures::FutureExt;
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::pin::Pin;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::Weak;
+use std::task::Context;
+use std::task::Poll;
+
+/// A map from request IDs to the futures awaiting their responses.
+///
+/// This supports RPC-style protocols in which many requests are outstanding at once over a single
+/// channel: Rust calls `request()` for each request it sends, and C++ calls back into Rust with
+/// each response as it arrives, which calls `route()` to complete the matching future. Responses
+/// may arrive in any order.
+///
+/// Cloning a router produces another handle to the same map.
+pub struct ResponseRouter<K, T> {
+    inner: Arc<RouterInner<K, T>>,
+}
+
+struct RouterInner<K, T> {
+    pending: Mutex<HashMap<K, PendingRequest<T>>>,
+    next_token: AtomicU64,
+}
+
+// The token distinguishes requests that reuse the same ID, so that a stale `Response` doesn't
+// remove its successor's entry when dropped.
+struct PendingRequest<T> {
+    token: u64,
+    sender: Sender<CxxAsyncResult<T>>,
+}
+
+/// A future that resolves to the response routed to one request. See `ResponseRouter`.
+///
+/// Dropping this before the response arrives removes the request from the router.
+pub struct Response<K, T>
+where
+    K: Eq + Hash,
+{
+    router: Weak<RouterInner<K, T>>,
+    id: K,
+    token: u64,
+    receiver: oneshot::Receiver<CxxAsyncResult<T>>,
+}
+
+impl<K, T> ResponseRouter<K, T>
+where
+    K: Eq + Hash + Clone,
+{
+    /// Creates a router with no outstanding requests.
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(RouterInner {
+                pending: Mutex::new(HashMap::new()),
+                next_token: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Registers an outstanding request with the given ID and returns a future that resolves to
+    /// its response.
+    ///
+    /// If a request with the same ID is already outstanding, it's replaced, and its future fails.
+    pub fn request(&self, id: K) -> Response<K, T> {
+        let (sender, receiver) = oneshot::channel();
+        let token = self.inner.next_token.fetch_add(1, Ordering::Relaxed);
+        self.inner
+            .pending
+            .lock()
+            .safe_unwrap()
+            .insert(id.clone(), PendingRequest { token, sender });
+        Response {
+            router: Arc::downgrade(&self.inner),
+            id,
+            token,
+            receiver,
+        }
+    }
+
+    /// Completes the outstanding request with the given ID with `response`.
+    ///
+    /// Returns false if no request with that ID is outstanding, either because it was never made,
+    /// because it has already been routed a response, or because its future was dropped.
+    pub fn route(&self, id: &K, response: CxxAsyncResult<T>) -> bool {
+        let request = self.inner.pending.lock().safe_unwrap().remove(id);
+        match request {
+            Some(request) => request.sender.send(response).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Returns the number of outstanding requests.
+    pub fn len(&self) -> usize {
+        self.inner.pending.lock().safe_unwrap().len()
+    }
+
+    /// Returns true if there are no outstanding requests.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<K, T> Default for ResponseRouter<K, T>
+where
+    K: Eq + Hash + Clone,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, T> Clone for ResponseRouter<K, T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<K, T> Future for Response<K, T>
+where
+    K: Eq + Hash + Unpin,
+{
+    type Output = CxxAsyncResult<T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.receiver.poll_unpin(cx).map(|result| match result {
+            Ok(response) => response,
+            Err(_) => Err(CxxAsyncException::new(
+                "The request was replaced by another with the same ID"
+                    .to_owned()
+                    .into_boxed_str(),
+            )),
+        })
+    }
+}
+
+impl<K, T> Drop for Response<K, T>
+where
+    K: Eq + Hash,
+{
+    fn drop(&mut self) {
+        let router = match self.router.upgrade() {
+            Some(router) => router,
+            None => return,
+        };
+        let mut pending = router.pending.lock().safe_unwrap();
+        if let Some(request) = pending.get(&self.id) {
+            if request.token == self.token {
+                pending.remove(&self.id);
+            }
+        }
+    }
+}
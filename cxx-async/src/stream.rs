@@ -0,0 +1,270 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+// cxx-async/src/stream.rs
+//
+//! Combinators for bridged streams.
+//!
+//! Bridged streams always flow from C++ to Rust: a C++ coroutine `co_yield`s items, and Rust
+//! consumes them. Backpressure is built in, since the channel between the two holds a single item
+//! or batch. A C++ producer that yields while it's full suspends until Rust takes what's there, so
+//! the Rust consumer paces the producer without either side busy-waiting. C++ can't consume Rust
+//! streams yet, so there's no sink for a Rust producer to watch for readiness.
+
+use crate::timer::Timer;
+use crate::CxxAsyncException;
+use crate::CxxAsyncResult;
+use crate::IntoCxxAsyncFuture;
+use futures::channel::oneshot;
+use futures::channel::oneshot::Sender;
+use futures::future;
+use futures::stream::SelectAll;
+use futures::FutureExt;
+use futures::Stream;
+use futures::StreamExt;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::Context;
+use std::task::Poll;
+use std::time::Duration;
+
+/// A stream that interleaves the items of several streams. See `merge()`.
+pub struct Merge<Stm> {
+    streams: SelectAll<Stm>,
+    failed: bool,
+}
+
+/// Merges several streams into one that yields items as soon as any of them produces one.
+///
+/// The merged stream ends once every source has ended. If any source fails, the merged stream
+/// yields that error and then ends, dropping the remaining sources. Dropping the merged stream
+/// early likewise drops, and thereby cancels, every source.
+pub fn merge<I, Stm, T>(streams: I) -> Merge<Stm>
+where
+    I: IntoIterator<Item = Stm>,
+    Stm: Stream<Item = CxxAsyncResult<T>> + Unpin,
+{
+    Merge {
+        streams: futures::stream::select_all(streams),
+        failed: false,
+    }
+}
+
+impl<Stm, T> Stream for Merge<Stm>
+where
+    Stm: Stream<Item = CxxAsyncResult<T>> + Unpin,
+{
+    type Item = CxxAsyncResult<T>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.failed {
+            return Poll::Ready(None);
+        }
+        let item = self.streams.poll_next_unpin(cx);
+        if let Poll::Ready(Some(Err(_))) = item {
+            self.failed = true;
+            self.streams.clear();
+        }
+        item
+    }
+}
+
+/// Collects the items of a stream until it ends or `timeout` elapses, whichever comes first, and
+/// resolves to the items collected so far.
+///
+/// If the stream fails, collection stops there, and the items that arrived before the error are
+/// returned. The stream is dropped, and thereby cancelled, when the returned future resolves.
+pub async fn collect_until<Stm, T>(mut stream: Stm, timeout: Duration) -> Vec<T>
+where
+    Stm: Stream<Item = CxxAsyncResult<T>> + Unpin,
+{
+    let mut timer = Timer::after(timeout);
+    let mut items = vec![];
+    future::poll_fn(|cx| loop {
+        // Check the deadline before each item, so that a stream that's always ready can't keep us
+        // past it.
+        if timer.poll_unpin(cx).is_ready() {
+            return Poll::Ready(());
+        }
+        match stream.poll_next_unpin(cx) {
+            Poll::Ready(Some(Ok(item))) => items.push(item),
+            Poll::Ready(Some(Err(_))) | Poll::Ready(None) => return Poll::Ready(()),
+            Poll::Pending => return Poll::Pending,
+        }
+    })
+    .await;
+    items
+}
+
+/// Drives a stream to its end and resolves to its last item, or to `None` if it yielded none.
+///
+/// If the stream fails, the future resolves to that error, discarding the items before it.
+pub async fn last<Stm, T>(mut stream: Stm) -> CxxAsyncResult<Option<T>>
+where
+    Stm: Stream<Item = CxxAsyncResult<T>> + Unpin,
+{
+    let mut last = None;
+    while let Some(item) = stream.next().await {
+        last = Some(item?);
+    }
+    Ok(last)
+}
+
+/// Collects all items of a stream into a bridged future that C++ can await once to receive them
+/// as a `rust::Vec`.
+///
+/// If the stream fails, the future fails with that error, discarding the items before it.
+pub fn collect_to_future<Fut, Stm, T>(mut stream: Stm) -> Fut
+where
+    Fut: IntoCxxAsyncFuture<Output = Vec<T>>,
+    Stm: Stream<Item = CxxAsyncResult<T>> + Unpin + Send + 'static,
+    T: Send + 'static,
+{
+    Fut::fallible(async move {
+        let mut items = vec![];
+        while let Some(item) = stream.next().await {
+            items.push(item?);
+        }
+        Ok(items)
+    })
+}
+
+/// A stream that yields at most a fixed number of items from another and then ends. See
+/// `take()`.
+pub struct Take<Stm> {
+    stream: Option<Stm>,
+    remaining: usize,
+}
+
+/// Yields at most `count` items of a stream and then ends.
+///
+/// Unlike `StreamExt::take()`, this drops, and thereby cancels, the source as soon as it has
+/// yielded the last item, instead of when the returned stream is dropped. A C++ producer is
+/// therefore destroyed right after it produces item `count`, rather than after it produces more.
+/// Errors count toward `count`.
+pub fn take<Stm>(stream: Stm, count: usize) -> Take<Stm>
+where
+    Stm: Stream + Unpin,
+{
+    Take {
+        stream: if count == 0 { None } else { Some(stream) },
+        remaining: count,
+    }
+}
+
+impl<Stm> Stream for Take<Stm>
+where
+    Stm: Stream + Unpin,
+{
+    type Item = Stm::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let stream = match self.stream {
+            Some(ref mut stream) => stream,
+            None => return Poll::Ready(None),
+        };
+        let item = match stream.poll_next_unpin(cx) {
+            Poll::Ready(item) => item,
+            Poll::Pending => return Poll::Pending,
+        };
+        match item {
+            Some(_) => self.remaining -= 1,
+            None => self.remaining = 0,
+        }
+        if self.remaining == 0 {
+            self.stream = None;
+        }
+        Poll::Ready(item)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match self.stream {
+            Some(ref stream) => {
+                let (lower, upper) = stream.size_hint();
+                let upper = match upper {
+                    Some(upper) => upper.min(self.remaining),
+                    None => self.remaining,
+                };
+                (lower.min(self.remaining), Some(upper))
+            }
+            None => (0, Some(0)),
+        }
+    }
+}
+
+/// The successful items of a stream split by `split_error()`.
+pub struct Values<Stm> {
+    stream: Option<Stm>,
+    completion: Option<Sender<CxxAsyncResult<()>>>,
+}
+
+/// A future that resolves once a stream split by `split_error()` has ended, to the error that it
+/// ended with, if any.
+pub struct Completion {
+    receiver: oneshot::Receiver<CxxAsyncResult<()>>,
+}
+
+/// Splits a stream of results into a stream of its successful items and a future that resolves
+/// to how it ended.
+///
+/// The item stream ends at the first error, dropping, and thereby cancelling, the source. The
+/// future then resolves to that error, or to `Ok(())` if the source ended cleanly. If the item
+/// stream is dropped before either happens, the future fails.
+pub fn split_error<Stm, T>(stream: Stm) -> (Values<Stm>, Completion)
+where
+    Stm: Stream<Item = CxxAsyncResult<T>> + Unpin,
+{
+    let (sender, receiver) = oneshot::channel();
+    let values = Values {
+        stream: Some(stream),
+        completion: Some(sender),
+    };
+    (values, Completion { receiver })
+}
+
+impl<Stm, T> Stream for Values<Stm>
+where
+    Stm: Stream<Item = CxxAsyncResult<T>> + Unpin,
+{
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let stream = match self.stream {
+            Some(ref mut stream) => stream,
+            None => return Poll::Ready(None),
+        };
+        let outcome = match stream.poll_next_unpin(cx) {
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(Some(Ok(item))) => return Poll::Ready(Some(item)),
+            Poll::Ready(Some(Err(exception))) => Err(exception),
+            Poll::Ready(None) => Ok(()),
+        };
+        self.stream = None;
+        if let Some(completion) = self.completion.take() {
+            // The completion future may have been dropped, in which case nobody wants the outcome.
+            let _ = completion.send(outcome);
+        }
+        Poll::Ready(None)
+    }
+}
+
+impl Future for Completion {
+    type Output = CxxAsyncResult<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.receiver.poll_unpin(cx).map(|result| match result {
+            Ok(outcome) => outcome,
+            Err(_) => Err(CxxAsyncException::new(
+                "The stream was dropped before it ended"
+                    .to_owned()
+                    .into_boxed_str(),
+            )),
+        })
+    }
+}
@@ -0,0 +1,94 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+// cxx-async/src/task_local.rs
+//
+//! Propagation of task-local context, such as a request ID, through bridged futures.
+//!
+//! A bridged future can be polled from whichever thread happens to wake it, including C++ threads
+//! that know nothing about the Rust runtime's task-locals. See
+//! `IntoCxxAsyncFuture::with_task_locals()`.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::Context;
+use std::task::Poll;
+
+/// A set of task-local values that a bridged future captures when it's created and re-establishes
+/// around each of its polls.
+///
+/// Implement this for a type holding the values of your task-locals. For a `tokio::task_local!`,
+/// for example, `capture()` clones the current value with `try_with()`, and `scope()` calls
+/// `sync_scope()` with a clone of it. Tuples of implementations capture and establish each of
+/// their elements in turn.
+pub trait TaskLocals: Send + 'static {
+    /// Captures the current values of the task-locals.
+    fn capture() -> Self;
+
+    /// Runs `f` with the captured values established, so that code that `f` runs sees them.
+    fn scope<R>(&mut self, f: impl FnOnce() -> R) -> R;
+}
+
+impl TaskLocals for () {
+    fn capture() -> Self {}
+
+    fn scope<R>(&mut self, f: impl FnOnce() -> R) -> R {
+        f()
+    }
+}
+
+macro_rules! impl_task_locals_for_tuple {
+    ($($name:ident),+) => {
+        impl<$($name: TaskLocals),+> TaskLocals for ($($name,)+) {
+            fn capture() -> Self {
+                ($($name::capture(),)+)
+            }
+
+            #[allow(non_snake_case)]
+            fn scope<R>(&mut self, f: impl FnOnce() -> R) -> R {
+                let ($(ref mut $name,)+) = *self;
+                impl_task_locals_for_tuple!(@nest f, $($name),+)
+            }
+        }
+    };
+    (@nest $f:ident, $first:ident $(, $rest:ident)*) => {
+        $first.scope(|| impl_task_locals_for_tuple!(@nest $f $(, $rest)*))
+    };
+    (@nest $f:ident) => {
+        $f()
+    };
+}
+
+impl_task_locals_for_tuple!(A);
+impl_task_locals_for_tuple!(A, B);
+impl_task_locals_for_tuple!(A, B, C);
+impl_task_locals_for_tuple!(A, B, C, D);
+
+// A future that re-establishes its captured task-locals around each poll.
+pub(crate) struct WithTaskLocals<L, Fut> {
+    pub(crate) locals: L,
+    pub(crate) future: Pin<Box<Fut>>,
+}
+
+// The locals are never pinned, and the future is pinned in its own box.
+impl<L, Fut> Unpin for WithTaskLocals<L, Fut> {}
+
+impl<L, Fut> Future for WithTaskLocals<L, Fut>
+where
+    L: TaskLocals,
+    Fut: Future,
+{
+    type Output = Fut::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let future = &mut this.future;
+        this.locals.scope(|| future.as_mut().poll(cx))
+    }
+}
@@ -0,0 +1,223 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+// cxx-async/src/timer.rs
+//
+//! The timers behind the crate's deadlines and grace periods.
+//!
+//! There's no timer runtime to lean on, so one thread, started on first use, serves every timer
+//! in the process. It sleeps until the earliest pending deadline, fires every timer that's due,
+//! and goes back to sleep. Dropping a timer removes its deadline, so a timer that's no longer
+//! needed costs nothing, however far off its deadline was.
+
+use crate::SafeExpect;
+use crate::SafeUnwrap;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::future::Future;
+use std::mem;
+use std::pin::Pin;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::task::Context;
+use std::task::Poll;
+use std::task::Waker;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+static TIMERS: Mutex<Timers> = Mutex::new(Timers {
+    entries: BTreeMap::new(),
+    deadlines: BTreeSet::new(),
+    next_id: 0,
+    started: false,
+});
+
+// Signaled whenever the earliest deadline may have moved.
+static CHANGED: Condvar = Condvar::new();
+
+struct Timers {
+    // Every timer that hasn't been dropped, by ID.
+    entries: BTreeMap<u64, Entry>,
+    // The deadlines of the timers that haven't fired yet, earliest first, along with their IDs.
+    deadlines: BTreeSet<(Instant, u64)>,
+    next_id: u64,
+    // Whether the timer thread has been started.
+    started: bool,
+}
+
+struct Entry {
+    // When the timer fires, or `None` if its deadline is too far off to represent and never
+    // arrives.
+    deadline: Option<Instant>,
+    fired: bool,
+    waker: Option<Waker>,
+}
+
+fn lock() -> MutexGuard<'static, Timers> {
+    TIMERS.lock().unwrap_or_else(|error| error.into_inner())
+}
+
+impl Timers {
+    // Fires every timer whose deadline has passed, returning their wakers, and returns the next
+    // deadline, if any.
+    fn fire_due(&mut self, now: Instant, wakers: &mut Vec<Waker>) -> Option<Instant> {
+        while let Some(&(deadline, id)) = self.deadlines.first() {
+            if deadline > now {
+                return Some(deadline);
+            }
+            self.deadlines.pop_first();
+            if let Some(entry) = self.entries.get_mut(&id) {
+                entry.fired = true;
+                wakers.extend(entry.waker.take());
+            }
+        }
+        None
+    }
+}
+
+// Runs forever on the timer thread.
+fn run() {
+    let mut wakers = vec![];
+    let mut timers = lock();
+    loop {
+        let next = timers.fire_due(Instant::now(), &mut wakers);
+        if !wakers.is_empty() {
+            // Wake outside the lock, since waking may run arbitrary code, including code that
+            // creates timers.
+            drop(timers);
+            wakers.drain(..).for_each(Waker::wake);
+            timers = lock();
+            continue;
+        }
+        timers = match next {
+            Some(deadline) => {
+                CHANGED
+                    .wait_timeout(timers, deadline.saturating_duration_since(Instant::now()))
+                    .unwrap_or_else(|error| error.into_inner())
+                    .0
+            }
+            None => CHANGED
+                .wait(timers)
+                .unwrap_or_else(|error| error.into_inner()),
+        };
+    }
+}
+
+/// A future that resolves once its deadline passes. Dropping it cancels it.
+pub(crate) struct Timer {
+    key: TimerKey,
+}
+
+/// Identifies a timer, for moving its deadline or checking whether it has fired from wherever the
+/// timer itself isn't reachable. Once the timer is dropped, the key refers to nothing.
+#[derive(Clone, Copy)]
+pub(crate) struct TimerKey(u64);
+
+impl Timer {
+    /// Creates a timer that fires once `timeout` has elapsed.
+    pub(crate) fn after(timeout: Duration) -> Self {
+        let deadline = Instant::now().checked_add(timeout);
+        let mut timers = lock();
+        let id = timers.next_id;
+        timers.next_id += 1;
+        timers.entries.insert(
+            id,
+            Entry {
+                deadline,
+                fired: false,
+                waker: None,
+            },
+        );
+        if let Some(deadline) = deadline {
+            timers.deadlines.insert((deadline, id));
+        }
+        if !timers.started {
+            timers.started = true;
+            thread::Builder::new()
+                .name("cxx-async-timer".to_owned())
+                .spawn(run)
+                .safe_unwrap();
+        }
+        CHANGED.notify_one();
+        Timer { key: TimerKey(id) }
+    }
+
+    pub(crate) fn key(&self) -> TimerKey {
+        self.key
+    }
+}
+
+impl TimerKey {
+    /// Moves the deadline of the timer to `timeout` from now, which may be earlier or later than
+    /// before.
+    ///
+    /// Returns false, changing nothing, if the timer has already fired or been dropped.
+    pub(crate) fn reset(self, timeout: Duration) -> bool {
+        let deadline = Instant::now().checked_add(timeout);
+        let mut timers = lock();
+        let entry = match timers.entries.get_mut(&self.0) {
+            Some(entry) if !entry.fired => entry,
+            _ => return false,
+        };
+        let old_deadline = mem::replace(&mut entry.deadline, deadline);
+        if let Some(old_deadline) = old_deadline {
+            timers.deadlines.remove(&(old_deadline, self.0));
+        }
+        if let Some(deadline) = deadline {
+            timers.deadlines.insert((deadline, self.0));
+        }
+        CHANGED.notify_one();
+        true
+    }
+
+    /// Returns true if the timer has fired.
+    pub(crate) fn has_fired(self) -> bool {
+        lock().entries.get(&self.0).is_some_and(|entry| entry.fired)
+    }
+}
+
+impl Future for Timer {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut timers = lock();
+        let entry = timers
+            .entries
+            .get_mut(&self.key.0)
+            .safe_expect("Where's the timer's entry?");
+        if entry.fired {
+            return Poll::Ready(());
+        }
+        if !entry
+            .waker
+            .as_ref()
+            .is_some_and(|waker| waker.will_wake(cx.waker()))
+        {
+            entry.waker = Some(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        let mut timers = lock();
+        // If this was the earliest deadline, the timer thread wakes up for it anyway and finds
+        // nothing due, so there's no need to notify it.
+        if let Some(Entry {
+            deadline: Some(deadline),
+            ..
+        }) = timers.entries.remove(&self.key.0)
+        {
+            timers.deadlines.remove(&(deadline, self.key.0));
+        }
+    }
+}
@@ -0,0 +1,124 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+// cxx-async/src/watchdog.rs
+//
+//! Detection of bridged futures that are polled over and over without being woken.
+//!
+//! This module is only available when the `pending-watchdog` feature is enabled. A future that
+//! keeps returning `Pending` to polls that no wakeup asked for usually means that whatever polls
+//! it has lost track of its waker and is spinning, burning CPU without making progress. When that
+//! happens, the watchdog logs a warning via `log`, naming the future if it was given a name with
+//! `FutureBuilder::name()`. It never changes how futures behave.
+
+use futures::task::ArcWake;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::task::Waker;
+use std::time::Duration;
+use std::time::Instant;
+
+/// The default number of consecutive unwoken polls that trigger a warning.
+pub const DEFAULT_POLL_THRESHOLD: u64 = 1000;
+
+/// The default window within which those polls must happen.
+pub const DEFAULT_WINDOW: Duration = Duration::from_secs(1);
+
+static POLL_THRESHOLD: AtomicU64 = AtomicU64::new(DEFAULT_POLL_THRESHOLD);
+static WINDOW_NANOS: AtomicU64 = AtomicU64::new(DEFAULT_WINDOW.as_nanos() as u64);
+
+/// Sets how many consecutive polls returning `Pending` without an intervening wakeup, all within
+/// `window`, make the watchdog warn about a future.
+///
+/// This affects polls made after the call.
+pub fn set_threshold(polls: u64, window: Duration) {
+    POLL_THRESHOLD.store(polls.max(1), Ordering::Relaxed);
+    WINDOW_NANOS.store(
+        u64::try_from(window.as_nanos()).unwrap_or(u64::MAX),
+        Ordering::Relaxed,
+    );
+}
+
+// The watchdog state of one bridged future.
+pub(crate) struct Watchdog {
+    // Set whenever a waker handed to the future is woken.
+    woken: Arc<AtomicBool>,
+    // Whether the last poll returned `Pending`.
+    pending: bool,
+    // The number of consecutive polls since the last wakeup, and when the first of them happened.
+    unwoken_polls: u64,
+    window_start: Option<Instant>,
+}
+
+// Records that the wrapped waker was woken before passing the wakeup on.
+struct FlaggingWaker {
+    woken: Arc<AtomicBool>,
+    waker: Waker,
+}
+
+impl ArcWake for FlaggingWaker {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        arc_self.woken.store(true, Ordering::Release);
+        arc_self.waker.wake_by_ref();
+    }
+}
+
+impl Watchdog {
+    pub(crate) fn new() -> Self {
+        Watchdog {
+            woken: Arc::new(AtomicBool::new(false)),
+            pending: false,
+            unwoken_polls: 0,
+            window_start: None,
+        }
+    }
+
+    // Called before each poll. Returns the waker to poll the future with, which notes wakeups.
+    pub(crate) fn before_poll(&mut self, waker: &Waker) -> Waker {
+        let woken = self.woken.swap(false, Ordering::Acquire);
+        if !self.pending || woken {
+            self.unwoken_polls = 0;
+            self.window_start = None;
+        } else {
+            self.unwoken_polls += 1;
+            self.window_start.get_or_insert_with(Instant::now);
+        }
+        futures::task::waker(Arc::new(FlaggingWaker {
+            woken: self.woken.clone(),
+            waker: waker.clone(),
+        }))
+    }
+
+    // Called after each poll, with a description of the future to log if it seems to be spinning.
+    pub(crate) fn after_poll(&mut self, pending: bool, describe: impl FnOnce() -> String) {
+        self.pending = pending;
+        let threshold = POLL_THRESHOLD.load(Ordering::Relaxed);
+        if !pending || self.unwoken_polls < threshold {
+            return;
+        }
+        let elapsed = self
+            .window_start
+            .map_or(Duration::ZERO, |start| start.elapsed());
+        let window = Duration::from_nanos(WINDOW_NANOS.load(Ordering::Relaxed));
+        if elapsed <= window {
+            log::warn!(
+                "{} returned Pending to {} consecutive polls in {:?} without being woken; \
+                 whatever polls it may be spinning instead of waiting for its waker",
+                describe(),
+                self.unwoken_polls,
+                elapsed
+            );
+        }
+        // Start counting afresh, so that a spinning future is reported once per threshold.
+        self.unwoken_polls = 0;
+        self.window_start = None;
+    }
+}
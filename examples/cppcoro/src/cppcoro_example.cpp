@@ -19,15 +19,18 @@
 #include <cppcoro/sync_wait.hpp>
 #include <cppcoro/task.hpp>
 #include <cppcoro/when_all.hpp>
+#include <chrono>
 #include <condition_variable>
 #include <cstdlib>
 #include <exception>
 #include <functional>
 #include <iosfwd>
 #include <iostream>
+#include <limits>
 #include <mutex>
 #include <new>
 #include <stdexcept>
+#include <stop_token>
 #include <string>
 #include <thread>
 #include <tuple>
@@ -81,6 +84,12 @@ RustFutureF64 cppcoro_dot_product() {
   co_return co_await dot_product();
 }
 
+// Multiplies the dot product by `scale`, so that concurrent calls can be told
+// apart by their results.
+RustFutureF64 cppcoro_scaled_dot_product(double scale) {
+  co_return scale * co_await dot_product();
+}
+
 foo::bar::RustFutureStringNamespaced cppcoro_get_namespaced_string() {
   co_return rust::String("hello world");
 }
@@ -93,6 +102,10 @@ double cppcoro_call_rust_dot_product() {
   return cppcoro::sync_wait(rust_dot_product());
 }
 
+double cppcoro_call_rust_stash_waker() {
+  return cppcoro::sync_wait(rust_stash_waker());
+}
+
 double cppcoro_schedule_rust_dot_product() {
   return cppcoro::sync_wait(
       cppcoro::schedule_on(g_thread_pool, rust_dot_product()));
@@ -180,6 +193,85 @@ RustStreamString cppcoro_not_fizzbuzz() {
   throw MyException("kablam");
 }
 
+// Yields two items and then fails. The Rust side should see both items, then
+// the error, and then the end of the stream.
+RustStreamF64 cppcoro_two_then_fail() {
+  co_yield 1.0;
+  co_yield 2.0;
+  throw MyException("kersplat");
+}
+
+// Yields the numbers from 0 to `count` one at a time.
+RustStreamF64 cppcoro_count(size_t count) {
+  for (size_t i = 0; i < count; i++)
+    co_yield (double)i;
+  co_return;
+}
+
+// Yields the numbers from 0 to `count`, one every `interval_ms` milliseconds.
+RustStreamF64 cppcoro_count_slowly(size_t count, uint32_t interval_ms) {
+  for (size_t i = 0; i < count; i++) {
+    // Sleep on the thread pool, so that the thread consuming the stream isn't
+    // blocked in the meantime.
+    co_await g_thread_pool.schedule();
+    std::this_thread::sleep_for(std::chrono::milliseconds(interval_ms));
+    co_yield (double)i;
+  }
+  co_return;
+}
+
+static std::atomic<size_t> g_live_count_forever;
+
+// Yields 0, 1, 2, and so on until Rust drops the stream.
+RustStreamF64 cppcoro_count_forever() {
+  // Track whether the coroutine is still alive, so that tests can check when it
+  // gets destroyed.
+  struct Live {
+    Live() {
+      g_live_count_forever++;
+    }
+    ~Live() {
+      g_live_count_forever--;
+    }
+  } live;
+  for (size_t i = 0;; i++)
+    co_yield (double)i;
+}
+
+size_t cppcoro_live_count_forever() {
+  return g_live_count_forever;
+}
+
+// Yields the numbers from 0 to `count` in batches of `batch_size`.
+RustStreamF64 cppcoro_count_batched(size_t count, size_t batch_size) {
+  std::vector<double> batch;
+  for (size_t start = 0; start < count; start += batch_size) {
+    batch.clear();
+    for (size_t i = start; i < count && i < start + batch_size; i++)
+      batch.push_back((double)i);
+    co_yield rust::async::batch(
+        rust::Slice<const double>(batch.data(), batch.size()));
+  }
+  co_return;
+}
+
+std::atomic<size_t> DropCounted::s_live;
+
+// Creates `count` heap-allocated values up front and then yields them one at a
+// time.
+RustStreamDropCounted cppcoro_drop_counted_stream(size_t count) {
+  std::vector<std::unique_ptr<DropCounted>> items;
+  for (size_t i = 0; i < count; i++)
+    items.push_back(std::make_unique<DropCounted>());
+  for (std::unique_ptr<DropCounted>& item : items)
+    co_yield std::move(item);
+  co_return;
+}
+
+size_t cppcoro_live_drop_counted() {
+  return DropCounted::live();
+}
+
 struct DestructorTest {
   cppcoro::async_latch m_latch;
   Sem m_sem;
@@ -216,3 +308,643 @@ RustFutureVoid cppcoro_drop_coroutine_signal() {
   g_destructor_test.m_sem.wait();
   co_return;
 }
+
+static Sem g_cancellation_sem;
+
+// Waits until Rust drops the returned future, and then finishes. The semaphore
+// is signaled in the destructor of an object on this coroutine's stack.
+RustFutureF64 cppcoro_cancellable() {
+  struct SignalOnDestruction {
+    ~SignalOnDestruction() {
+      g_cancellation_sem.signal();
+    }
+  };
+
+  SignalOnDestruction signaller;
+  auto latch = std::make_shared<cppcoro::async_latch>(1);
+  co_await rust::async::on_cancel([latch] { latch->count_down(); });
+  co_await *latch;
+  co_return 0.0;
+}
+
+void cppcoro_wait_for_cancellation() {
+  g_cancellation_sem.wait();
+}
+
+static std::atomic<size_t> g_cancelled_ping_pong_levels;
+
+// Like `cppcoro_ping_pong()`, but the innermost Rust future never finishes.
+// Each level counts itself when its coroutine is destroyed, which only happens
+// when the whole chain is cancelled.
+RustFutureString cppcoro_cancellable_ping_pong(int i) {
+  struct CountOnDestruction {
+    ~CountOnDestruction() {
+      g_cancelled_ping_pong_levels++;
+    }
+  };
+
+  CountOnDestruction counter;
+  std::string string(co_await rust_cppcoro_cancellable_ping_pong(i));
+  co_return std::move(string) + "pong ";
+}
+
+size_t cppcoro_cancelled_ping_pong_levels() {
+  return g_cancelled_ping_pong_levels;
+}
+
+// Returns a future that resolves to another future, for testing `flatten`.
+RustFutureFutureF64 cppcoro_nested_dot_product() {
+  co_return cppcoro_dot_product();
+}
+
+// Returns a future that resolves to a future that fails.
+RustFutureFutureF64 cppcoro_nested_not_product() {
+  co_return cppcoro_not_product();
+}
+
+// Returns a future that fails before producing the inner future.
+RustFutureFutureF64 cppcoro_not_nested_product() {
+  if (true)
+    throw MyException("kaboom");
+  co_return cppcoro_dot_product();
+}
+
+// Returns a fixed-size array, for testing large by-value payloads.
+RustFutureRow cppcoro_matrix_row() {
+  MatrixRow row;
+  for (size_t i = 0; i < row.size(); i++)
+    row[i] = (double)i * 0.5;
+  co_return row;
+}
+
+bool cppcoro_call_rust_matrix_row() {
+  MatrixRow row = cppcoro::sync_wait(rust_matrix_row());
+  for (size_t i = 0; i < row.size(); i++) {
+    if (row[i] != (double)i * 0.5)
+      return false;
+  }
+  return true;
+}
+
+// Returns a large variable-length value, for testing transfer of ownership of
+// heap memory.
+RustFutureBytes cppcoro_large_bytes(size_t len) {
+  rust::Vec<uint8_t> bytes;
+  bytes.reserve(len);
+  for (size_t i = 0; i < len; i++)
+    bytes.push_back((uint8_t)(i % 251));
+  co_return std::move(bytes);
+}
+
+// Takes ownership of a buffer allocated by Rust, and reports where it is. The
+// buffer is freed, by Rust's allocator, when `bytes` goes out of scope.
+BufferInfo cppcoro_take_owned_buffer(size_t len) {
+  rust::Vec<uint8_t> bytes = cppcoro::sync_wait(rust_owned_buffer(len));
+  return BufferInfo{
+      reinterpret_cast<size_t>(bytes.data()), bytes.capacity()};
+}
+
+bool cppcoro_call_rust_large_bytes(size_t len) {
+  rust::Vec<uint8_t> bytes = cppcoro::sync_wait(rust_large_bytes(len));
+  if (bytes.size() != len)
+    return false;
+  for (size_t i = 0; i < len; i++) {
+    if (bytes[i] != (uint8_t)(i % 251))
+      return false;
+  }
+  return true;
+}
+
+// Resolves as soon as it's polled, since the Rust future it awaits is already
+// ready.
+RustFutureF64 cppcoro_ready_value(double x) {
+  co_return co_await rust_ready_value(x);
+}
+
+RustFutureF64 cppcoro_slow_square(double x) {
+  co_return co_await rust_slow_square(x);
+}
+
+RustFutureReply cppcoro_reply(int32_t which) {
+  switch (which) {
+    case 0:
+      co_return Reply::make_value(1.5);
+    case 1:
+      co_return Reply::make_retry(250);
+    default:
+      co_return Reply::make_failed(rust::String("backend unavailable"));
+  }
+}
+
+// Round-trips a reply produced by Rust.
+RustFutureReply cppcoro_forward_reply(int32_t which) {
+  co_return co_await rust_reply(which);
+}
+
+RustFutureRecord cppcoro_record() {
+  co_return Record{rust::String("readings"), rust::Vec<double>{1.5, 2.5, 3.5}};
+}
+
+// Round-trips a record produced by Rust.
+RustFutureRecord cppcoro_forward_record() {
+  co_return co_await rust_record();
+}
+
+// Awaits a failing Rust future, tagging the await site.
+RustFutureF64 cppcoro_not_product_at_await_site() {
+  co_return co_await rust::async::set_await_site(rust_not_product(), __func__);
+}
+
+// Awaits a Rust future that's ready after `polls` polls, polling it up to
+// `max_spins` times before suspending.
+RustFutureF64 cppcoro_spin_on_rust(uint32_t polls, size_t max_spins) {
+  co_return co_await rust_ready_after_polls(polls).await_spin(max_spins);
+}
+
+// Deliberately not atomic: Rust reads it after awaiting
+// `cppcoro_publish_from_thread_pool()`, relying on completing the future to
+// publish it.
+static uint64_t g_published;
+
+// Writes `value` to `g_published` on the thread pool and then completes with
+// it from there.
+RustFutureF64 cppcoro_publish_from_thread_pool(uint64_t value) {
+  co_await g_thread_pool.schedule();
+  g_published = value;
+  co_return (double)value;
+}
+
+uint64_t cppcoro_published() {
+  return g_published;
+}
+
+// `MyException` doesn't own its message, so it's kept here.
+static std::string g_long_message;
+
+// Throws an exception whose message is `len` bytes long.
+RustFutureF64 cppcoro_throw_long_message(size_t len) {
+  g_long_message = std::string(len, 'x');
+  if (true)
+    throw MyException(g_long_message.c_str());
+  co_return 1.0; // Just to make this function a coroutine.
+}
+
+// Computes a quotient and a remainder on the thread pool, completing each
+// separately. On division by zero, the remainder is left for the destructor of
+// its out-parameter to fail.
+static RustFutureVoid cppcoro_complete_divmod(
+    int32_t dividend,
+    int32_t divisor,
+    rust::async::RustOutParam<RustFutureI32> quotient,
+    rust::async::RustOutParam<RustFutureI32> remainder) {
+  co_await g_thread_pool.schedule();
+  if (divisor == 0) {
+    quotient.fail("division by zero");
+    co_return;
+  }
+  quotient.complete(dividend / divisor);
+  remainder.complete(dividend % divisor);
+}
+
+DivMod cppcoro_divmod(int32_t dividend, int32_t divisor) {
+  auto [quotient, remainder] =
+      rust::async::make_group<RustFutureI32, RustFutureI32>();
+  RustFutureI32 quotient_future = quotient.take_future();
+  RustFutureI32 remainder_future = remainder.take_future();
+  RustFutureVoid done = cppcoro_complete_divmod(
+      dividend, divisor, std::move(quotient), std::move(remainder));
+  return DivMod{
+      std::move(done), std::move(quotient_future), std::move(remainder_future)};
+}
+
+static rust::async::RustEvent<RustFutureVoid> g_event;
+
+RustFutureVoid cppcoro_event_wait() {
+  return g_event.wait();
+}
+
+// Sets `g_event` from a new thread after `delay_ms` milliseconds.
+void cppcoro_event_set_from_thread(uint64_t delay_ms) {
+  std::thread([delay_ms] {
+    std::this_thread::sleep_for(std::chrono::milliseconds(delay_ms));
+    g_event.set();
+  }).detach();
+}
+
+void cppcoro_event_reset() {
+  g_event.reset();
+}
+
+// Awaits a Rust future that's expected to fail, and reports whether it was
+// cancelled or failed outright.
+rust::String cppcoro_describe_failure(RustFutureF64 future) {
+  try {
+    cppcoro::sync_wait(std::move(future));
+    return rust::String("completed");
+  } catch (const rust::async::CxxAsyncCancelled& cancelled) {
+    return rust::String(std::string("cancelled: ") + cancelled.what());
+  } catch (const rust::async::Error& error) {
+    return rust::String(std::string("failed: ") + error.what());
+  }
+}
+
+// Awaits a vector of all items of a Rust stream and yields them in reverse.
+RustFutureF64Vec cppcoro_reverse_f64_vec(RustFutureF64Vec future) {
+  rust::Vec<double> items = co_await std::move(future);
+  rust::Vec<double> reversed;
+  for (size_t i = items.size(); i > 0; i--)
+    reversed.push_back(items[i - 1]);
+  co_return reversed;
+}
+
+// Awaits a boxed Rust value, updates it, and hands it back to Rust.
+RustFutureCounter cppcoro_bump_counter(RustFutureCounter future) {
+  rust::Box<Counter> counter = co_await std::move(future);
+  counter->increment();
+  co_return std::move(counter);
+}
+
+// Checks that Rust and C++ agree on how a scalar, an owning type, and a boxed
+// Rust type are handed over, and that mismatches would be caught.
+bool cppcoro_result_kinds_agree() {
+  using rust::async::check_result_kind;
+  using rust::async::result_kind_agrees;
+  using rust::async::ResultKind;
+  return check_result_kind<RustFutureF64>() &&
+      check_result_kind<RustFutureString>() &&
+      check_result_kind<RustFutureCounter>() &&
+      !result_kind_agrees<double>(ResultKind::Boxed) &&
+      !result_kind_agrees<rust::String>(ResultKind::InPlace) &&
+      result_kind_agrees<rust::Box<Counter>>(ResultKind::Moved);
+}
+
+static std::atomic<size_t> g_cleanups;
+
+// Awaits a Rust future, counting a cleanup if the await is cancelled.
+RustFutureF64 cppcoro_await_with_cleanup(RustFutureF64 future) {
+  co_return co_await rust::async::cleanup_on_cancel(
+      std::move(future), [] { g_cleanups++; });
+}
+
+size_t cppcoro_cleanups() {
+  return g_cleanups;
+}
+
+// Returns the time point `seconds` and `millis` after the Unix epoch.
+RustFutureTimePoint cppcoro_time_point(int64_t seconds, int32_t millis) {
+  co_return rust::async::to_epoch_nanos(std::chrono::system_clock::time_point(
+      std::chrono::seconds(seconds) + std::chrono::milliseconds(millis)));
+}
+
+// Awaits a time point from Rust and returns the time point a second later.
+RustFutureTimePoint cppcoro_add_second(RustFutureTimePoint future) {
+  std::chrono::system_clock::time_point time =
+      rust::async::from_epoch_nanos(co_await std::move(future));
+  co_return rust::async::to_epoch_nanos(time + std::chrono::seconds(1));
+}
+
+// Waits for a Rust future from synchronous code that isn't a coroutine.
+double cppcoro_blocking_wait(RustFutureF64 future) {
+  return rust::async::blocking_wait(std::move(future));
+}
+
+// Awaits two Rust booleans. Values this small come back from each poll packed
+// alongside the poll status.
+RustFutureBool cppcoro_both(RustFutureBool a, RustFutureBool b) {
+  bool first = co_await std::move(a);
+  bool second = co_await std::move(b);
+  co_return first && second;
+}
+
+RustFutureI32 cppcoro_negate(RustFutureI32 future) {
+  co_return -co_await std::move(future);
+}
+
+// Returns true if Rust offers packed polls exactly for the futures whose values
+// fit in them.
+bool cppcoro_packs_results() {
+  return RustFutureVoid::vtable()->future_poll_packed != nullptr &&
+      RustFutureBool::vtable()->future_poll_packed != nullptr &&
+      RustFutureI32::vtable()->future_poll_packed != nullptr &&
+      RustFutureF64::vtable()->future_poll_packed == nullptr &&
+      RustFutureString::vtable()->future_poll_packed == nullptr;
+}
+
+// A thread that runs the functions posted to it in order, standing in for a
+// C++ event loop.
+class ResumeLoop {
+  std::mutex m_lock;
+  std::condition_variable m_cond;
+  std::vector<std::function<void()>> m_queue;
+  std::thread::id m_id;
+
+ public:
+  ResumeLoop() {
+    std::thread thread([this]() { run(); });
+    m_id = thread.get_id();
+    thread.detach();
+  }
+
+  std::thread::id id() const {
+    return m_id;
+  }
+
+  void post(std::function<void()> function) {
+    std::lock_guard<std::mutex> guard(m_lock);
+    m_queue.push_back(std::move(function));
+    m_cond.notify_one();
+  }
+
+ private:
+  void run() {
+    while (true) {
+      std::vector<std::function<void()>> functions;
+      {
+        std::unique_lock<std::mutex> guard(m_lock);
+        m_cond.wait(guard, [this]() { return !m_queue.empty(); });
+        std::swap(functions, m_queue);
+      }
+      for (std::function<void()>& function : functions) {
+        function();
+      }
+    }
+  }
+};
+
+// Leaked so that the thread never outlives it.
+static ResumeLoop& resume_loop() {
+  static ResumeLoop* resume_loop = new ResumeLoop();
+  return *resume_loop;
+}
+
+// Awaits a Rust future, resuming on the loop thread no matter which thread
+// completes the future.
+RustFutureF64 cppcoro_add_one_on_loop(RustFutureF64 future) {
+  double value = co_await rust::async::resume_via(
+      std::move(future), [](std::function<void()> resume) {
+        resume_loop().post(std::move(resume));
+      });
+  if (std::this_thread::get_id() != resume_loop().id()) {
+    throw std::runtime_error("resumed on the wrong thread");
+  }
+  co_return value + 1.0;
+}
+
+// Returns true if the C++ types of these futures have the layouts that Rust
+// gives their values, and that a mismatched type would be caught.
+bool cppcoro_result_layouts_agree() {
+  using rust::async::check_result_layout;
+  using rust::async::result_layout_agrees;
+  return check_result_layout<RustFutureVoid>() &&
+      check_result_layout<RustFutureI32>() &&
+      check_result_layout<RustFutureF64>() &&
+      check_result_layout<RustFutureString>() &&
+      check_result_layout<RustFutureCounter>() &&
+      !result_layout_agrees<int64_t>(
+          RustFutureI32::vtable()->result_layout()) &&
+      !result_layout_agrees<int16_t>(
+          RustFutureI32::vtable()->result_layout()) &&
+      !result_layout_agrees<double>(RustFutureVoid::vtable()->result_layout());
+}
+
+static std::atomic<size_t> g_foreign_buffers_freed;
+
+// The deleter of the buffers that `cppcoro_foreign_buffer()` hands out.
+static void free_foreign_buffer(uint8_t* data, size_t) {
+  delete[] data;
+  g_foreign_buffers_freed++;
+}
+
+// Returns `len` bytes counting up from zero in a buffer with a deleter of its
+// own, the way a C API might.
+RustFutureForeignBuffer cppcoro_foreign_buffer(size_t len) {
+  uint8_t* data = new uint8_t[len];
+  for (size_t i = 0; i < len; i++) {
+    data[i] = static_cast<uint8_t>(i);
+  }
+  co_return rust::async::ForeignBuffer(data, len, free_foreign_buffer);
+}
+
+size_t cppcoro_foreign_buffers_freed() {
+  return g_foreign_buffers_freed;
+}
+
+static std::mutex g_stop_lock;
+static std::stop_source g_stop_source;
+
+// Returns a future that completes once `cppcoro_request_stop()` is next called.
+RustFutureVoid cppcoro_stop_requested() {
+  std::lock_guard<std::mutex> guard(g_stop_lock);
+  return rust::async::stop_requested<RustFutureVoid>(
+      g_stop_source.get_token());
+}
+
+// Requests stop through the token that the futures from
+// `cppcoro_stop_requested()` watch, and starts afresh with a new one.
+void cppcoro_request_stop() {
+  std::stop_source stop_source;
+  {
+    std::lock_guard<std::mutex> guard(g_stop_lock);
+    std::swap(stop_source, g_stop_source);
+  }
+  stop_source.request_stop();
+}
+
+RustFutureF64 cppcoro_add_guarded(
+    RustFutureGuardedI32 a,
+    RustFutureGuardedF64 b) {
+  int32_t first = co_await std::move(a);
+  double second = co_await std::move(b);
+  co_return first + second;
+}
+
+// Completes an out-parameter from a string that's still used afterward, which
+// must be copied rather than moved from.
+RustFutureString cppcoro_complete_from_lvalue() {
+  rust::async::RustOutParam<RustFutureString> out;
+  RustFutureString future = out.take_future();
+  rust::String greeting("hello");
+  out.complete(greeting);
+  rust::String completed = co_await std::move(future);
+  co_return std::string(completed) + ", " + std::string(greeting);
+}
+
+// Delivers responses to requests 1, 2, and 3 over a shared channel, in a
+// different order than the requests were made.
+void cppcoro_route_responses_out_of_order() {
+  rust_route_response(3, 30.0);
+  rust_route_response(1, 10.0);
+  rust_route_response(2, 20.0);
+}
+
+RustFutureString cppcoro_subtask_thread_name(int32_t pool) {
+  co_return co_await rust_subtask_thread_name(pool);
+}
+
+// The plain C interface to `RustFutureF64`, generated by its
+// `c_abi = rust_future_f64` attribute. Nothing below uses `cxx` or coroutines.
+extern "C" {
+struct CxxAsyncCWaker {
+  void* data;
+  void (*wake)(void* data);
+  void (*release)(void* data);
+};
+
+uint32_t rust_future_f64_poll(
+    uint64_t handle,
+    double* result,
+    char** error,
+    CxxAsyncCWaker waker);
+bool rust_future_f64_send(uint64_t sender, double* value);
+void cxxasync_c_string_free(char* string);
+}
+
+// Wakers may outlive the poll loop, so the state they point to is refcounted:
+// one reference for the loop, and one for each poll until Rust releases it.
+struct CWakerState {
+  Sem m_sem;
+  std::atomic<size_t> m_refcount;
+
+  CWakerState() : m_refcount(1) {}
+
+  static void wake(void* data) {
+    static_cast<CWakerState*>(data)->m_sem.signal();
+  }
+  static void release(void* data) {
+    CWakerState* state = static_cast<CWakerState*>(data);
+    if (--state->m_refcount == 0) {
+      delete state;
+    }
+  }
+};
+
+// Polls the future behind `handle` until it's no longer pending, blocking
+// while it is. Returns the final status.
+static uint32_t poll_c_abi_future(
+    uint64_t handle,
+    double* result,
+    char** error) {
+  CWakerState* state = new CWakerState();
+  CxxAsyncCWaker waker{state, CWakerState::wake, CWakerState::release};
+  uint32_t status;
+  while (true) {
+    state->m_refcount++;
+    status = rust_future_f64_poll(handle, result, error, waker);
+    if (status != 0) {
+      break;
+    }
+    state->m_sem.wait();
+  }
+  CWakerState::release(state);
+  return status;
+}
+
+// Returns the value of the future behind `handle`, or NaN if it failed or the
+// handle is invalid.
+double cppcoro_poll_c_abi_future(uint64_t handle) {
+  double result = std::numeric_limits<double>::quiet_NaN();
+  poll_c_abi_future(handle, &result, nullptr);
+  return result;
+}
+
+// Returns the exception message of the future behind `handle`, or an empty
+// string if it didn't fail.
+rust::String cppcoro_poll_c_abi_error(uint64_t handle) {
+  double result;
+  char* error = nullptr;
+  if (poll_c_abi_future(handle, &result, &error) != 2) {
+    return rust::String();
+  }
+  rust::String what(error);
+  cxxasync_c_string_free(error);
+  return what;
+}
+
+bool cppcoro_send_c_abi(uint64_t sender, double value) {
+  return rust_future_f64_send(sender, &value);
+}
+
+// Finishes early with -1.0 when Rust asks it to.
+RustFutureF64 cppcoro_cooperative() {
+  auto latch = std::make_shared<cppcoro::async_latch>(1);
+  co_await rust::async::on_cancel([latch] { latch->count_down(); });
+  co_await *latch;
+  co_return -1.0;
+}
+
+static struct {
+  cppcoro::async_latch m_latch{1};
+  Sem m_sem;
+} g_uncooperative_test;
+
+// Ignores requests to finish early and waits for
+// `cppcoro_release_uncooperative()`. The semaphore is signaled in the
+// destructor of an object on this coroutine's stack.
+RustFutureF64 cppcoro_uncooperative() {
+  struct SignalOnDestruction {
+    ~SignalOnDestruction() {
+      g_uncooperative_test.m_sem.signal();
+    }
+  };
+
+  SignalOnDestruction signaller;
+  co_await g_uncooperative_test.m_latch;
+  co_return 0.0;
+}
+
+// Lets `cppcoro_uncooperative()` finish, and waits for it to do so.
+void cppcoro_release_uncooperative() {
+  g_uncooperative_test.m_latch.count_down();
+  g_uncooperative_test.m_sem.wait();
+}
+
+double cppcoro_call_rust_sum_ready_values() {
+  return cppcoro::sync_wait(rust_sum_ready_values());
+}
+
+// Stands in for a C++ API that expects a native cppcoro future type.
+static double wait_for_native_future(cppcoro::task<double> task) {
+  return cppcoro::sync_wait(task);
+}
+
+double cppcoro_call_rust_dot_product_natively() {
+  return wait_for_native_future(rust::async::to_task(rust_dot_product()));
+}
+
+// Takes ownership of a Rust future and drops it without awaiting it.
+void cppcoro_drop_future(RustFutureF64 future) {
+  RustFutureF64 dropped(std::move(future));
+}
+
+RustFutureI32 cppcoro_answer() {
+  co_return 42;
+}
+
+// Awaits a Rust future, letting any exception it throws propagate back out.
+RustFutureF64 cppcoro_rethrow(RustFutureF64 future) {
+  co_return co_await std::move(future);
+}
+
+// Decodes a `Tagged` value serialized by Rust as a little-endian 32-bit tag
+// followed by UTF-8 text, increments its tag, appends "!" to its text, and
+// serializes it again.
+RustFutureTagged cppcoro_bump_tagged(RustFutureTagged future) {
+  rust::Vec<uint8_t> bytes = co_await std::move(future);
+  if (bytes.size() < 4)
+    throw MyException("truncated tag");
+  uint32_t tag = 0;
+  for (size_t i = 0; i < 4; i++)
+    tag |= (uint32_t)bytes[i] << (8 * i);
+  std::string text(bytes.begin() + 4, bytes.end());
+
+  tag++;
+  text += "!";
+
+  rust::Vec<uint8_t> result;
+  result.reserve(4 + text.size());
+  for (size_t i = 0; i < 4; i++)
+    result.push_back((uint8_t)(tag >> (8 * i)));
+  for (char c : text)
+    result.push_back((uint8_t)c);
+  co_return std::move(result);
+}
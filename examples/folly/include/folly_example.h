@@ -13,16 +13,119 @@
 #define CXX_ASYNC_FOLLY_EXAMPLE_H
 
 #include <folly/ExceptionWrapper.h>
+#include <array>
+#include <atomic>
+#include <cstddef>
+#include <cstdint>
 #include <exception>
 #include <iostream>
+#include <memory>
+#include <new>
+#include <utility>
 #include "rust/cxx.h"
 #include "rust/cxx_async.h"
 
+// A heap-allocated value that tracks how many instances of it are alive, to
+// check for leaks.
+class DropCounted {
+  static std::atomic<size_t> s_live;
+
+ public:
+  DropCounted() {
+    s_live++;
+  }
+  ~DropCounted() {
+    s_live--;
+  }
+  static size_t live() {
+    return s_live;
+  }
+};
+
+// Mirrors the Rust `Reply` enum. Because that enum is `#[repr(C, u8)]`, its
+// layout is a `uint8_t` tag followed by a union of the variants' fields, so the
+// bridge can move it across as is.
+struct Reply {
+  enum class Tag : uint8_t { Value, Retry, Failed };
+
+  Tag tag;
+  union {
+    double value;
+    uint32_t retry_after_ms;
+    rust::String failure;
+  };
+
+  static Reply make_value(double value) {
+    Reply reply(Tag::Value);
+    reply.value = value;
+    return reply;
+  }
+  static Reply make_retry(uint32_t after_ms) {
+    Reply reply(Tag::Retry);
+    reply.retry_after_ms = after_ms;
+    return reply;
+  }
+  static Reply make_failed(rust::String failure) {
+    Reply reply(Tag::Failed);
+    new (&reply.failure) rust::String(std::move(failure));
+    return reply;
+  }
+
+  Reply(Reply&& other) noexcept : tag(other.tag) {
+    switch (tag) {
+      case Tag::Value:
+        value = other.value;
+        break;
+      case Tag::Retry:
+        retry_after_ms = other.retry_after_ms;
+        break;
+      case Tag::Failed:
+        new (&failure) rust::String(std::move(other.failure));
+        break;
+    }
+  }
+  ~Reply() {
+    if (tag == Tag::Failed) {
+      failure.~String();
+    }
+  }
+
+ private:
+  explicit Reply(Tag tag) : tag(tag) {}
+};
+
+// The macro can't take a type containing a comma, so name the array type first.
+using MatrixRow = std::array<double, 256>;
+
 CXXASYNC_DEFINE_FUTURE(void, RustFutureVoid);
+CXXASYNC_DEFINE_FUTURE(bool, RustFutureBool);
+CXXASYNC_DEFINE_FUTURE(int32_t, RustFutureI32);
 CXXASYNC_DEFINE_FUTURE(double, RustFutureF64);
 CXXASYNC_DEFINE_FUTURE(rust::String, RustFutureString);
+CXXASYNC_DEFINE_FUTURE(RustFutureF64, RustFutureFutureF64);
+CXXASYNC_DEFINE_FUTURE(MatrixRow, RustFutureRow);
+CXXASYNC_DEFINE_FUTURE(rust::Vec<uint8_t>, RustFutureBytes);
+CXXASYNC_DEFINE_FUTURE(rust::Vec<double>, RustFutureF64Vec);
+CXXASYNC_DEFINE_FUTURE(Reply, RustFutureReply);
+// `Record`, `BufferInfo`, and `DivMod` are shared structs, defined by the generated bridge
+// header, which includes this one. Declaring them is enough to define the
+// future type and the functions below.
+struct Record;
+struct BufferInfo;
+struct DivMod;
+// `Counter` is an opaque Rust type, which C++ only ever holds in a `rust::Box`.
+struct Counter;
+CXXASYNC_DEFINE_FUTURE(rust::Box<Counter>, RustFutureCounter);
+CXXASYNC_DEFINE_FUTURE(Record, RustFutureRecord);
+CXXASYNC_DEFINE_FUTURE(rust::Vec<uint8_t>, RustFutureTagged);
+CXXASYNC_DEFINE_FUTURE(int64_t, RustFutureTimePoint);
+CXXASYNC_DEFINE_FUTURE(rust::async::ForeignBuffer, RustFutureForeignBuffer);
+CXXASYNC_DEFINE_FUTURE(int32_t, RustFutureGuardedI32);
+CXXASYNC_DEFINE_FUTURE(double, RustFutureGuardedF64);
 CXXASYNC_DEFINE_FUTURE(rust::String, foo, bar, RustFutureStringNamespaced);
 CXXASYNC_DEFINE_STREAM(rust::String, RustStreamString);
+CXXASYNC_DEFINE_STREAM(double, RustStreamF64);
+CXXASYNC_DEFINE_STREAM(std::unique_ptr<DropCounted>, RustStreamDropCounted);
 
 class MyException : public std::exception {
   const char* m_message;
@@ -60,10 +163,12 @@ struct TryCatch<T, Custom> {
 } // namespace rust
 
 RustFutureF64 folly_dot_product_coro();
+RustFutureF64 folly_scaled_dot_product(double scale);
 RustFutureF64 folly_dot_product_futures();
 void folly_call_rust_hello();
 foo::bar::RustFutureStringNamespaced folly_get_namespaced_string();
 double folly_call_rust_dot_product();
+double folly_call_rust_stash_waker();
 double folly_schedule_rust_dot_product();
 RustFutureF64 folly_not_product();
 rust::String folly_call_rust_not_product();
@@ -74,7 +179,74 @@ RustFutureF64 folly_send_to_dropped_future();
 RustStreamString folly_fizzbuzz();
 RustStreamString folly_indirect_fizzbuzz();
 RustStreamString folly_not_fizzbuzz();
+RustStreamF64 folly_two_then_fail();
+RustStreamF64 folly_count(size_t count);
+RustStreamF64 folly_count_batched(size_t count, size_t batch_size);
+RustStreamF64 folly_count_slowly(size_t count, uint32_t interval_ms);
+RustStreamF64 folly_count_forever();
+size_t folly_live_count_forever();
+RustStreamDropCounted folly_drop_counted_stream(size_t count);
+size_t folly_live_drop_counted();
 RustFutureVoid folly_drop_coroutine_wait();
 RustFutureVoid folly_drop_coroutine_signal();
+RustFutureF64 folly_cancellable();
+void folly_wait_for_cancellation();
+RustFutureFutureF64 folly_nested_dot_product();
+RustFutureFutureF64 folly_nested_not_product();
+RustFutureFutureF64 folly_not_nested_product();
+RustFutureRow folly_matrix_row();
+bool folly_call_rust_matrix_row();
+RustFutureBytes folly_large_bytes(size_t len);
+bool folly_call_rust_large_bytes(size_t len);
+BufferInfo folly_take_owned_buffer(size_t len);
+RustFutureF64 folly_ready_value(double x);
+RustFutureF64 folly_slow_square(double x);
+RustFutureReply folly_reply(int32_t which);
+RustFutureReply folly_forward_reply(int32_t which);
+RustFutureRecord folly_record();
+RustFutureRecord folly_forward_record();
+RustFutureF64 folly_not_product_at_await_site();
+RustFutureF64 folly_spin_on_rust(uint32_t polls, size_t max_spins);
+RustFutureF64 folly_publish_from_thread_pool(uint64_t value);
+uint64_t folly_published();
+RustFutureF64 folly_throw_long_message(size_t len);
+DivMod folly_divmod(int32_t dividend, int32_t divisor);
+RustFutureVoid folly_event_wait();
+void folly_event_set_from_thread(uint64_t delay_ms);
+void folly_event_reset();
+rust::String folly_describe_failure(RustFutureF64 future);
+RustFutureF64Vec folly_reverse_f64_vec(RustFutureF64Vec future);
+RustFutureCounter folly_bump_counter(RustFutureCounter future);
+bool folly_result_kinds_agree();
+RustFutureF64 folly_await_with_cleanup(RustFutureF64 future);
+size_t folly_cleanups();
+RustFutureTimePoint folly_time_point(int64_t seconds, int32_t millis);
+RustFutureTimePoint folly_add_second(RustFutureTimePoint future);
+double folly_blocking_wait(RustFutureF64 future);
+RustFutureBool folly_both(RustFutureBool a, RustFutureBool b);
+RustFutureI32 folly_negate(RustFutureI32 future);
+bool folly_packs_results();
+RustFutureF64 folly_add_one_on_loop(RustFutureF64 future);
+bool folly_result_layouts_agree();
+RustFutureForeignBuffer folly_foreign_buffer(size_t len);
+size_t folly_foreign_buffers_freed();
+RustFutureVoid folly_stop_requested();
+void folly_request_stop();
+RustFutureF64 folly_add_guarded(RustFutureGuardedI32 a, RustFutureGuardedF64 b);
+RustFutureString folly_complete_from_lvalue();
+void folly_route_responses_out_of_order();
+RustFutureString folly_subtask_thread_name(int32_t pool);
+double folly_poll_c_abi_future(uint64_t handle);
+rust::String folly_poll_c_abi_error(uint64_t handle);
+bool folly_send_c_abi(uint64_t sender, double value);
+RustFutureF64 folly_cooperative();
+RustFutureF64 folly_uncooperative();
+void folly_release_uncooperative();
+double folly_call_rust_sum_ready_values();
+double folly_call_rust_dot_product_natively();
+void folly_drop_future(RustFutureF64 future);
+RustFutureI32 folly_answer();
+RustFutureF64 folly_rethrow(RustFutureF64 future);
+RustFutureTagged folly_bump_tagged(RustFutureTagged future);
 
 #endif // CXX_ASYNC_FOLLY_EXAMPLE_H
@@ -2,46 +2,117 @@
 //
 //! Demonstrates how to use Folly with `cxx_async`.
 
+use crate::ffi::BufferInfo;
+use crate::ffi::Record;
 use crate::ffi::StringNamespaced;
 use async_recursion::async_recursion;
-use cxx_async::CxxAsyncException;
+use cxx::UniquePtr;
+use cxx_async::router::ResponseRouter;
+use cxx_async::task_local::TaskLocals;
+use cxx_async::CxxAsyncPayload;
+use cxx_async::CxxAsyncResult;
+use cxx_async::CxxAsyncResultKind;
+use cxx_async::CxxAsyncResultRepr;
 use futures::executor::{self, ThreadPool};
 use futures::task::SpawnExt;
 use futures::{StreamExt, TryStreamExt};
 use futures::{Stream, join};
 use once_cell::sync::Lazy;
+use std::cell::Cell;
 use std::future::Future;
 use std::ops::Range;
+use std::sync::Mutex;
+use std::sync::Once;
+use std::time::Instant;
+use std::time::SystemTime;
 
+// The `Box<Counter>` glue that `cxx` generates ignores the result of `Box::from_raw()`.
 #[cxx::bridge]
+#[allow(unused_must_use)]
 mod ffi {
     #[derive(Debug, PartialEq)]
     struct StringNamespaced {
         namespaced_string: String,
     }
 
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    struct BufferInfo {
+        address: usize,
+        capacity: usize,
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct Record {
+        name: String,
+        values: Vec<f64>,
+    }
+
+    // The results of one division, which C++ completes separately.
+    struct DivMod {
+        done: RustFutureVoid,
+        quotient: RustFutureI32,
+        remainder: RustFutureI32,
+    }
+
     extern "Rust" {
+        type Counter;
+
+        fn increment(self: &mut Counter);
+
         fn rust_hello() -> RustFutureVoid;
         fn rust_dot_product() -> RustFutureF64;
         fn rust_not_product() -> RustFutureF64;
+        fn rust_matrix_row() -> RustFutureRow;
+        fn rust_large_bytes(len: usize) -> RustFutureBytes;
+        fn rust_owned_buffer(len: usize) -> RustFutureBytes;
         fn rust_folly_ping_pong(i: i32) -> RustFutureString;
+        fn rust_ready_value(x: f64) -> RustFutureF64;
+        fn rust_sum_ready_values() -> RustFutureF64;
+        fn rust_slow_square(x: f64) -> RustFutureF64;
+        fn rust_stash_waker() -> RustFutureF64;
+        fn rust_ready_after_polls(polls: u32) -> RustFutureF64;
+        fn rust_reply(which: i32) -> RustFutureReply;
+        fn rust_record() -> RustFutureRecord;
+        fn rust_route_response(id: u64, value: f64) -> bool;
+        fn rust_subtask_thread_name(pool: i32) -> RustFutureString;
+        fn rust_counter(start: u64) -> RustFutureCounter;
     }
 
     unsafe extern "C++" {
         include!("folly_example.h");
 
+        type DropCounted;
+
         type RustFutureVoid = crate::RustFutureVoid;
+        type RustFutureBool = crate::RustFutureBool;
+        type RustFutureI32 = crate::RustFutureI32;
         type RustFutureF64 = crate::RustFutureF64;
         type RustFutureString = crate::RustFutureString;
+        type RustFutureFutureF64 = crate::RustFutureFutureF64;
+        type RustFutureRow = crate::RustFutureRow;
+        type RustFutureBytes = crate::RustFutureBytes;
+        type RustFutureF64Vec = crate::RustFutureF64Vec;
+        type RustFutureCounter = crate::RustFutureCounter;
+        type RustFutureReply = crate::RustFutureReply;
+        type RustFutureRecord = crate::RustFutureRecord;
+        type RustFutureTagged = crate::RustFutureTagged;
+        type RustFutureTimePoint = crate::RustFutureTimePoint;
+        type RustFutureForeignBuffer = crate::RustFutureForeignBuffer;
+        type RustFutureGuardedI32 = crate::RustFutureGuardedI32;
+        type RustFutureGuardedF64 = crate::RustFutureGuardedF64;
         #[namespace = foo::bar]
         type RustFutureStringNamespaced = crate::RustFutureStringNamespaced;
         type RustStreamString = crate::RustStreamString;
+        type RustStreamF64 = crate::RustStreamF64;
+        type RustStreamDropCounted = crate::RustStreamDropCounted;
 
         fn folly_dot_product_coro() -> RustFutureF64;
+        fn folly_scaled_dot_product(scale: f64) -> RustFutureF64;
         fn folly_dot_product_futures() -> RustFutureF64;
         fn folly_get_namespaced_string() -> RustFutureStringNamespaced;
         fn folly_call_rust_hello();
         fn folly_call_rust_dot_product() -> f64;
+        fn folly_call_rust_stash_waker() -> f64;
         fn folly_schedule_rust_dot_product() -> f64;
         fn folly_not_product() -> RustFutureF64;
         fn folly_call_rust_not_product() -> String;
@@ -52,9 +123,79 @@ mod ffi {
         fn folly_fizzbuzz() -> RustStreamString;
         fn folly_indirect_fizzbuzz() -> RustStreamString;
         fn folly_not_fizzbuzz() -> RustStreamString;
+        fn folly_two_then_fail() -> RustStreamF64;
+        fn folly_count(count: usize) -> RustStreamF64;
+        fn folly_count_batched(count: usize, batch_size: usize) -> RustStreamF64;
+        fn folly_count_slowly(count: usize, interval_ms: u32) -> RustStreamF64;
+        fn folly_count_forever() -> RustStreamF64;
+        fn folly_live_count_forever() -> usize;
+        fn folly_drop_counted_stream(count: usize) -> RustStreamDropCounted;
+        fn folly_live_drop_counted() -> usize;
         fn folly_drop_coroutine_wait() -> RustFutureVoid;
         fn folly_drop_coroutine_signal() -> RustFutureVoid;
+        fn folly_cancellable() -> RustFutureF64;
+        fn folly_wait_for_cancellation();
+        fn folly_nested_dot_product() -> RustFutureFutureF64;
+        fn folly_nested_not_product() -> RustFutureFutureF64;
+        fn folly_not_nested_product() -> RustFutureFutureF64;
+        fn folly_matrix_row() -> RustFutureRow;
+        fn folly_call_rust_matrix_row() -> bool;
+        fn folly_large_bytes(len: usize) -> RustFutureBytes;
+        fn folly_call_rust_large_bytes(len: usize) -> bool;
+        fn folly_take_owned_buffer(len: usize) -> BufferInfo;
+        fn folly_ready_value(x: f64) -> RustFutureF64;
+        fn folly_call_rust_sum_ready_values() -> f64;
+        fn folly_slow_square(x: f64) -> RustFutureF64;
+        fn folly_reply(which: i32) -> RustFutureReply;
+        fn folly_forward_reply(which: i32) -> RustFutureReply;
+        fn folly_record() -> RustFutureRecord;
+        fn folly_forward_record() -> RustFutureRecord;
+        fn folly_not_product_at_await_site() -> RustFutureF64;
+        fn folly_spin_on_rust(polls: u32, max_spins: usize) -> RustFutureF64;
+        fn folly_publish_from_thread_pool(value: u64) -> RustFutureF64;
+        fn folly_published() -> u64;
+        fn folly_throw_long_message(len: usize) -> RustFutureF64;
+        fn folly_divmod(dividend: i32, divisor: i32) -> DivMod;
+        fn folly_event_wait() -> RustFutureVoid;
+        fn folly_event_set_from_thread(delay_ms: u64);
+        fn folly_event_reset();
+        fn folly_describe_failure(future: RustFutureF64) -> String;
+        fn folly_reverse_f64_vec(future: RustFutureF64Vec) -> RustFutureF64Vec;
+        fn folly_bump_counter(future: RustFutureCounter) -> RustFutureCounter;
+        fn folly_result_kinds_agree() -> bool;
+        fn folly_await_with_cleanup(future: RustFutureF64) -> RustFutureF64;
+        fn folly_cleanups() -> usize;
+        fn folly_time_point(seconds: i64, millis: i32) -> RustFutureTimePoint;
+        fn folly_add_second(future: RustFutureTimePoint) -> RustFutureTimePoint;
+        fn folly_blocking_wait(future: RustFutureF64) -> Result<f64>;
+        fn folly_both(a: RustFutureBool, b: RustFutureBool) -> RustFutureBool;
+        fn folly_negate(future: RustFutureI32) -> RustFutureI32;
+        fn folly_packs_results() -> bool;
+        fn folly_add_one_on_loop(future: RustFutureF64) -> RustFutureF64;
+        fn folly_result_layouts_agree() -> bool;
+        fn folly_foreign_buffer(len: usize) -> RustFutureForeignBuffer;
+        fn folly_foreign_buffers_freed() -> usize;
+        fn folly_stop_requested() -> RustFutureVoid;
+        fn folly_request_stop();
+        fn folly_add_guarded(a: RustFutureGuardedI32, b: RustFutureGuardedF64) -> RustFutureF64;
+        fn folly_complete_from_lvalue() -> RustFutureString;
+        fn folly_route_responses_out_of_order();
+        fn folly_subtask_thread_name(pool: i32) -> RustFutureString;
+        fn folly_poll_c_abi_future(handle: u64) -> f64;
+        fn folly_poll_c_abi_error(handle: u64) -> String;
+        fn folly_send_c_abi(sender: u64, value: f64) -> bool;
+        fn folly_cooperative() -> RustFutureF64;
+        fn folly_uncooperative() -> RustFutureF64;
+        fn folly_release_uncooperative();
+        fn folly_call_rust_dot_product_natively() -> f64;
+        fn folly_drop_future(future: RustFutureF64);
+        fn folly_answer() -> RustFutureI32;
+        fn folly_rethrow(future: RustFutureF64) -> RustFutureF64;
+        fn folly_bump_tagged(future: RustFutureTagged) -> RustFutureTagged;
     }
+
+    impl UniquePtr<DropCounted> {}
+    impl Box<Counter> {}
 }
 
 #[cxx_async::bridge]
@@ -62,6 +203,15 @@ unsafe impl Future for RustFutureVoid {
     type Output = ();
 }
 #[cxx_async::bridge]
+unsafe impl Future for RustFutureBool {
+    type Output = bool;
+}
+#[cxx_async::bridge]
+unsafe impl Future for RustFutureI32 {
+    type Output = i32;
+}
+// Also exposed to plain C, for `folly_poll_c_abi_future()` and friends.
+#[cxx_async::bridge(c_abi = rust_future_f64)]
 unsafe impl Future for RustFutureF64 {
     type Output = f64;
 }
@@ -69,6 +219,56 @@ unsafe impl Future for RustFutureF64 {
 unsafe impl Future for RustFutureString {
     type Output = String;
 }
+#[cxx_async::bridge]
+unsafe impl Future for RustFutureFutureF64 {
+    type Output = RustFutureF64;
+}
+#[cxx_async::bridge]
+unsafe impl Future for RustFutureRow {
+    type Output = [f64; ROW_LENGTH];
+}
+#[cxx_async::bridge]
+unsafe impl Future for RustFutureBytes {
+    type Output = Vec<u8>;
+}
+#[cxx_async::bridge]
+unsafe impl Future for RustFutureF64Vec {
+    type Output = Vec<f64>;
+}
+#[cxx_async::bridge]
+unsafe impl Future for RustFutureReply {
+    type Output = Reply;
+}
+#[cxx_async::bridge]
+unsafe impl Future for RustFutureRecord {
+    type Output = Record;
+}
+#[cxx_async::bridge]
+unsafe impl Future for RustFutureCounter {
+    type Output = Counter;
+}
+// Carries a serialized `Tagged`.
+#[cxx_async::bridge(payload = Tagged)]
+unsafe impl Future for RustFutureTagged {
+    type Output = Vec<u8>;
+}
+// Carries a `std::chrono::system_clock::time_point` as nanoseconds since the Unix epoch.
+#[cxx_async::bridge(payload = SystemTime)]
+unsafe impl Future for RustFutureTimePoint {
+    type Output = i64;
+}
+#[cxx_async::bridge]
+unsafe impl Future for RustFutureForeignBuffer {
+    type Output = cxx_async::ForeignBuffer;
+}
+#[cxx_async::bridge]
+unsafe impl Future for RustFutureGuardedI32 {
+    type Output = Guarded<i32>;
+}
+#[cxx_async::bridge]
+unsafe impl Future for RustFutureGuardedF64 {
+    type Output = Guarded<f64>;
+}
 #[cxx_async::bridge(namespace = foo::bar)]
 unsafe impl Future for RustFutureStringNamespaced {
     type Output = StringNamespaced;
@@ -77,12 +277,130 @@ unsafe impl Future for RustFutureStringNamespaced {
 unsafe impl Stream for RustStreamString {
     type Item = String;
 }
+#[cxx_async::bridge]
+unsafe impl Stream for RustStreamF64 {
+    type Item = f64;
+}
+#[cxx_async::bridge]
+unsafe impl Stream for RustStreamDropCounted {
+    type Item = UniquePtr<ffi::DropCounted>;
+}
+
+// A protocol response with several shapes. This is `#[repr(C, u8)]` so that its layout matches the
+// tagged union `Reply` on the C++ side.
+#[repr(C, u8)]
+#[derive(Debug, PartialEq)]
+pub enum Reply {
+    Value(f64),
+    Retry { after_ms: u32 },
+    Failed(String),
+}
+
+// C++ moves replies, and the shared structs below, like the `cxx` types they contain.
+unsafe impl CxxAsyncResultRepr for Reply {
+    const KIND: CxxAsyncResultKind = CxxAsyncResultKind::Moved;
+}
+unsafe impl CxxAsyncResultRepr for Record {
+    const KIND: CxxAsyncResultKind = CxxAsyncResultKind::Moved;
+}
+unsafe impl CxxAsyncResultRepr for StringNamespaced {
+    const KIND: CxxAsyncResultKind = CxxAsyncResultKind::Moved;
+}
+
+// An opaque Rust type, which C++ can only hold in a `rust::Box`.
+pub struct Counter(u64);
+
+impl Counter {
+    fn increment(&mut self) {
+        self.0 += 1;
+    }
+}
+
+unsafe impl CxxAsyncResultRepr for Counter {
+    const KIND: CxxAsyncResultKind = CxxAsyncResultKind::Boxed;
+}
+
+// A number that refuses to cross the bridge when it's negative, standing in for a value that C++
+// can't safely hold.
+#[repr(transparent)]
+pub struct Guarded<T>(T);
+
+unsafe impl<T> CxxAsyncResultRepr for Guarded<T>
+where
+    T: Copy + Default + PartialOrd,
+{
+    const KIND: CxxAsyncResultKind = CxxAsyncResultKind::InPlace;
+
+    fn check_result(&self) -> CxxAsyncResult<()> {
+        if self.0 < T::default() {
+            return Err(cxx_async::CxxAsyncException::from(
+                "refusing a negative value",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Reply {
+    fn example(which: i32) -> Reply {
+        match which {
+            0 => Reply::Value(1.5),
+            1 => Reply::Retry { after_ms: 250 },
+            _ => Reply::Failed("backend unavailable".to_owned()),
+        }
+    }
+}
+
+// A value that `cxx` can't share, sent across the bridge as a little-endian 32-bit tag followed by
+// UTF-8 text.
+#[derive(Debug, PartialEq)]
+pub struct Tagged {
+    tag: u32,
+    text: String,
+}
+
+impl CxxAsyncPayload for Tagged {
+    fn encode(self) -> Vec<u8> {
+        let mut bytes = self.tag.to_le_bytes().to_vec();
+        bytes.extend_from_slice(self.text.as_bytes());
+        bytes
+    }
+
+    fn decode(mut bytes: Vec<u8>) -> CxxAsyncResult<Self> {
+        if bytes.len() < 4 {
+            return Err("truncated tag".into());
+        }
+        let text = bytes.split_off(4);
+        Ok(Tagged {
+            tag: u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            text: String::from_utf8(text).map_err(|error| error.to_string())?,
+        })
+    }
+}
+
+// The C++ `DropCounted` type has no thread affinity.
+unsafe impl Send for ffi::DropCounted {}
 
 const VECTOR_LENGTH: usize = 16384;
 const SPLIT_LIMIT: usize = 32;
+const ROW_LENGTH: usize = 256;
 
 static THREAD_POOL: Lazy<ThreadPool> = Lazy::new(|| ThreadPool::new().unwrap());
 
+// Two pools for `rust_subtask_thread_name()` to choose between.
+static SUBTASK_POOLS: Lazy<[ThreadPool; 2]> = Lazy::new(|| {
+    ["subtask-pool-a-", "subtask-pool-b-"].map(|prefix| {
+        ThreadPool::builder()
+            .pool_size(1)
+            .name_prefix(prefix)
+            .create()
+            .unwrap()
+    })
+});
+
+// Outstanding requests whose responses C++ delivers through `rust_route_response()`.
+static RESPONSE_ROUTER: Lazy<ResponseRouter<u64, f64>> = Lazy::new(ResponseRouter::new);
+
 static VECTORS: Lazy<(Vec<f64>, Vec<f64>)> = Lazy::new(|| {
     let mut rand = Xorshift::new();
     let (mut vector_a, mut vector_b) = (vec![], vec![]);
@@ -138,9 +456,187 @@ fn rust_dot_product() -> RustFutureF64 {
     RustFutureF64::infallible(dot_product(0..VECTOR_LENGTH))
 }
 
+fn rust_matrix_row() -> RustFutureRow {
+    RustFutureRow::infallible(async {
+        let mut row = [0.0; ROW_LENGTH];
+        for (i, element) in row.iter_mut().enumerate() {
+            *element = i as f64 * 0.5;
+        }
+        row
+    })
+}
+
+fn rust_large_bytes(len: usize) -> RustFutureBytes {
+    RustFutureBytes::infallible(async move { (0..len).map(|i| (i % 251) as u8).collect() })
+}
+
+// The address and capacity of the buffer that `rust_owned_buffer()` last allocated.
+static OWNED_BUFFER: Mutex<Option<BufferInfo>> = Mutex::new(None);
+
+// Allocates a buffer with spare capacity, and records where it is so that C++ can check that it
+// received that very allocation.
+fn rust_owned_buffer(len: usize) -> RustFutureBytes {
+    RustFutureBytes::infallible(async move {
+        let mut bytes = Vec::with_capacity(len + len / 2);
+        bytes.resize(len, 0xab);
+        *OWNED_BUFFER.lock().unwrap() = Some(BufferInfo {
+            address: bytes.as_ptr() as usize,
+            capacity: bytes.capacity(),
+        });
+        bytes
+    })
+}
+
 fn rust_not_product() -> RustFutureF64 {
-    RustFutureF64::fallible(async {
-        Err(CxxAsyncException::new("kapow".to_owned().into_boxed_str()))
+    RustFutureF64::fallible(async { Err("kapow".into()) })
+}
+
+fn rust_ready_value(x: f64) -> RustFutureF64 {
+    RustFutureF64::ready(x)
+}
+
+fn rust_reply(which: i32) -> RustFutureReply {
+    RustFutureReply::infallible(async move { Reply::example(which) })
+}
+
+impl Record {
+    fn example() -> Record {
+        Record {
+            name: "readings".to_owned(),
+            values: vec![1.5, 2.5, 3.5],
+        }
+    }
+}
+
+fn rust_record() -> RustFutureRecord {
+    RustFutureRecord::infallible(async { Record::example() })
+}
+
+fn rust_route_response(id: u64, value: f64) -> bool {
+    RESPONSE_ROUTER.route(&id, Ok(value))
+}
+
+// Spawns a subtask onto whichever pool the caller picked, and returns the name of the thread that
+// the subtask ran on.
+fn rust_subtask_thread_name(pool: i32) -> RustFutureString {
+    let executor = SUBTASK_POOLS[pool as usize].clone();
+    RustFutureString::from_on(executor, async {
+        let executor = cxx_async::current_executor().unwrap();
+        executor
+            .spawn_with_handle(async {
+                std::thread::current().name().unwrap_or_default().to_owned()
+            })
+            .unwrap()
+            .await
+    })
+}
+
+fn rust_counter(start: u64) -> RustFutureCounter {
+    RustFutureCounter::infallible(async move { Counter(start) })
+}
+
+const SLOW_SQUARE_DURATION_MS: u64 = 200;
+
+// Installs the pool that blocking closures run on. Everything that uses `from_blocking()` calls
+// this first, so that the pool is the same whichever test runs first.
+fn install_default_executor() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        let pool = ThreadPool::builder()
+            .pool_size(2)
+            .name_prefix("default-pool-")
+            .create()
+            .unwrap();
+        assert!(cxx_async::set_default_executor(pool));
+    });
+}
+
+// Stands in for a heavy synchronous computation, which runs on the blocking thread pool.
+fn rust_slow_square(x: f64) -> RustFutureF64 {
+    install_default_executor();
+    RustFutureF64::from_blocking(move || {
+        std::thread::sleep(std::time::Duration::from_millis(SLOW_SQUARE_DURATION_MS));
+        x * x
+    })
+}
+
+// A request ID kept in a thread-local, standing in for a task-local of an async runtime.
+thread_local! {
+    static REQUEST_ID: Cell<u64> = const { Cell::new(0) };
+}
+
+// The captured value of `REQUEST_ID`, re-established around each poll of a bridged future.
+struct RequestId(u64);
+
+impl TaskLocals for RequestId {
+    fn capture() -> Self {
+        RequestId(REQUEST_ID.with(Cell::get))
+    }
+
+    fn scope<R>(&mut self, f: impl FnOnce() -> R) -> R {
+        let previous = REQUEST_ID.with(|id| id.replace(self.0));
+        let result = f();
+        REQUEST_ID.with(|id| id.set(previous));
+        result
+    }
+}
+
+// Reads the request ID after a hop to the blocking thread pool, so that C++ polls this future
+// again from that thread.
+fn rust_read_request_id() -> RustFutureF64 {
+    RustFutureF64::with_task_locals::<RequestId, _>(async {
+        RustFutureF64::from_blocking(|| 0.0).await.unwrap();
+        REQUEST_ID.with(Cell::get) as f64
+    })
+}
+
+// The waker of the last `rust_stash_waker()` future, kept after the future has completed.
+static STASHED_WAKER: Mutex<Option<std::task::Waker>> = Mutex::new(None);
+
+// Resolves to 1.0 once woken from another thread, stashing its waker in `STASHED_WAKER` so that it
+// can be invoked again after the awaiting coroutine has been resumed.
+fn rust_stash_waker() -> RustFutureF64 {
+    let mut woken = false;
+    RustFutureF64::infallible(futures::future::poll_fn(move |cx| {
+        if woken {
+            return std::task::Poll::Ready(1.0);
+        }
+        woken = true;
+        *STASHED_WAKER.lock().unwrap() = Some(cx.waker().clone());
+        let waker = cx.waker().clone();
+        std::thread::spawn(move || waker.wake());
+        std::task::Poll::Pending
+    }))
+}
+
+const READY_AFTER_POLLS_WAKE_DELAY_MS: u64 = 100;
+
+// Resolves to `polls` after being polled that many times. Each time it isn't ready, it wakes itself
+// after a delay, which stands in for going to sleep until a result that's almost ready arrives.
+fn rust_ready_after_polls(polls: u32) -> RustFutureF64 {
+    let mut remaining = polls;
+    RustFutureF64::infallible(futures::future::poll_fn(move |cx| {
+        if remaining == 0 {
+            return std::task::Poll::Ready(polls as f64);
+        }
+        remaining -= 1;
+        let waker = cx.waker().clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(
+                READY_AFTER_POLLS_WAKE_DELAY_MS,
+            ));
+            waker.wake();
+        });
+        std::task::Poll::Pending
+    }))
+}
+
+// Awaits C++ futures that resolve synchronously, inside our own poll, by awaiting Rust futures
+// that are already ready.
+fn rust_sum_ready_values() -> RustFutureF64 {
+    RustFutureF64::infallible(async {
+        let inner = async { ffi::folly_ready_value(2.0).await.unwrap() };
+        ffi::folly_ready_value(1.0).await.unwrap() + inner.await
     })
 }
 
@@ -292,75 +788,2356 @@ fn test_streams_throwing_exceptions() {
     );
 }
 
+// Test that a C++ stream that fails partway yields its items, then one error, then ends.
 #[test]
-fn test_dropping_coroutines() {
-    // Make sure that coroutines get parented to the reaper so that destructors are called.
-    let _ = ffi::folly_drop_coroutine_wait();
-    drop(executor::block_on(ffi::folly_drop_coroutine_signal()));
+fn test_stream_items_then_error() {
+    let mut stream = ffi::folly_two_then_fail();
+    assert_eq!(executor::block_on(stream.next()).unwrap().unwrap(), 1.0);
+    assert_eq!(executor::block_on(stream.next()).unwrap().unwrap(), 2.0);
+    match executor::block_on(stream.next()) {
+        Some(Err(err)) => assert_eq!(err.what(), "kersplat"),
+        _ => panic!("expected an error"),
+    }
+    assert!(executor::block_on(stream.next()).is_none());
 }
 
-fn main() {
-    // Test Rust calling C++ async functions, both synchronously and via a scheduler.
-    for fun in &[ffi::folly_dot_product_coro, ffi::folly_dot_product_futures] {
-        let future = fun();
-        println!("{}", executor::block_on(future).unwrap());
-        let future = fun();
-        println!(
-            "{}",
-            executor::block_on(THREAD_POOL.spawn_with_handle(future).unwrap()).unwrap()
+// Test C++ sending stream items to Rust in batches.
+#[test]
+fn test_stream_batches() {
+    for &(count, batch_size) in &[(1000, 64), (1000, 1000), (10, 1), (0, 16)] {
+        let items = executor::block_on(
+            ffi::folly_count_batched(count, batch_size)
+                .map(Result::unwrap)
+                .collect::<Vec<f64>>(),
         );
+        assert_eq!(items, (0..count).map(|i| i as f64).collect::<Vec<f64>>());
     }
-    let future = ffi::folly_get_namespaced_string();
-    println!("{:?}", executor::block_on(future).unwrap());
+}
 
-    // Test C++ calling Rust async functions.
-    ffi::folly_call_rust_hello();
-    ffi::folly_call_rust_dot_product();
-    ffi::folly_schedule_rust_dot_product();
+// Test awaiting only the last item of a stream.
+#[test]
+fn test_stream_last() {
+    assert_eq!(
+        executor::block_on(ffi::folly_count(3).last()).unwrap(),
+        Some(2.0)
+    );
+    assert_eq!(
+        executor::block_on(ffi::folly_count(0).last()).unwrap(),
+        None
+    );
+    let error = executor::block_on(ffi::folly_two_then_fail().last()).unwrap_err();
+    assert_eq!(error.what(), "kersplat");
+}
 
-    // Test exceptions being thrown by C++ async functions.
-    let future = ffi::folly_not_product();
-    match executor::block_on(future) {
-        Ok(_) => panic!("shouldn't have succeeded!"),
-        Err(err) => println!("{}", err.what()),
+// Test splitting a stream into its items and how it ended.
+#[test]
+fn test_stream_split_error() {
+    let (values, completion) = ffi::folly_two_then_fail().split_error();
+    assert_eq!(
+        executor::block_on(values.collect::<Vec<f64>>()),
+        vec![1.0, 2.0]
+    );
+    assert_eq!(
+        executor::block_on(completion).unwrap_err().what(),
+        "kersplat"
+    );
+
+    let (values, completion) = ffi::folly_count(3).split_error();
+    assert_eq!(
+        executor::block_on(values.collect::<Vec<f64>>()),
+        vec![0.0, 1.0, 2.0]
+    );
+    executor::block_on(completion).unwrap();
+
+    // Giving up on the items fails the completion future.
+    let (values, completion) = ffi::folly_count(3).split_error();
+    drop(values);
+    assert!(executor::block_on(completion).is_err());
+}
+
+// Test collecting the items that a slow stream produces before a deadline.
+#[test]
+fn test_collect_until() {
+    use std::time::Duration;
+
+    let start = Instant::now();
+    let stream = ffi::folly_count_slowly(1000, 20);
+    let items = executor::block_on(stream.collect_until(Duration::from_millis(200)));
+    assert!(start.elapsed() < Duration::from_secs(5));
+    assert!(!items.is_empty() && items.len() < 1000);
+    let expected: Vec<f64> = (0..items.len()).map(|i| i as f64).collect();
+    assert_eq!(items, expected);
+
+    // A stream that ends before the deadline is collected in full.
+    let items = executor::block_on(ffi::folly_count(3).collect_until(Duration::from_secs(10)));
+    assert_eq!(items, vec![0.0, 1.0, 2.0]);
+}
+
+// Test that taking the first few items of an infinite stream cancels the producer right after
+// the last one.
+#[test]
+fn test_take_cancels_producer() {
+    let mut stream = ffi::folly_count_forever().take(3);
+    for i in 0..3 {
+        assert_eq!(
+            executor::block_on(stream.next()).unwrap().unwrap(),
+            i as f64
+        );
     }
+    // The producer has been destroyed without our polling the stream again.
+    assert_eq!(ffi::folly_live_count_forever(), 0);
+    assert!(executor::block_on(stream.next()).is_none());
 
-    // Test errors being thrown by Rust async functions.
-    ffi::folly_call_rust_not_product();
+    // Taking nothing cancels the producer immediately.
+    drop(ffi::folly_count_forever().take(0));
+    assert_eq!(ffi::folly_live_count_forever(), 0);
+}
 
-    // Test yielding across the boundary repeatedly.
-    let future = ffi::folly_ping_pong(0);
-    println!("{}", executor::block_on(future).unwrap());
+// Test that cancelling a stream frees the items that were produced but never consumed.
+#[test]
+fn test_cancelling_streams() {
+    // One item is buffered in the channel, one is waiting to be sent, and the rest are still held
+    // by the C++ coroutine.
+    drop(ffi::folly_drop_counted_stream(5));
+    assert_eq!(ffi::folly_live_drop_counted(), 0);
 
-    // Test returning void.
-    let future = ffi::folly_complete();
-    executor::block_on(future).unwrap();
+    // Consume some items, then cancel.
+    let mut stream = ffi::folly_drop_counted_stream(5);
+    drop(executor::block_on(stream.next()).unwrap().unwrap());
+    drop(executor::block_on(stream.next()).unwrap().unwrap());
+    drop(stream);
+    assert_eq!(ffi::folly_live_drop_counted(), 0);
+}
 
-    // Test dropping futures.
-    ffi::folly_send_to_dropped_future();
-    ffi::folly_send_to_dropped_future_go();
+// Test driving a C++ stream through Rust stream combinators from a hand-written executor that
+// supplies its own waker.
+#[test]
+fn test_stream_combinators_with_custom_executor() {
+    use futures::future;
+    use futures::task::ArcWake;
+    use std::pin::Pin;
+    use std::sync::Arc;
+    use std::task::{Context, Poll};
+    use std::thread::{self, Thread};
 
-    // Test Rust calling C++ streams.
-    let vector = executor::block_on(
-        ffi::folly_fizzbuzz()
-            .map(|result| result.unwrap())
-            .collect::<Vec<String>>(),
+    struct ParkingWaker {
+        thread: Thread,
+    }
+
+    impl ArcWake for ParkingWaker {
+        fn wake_by_ref(arc_self: &Arc<Self>) {
+            arc_self.thread.unpark();
+        }
+    }
+
+    // Bridged streams are `Unpin`, so combinators can be applied and polled without pinning.
+    fn check_unpin<T: Unpin>(_: &T) {}
+    let stream = ffi::folly_count(10);
+    check_unpin(&stream);
+
+    let mut items = stream
+        .map(Result::unwrap)
+        .filter(|item| future::ready(item % 2.0 == 0.0))
+        .map(|item| item * 10.0)
+        .collect::<Vec<f64>>();
+    let waker = futures::task::waker(Arc::new(ParkingWaker {
+        thread: thread::current(),
+    }));
+    let items = loop {
+        match Pin::new(&mut items).poll(&mut Context::from_waker(&waker)) {
+            Poll::Ready(items) => break items,
+            Poll::Pending => thread::park(),
+        }
+    };
+    assert_eq!(items, vec![0.0, 20.0, 40.0, 60.0, 80.0]);
+
+    // Items can also be pulled one at a time with `poll_next`.
+    let mut stream = ffi::folly_count(1);
+    let mut context = Context::from_waker(&waker);
+    let item = loop {
+        match Pin::new(&mut stream).poll_next(&mut context) {
+            Poll::Ready(item) => break item,
+            Poll::Pending => thread::park(),
+        }
+    };
+    assert_eq!(item.unwrap().unwrap(), 0.0);
+}
+
+// Test merging several C++ streams into one.
+#[test]
+fn test_merge_streams() {
+    use cxx_async::stream;
+
+    let streams = vec![ffi::folly_count(3), ffi::folly_count(3)];
+    let items: Vec<f64> = executor::block_on(stream::merge(streams).try_collect()).unwrap();
+    // Items from the two sources are interleaved rather than concatenated.
+    assert_ne!(items, vec![0.0, 1.0, 2.0, 0.0, 1.0, 2.0]);
+    let mut sorted = items.clone();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    assert_eq!(sorted, vec![0.0, 0.0, 1.0, 1.0, 2.0, 2.0]);
+
+    // The first error ends the merged stream.
+    let streams = vec![ffi::folly_two_then_fail(), ffi::folly_count(1000)];
+    let items: Vec<_> = executor::block_on(stream::merge(streams).collect());
+    assert!(items.len() < 1000);
+    assert_eq!(items.iter().filter(|item| item.is_err()).count(), 1);
+    assert_eq!(
+        items.last().unwrap().as_ref().unwrap_err().what(),
+        "kersplat"
     );
-    println!("{}", vector.join(", "));
-    let vector = executor::block_on(
-        ffi::folly_indirect_fizzbuzz()
-            .map(|result| result.unwrap())
-            .collect::<Vec<String>>(),
+}
+
+// Test that dropping a C++ future that loses a race cancels the C++ coroutine.
+#[test]
+fn test_cancelling_cpp_futures() {
+    use futures::future::{self, Either};
+
+    let race = future::select(ffi::folly_cancellable(), future::ready(1.0));
+    match executor::block_on(race) {
+        Either::Left(_) => panic!("the C++ future should never finish by itself"),
+        Either::Right((value, cpp_future)) => {
+            assert_eq!(value, 1.0);
+            drop(cpp_future);
+        }
+    }
+    // This returns once the C++ coroutine has been cancelled and has cleaned up.
+    ffi::folly_wait_for_cancellation();
+}
+
+// Test that many outstanding futures from the same C++ functions, created and polled on different
+// threads at once, each resolve to their own result.
+#[test]
+fn test_concurrent_futures_from_same_function() {
+    const COUNT: usize = 64;
+
+    let handles: Vec<_> = (0..COUNT)
+        .map(|i| {
+            // Create half of the futures here and half on the pool threads.
+            let eager = (i % 2 == 0).then(|| ffi::folly_scaled_dot_product(i as f64));
+            THREAD_POOL
+                .spawn_with_handle(async move {
+                    let scaled = match eager {
+                        Some(future) => future.await.unwrap(),
+                        None => ffi::folly_scaled_dot_product(i as f64).await.unwrap(),
+                    };
+                    let ready = ffi::folly_ready_value(i as f64).await.unwrap();
+                    (scaled, ready)
+                })
+                .unwrap()
+        })
+        .collect();
+    let results = executor::block_on(futures::future::join_all(handles));
+    for (i, (scaled, ready)) in results.into_iter().enumerate() {
+        assert_eq!(scaled, i as f64 * 75719554055754070000000.0);
+        assert_eq!(ready, i as f64);
+    }
+}
+
+// Test moving an enum with data across the bridge in both directions.
+#[test]
+fn test_enum_payloads() {
+    for which in 0..3 {
+        assert_eq!(
+            executor::block_on(ffi::folly_reply(which)).unwrap(),
+            Reply::example(which)
+        );
+        assert_eq!(
+            executor::block_on(ffi::folly_forward_reply(which)).unwrap(),
+            Reply::example(which)
+        );
+    }
+}
+
+// Test moving a shared struct with owning fields across the bridge in both directions.
+#[test]
+fn test_shared_struct_payloads() {
+    assert_eq!(
+        executor::block_on(ffi::folly_record()).unwrap(),
+        Record::example()
+    );
+    assert_eq!(
+        executor::block_on(ffi::folly_forward_record()).unwrap(),
+        Record::example()
     );
-    println!("{}", vector.join(", "));
 
-    // Test Rust calling C++ streams that throw exceptions partway through.
-    let vector = executor::block_on(
-        ffi::folly_not_fizzbuzz()
-            .map(|result| format!("{:?}", result))
-            .collect::<Vec<String>>(),
+    // A completed future that's never consumed drops its value along with it.
+    drop(ffi::folly_record());
+}
+
+// Test that a payload that `cxx` can't share survives being serialized, decoded and re-encoded by
+// C++, and decoded again.
+#[test]
+fn test_serialized_payload() {
+    let tagged = Tagged {
+        tag: 41,
+        text: "payload".to_owned(),
+    };
+    let future = ffi::folly_bump_tagged(RustFutureTagged::from_payload(async { Ok(tagged) }));
+    assert_eq!(
+        executor::block_on(future.into_payload()).unwrap(),
+        Tagged {
+            tag: 42,
+            text: "payload!".to_owned(),
+        }
     );
-    println!("{}", vector.join(", "));
+
+    // Bytes that C++ rejects, and bytes that Rust can't decode, both fail the future.
+    let future = ffi::folly_bump_tagged(RustFutureTagged::infallible(async { vec![1] }));
+    let error = executor::block_on(future.into_payload()).unwrap_err();
+    assert_eq!(error.what(), "truncated tag");
+    let future = RustFutureTagged::infallible(async { vec![1, 2] });
+    assert!(executor::block_on(future.into_payload()).is_err());
+}
+
+// Test inspecting the result of a completed future without consuming it.
+#[test]
+fn test_try_peek() {
+    let mut future = ffi::folly_answer();
+    assert_eq!(future.try_peek().unwrap().unwrap(), &42);
+    assert_eq!(future.try_peek().unwrap().unwrap(), &42);
+    assert_eq!(executor::block_on(future).unwrap(), 42);
+
+    let mut future = ffi::folly_not_product();
+    assert_eq!(future.try_peek().unwrap().unwrap_err().what(), "kaboom");
+    assert_eq!(executor::block_on(future).unwrap_err().what(), "kaboom");
+
+    let mut future = RustFutureF64::infallible(futures::future::pending());
+    assert!(future.try_peek().is_none());
+}
+
+// Test that an await site attached by C++ shows up in the error that a Rust future fails with.
+#[test]
+fn test_await_site() {
+    let error = executor::block_on(ffi::folly_not_product_at_await_site()).unwrap_err();
+    assert_eq!(error.what(), "folly_not_product_at_await_site: kapow");
+}
+
+// Test that C++ awaiting a blocking Rust computation doesn't block the awaiting thread.
+#[test]
+fn test_from_blocking() {
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use std::thread;
+    use std::time::Duration;
+
+    let start = Instant::now();
+    let mut future = ffi::folly_slow_square(3.0);
+    let waker = futures::task::noop_waker();
+    let mut ticks = 0;
+    let result = loop {
+        match Pin::new(&mut future).poll(&mut Context::from_waker(&waker)) {
+            Poll::Ready(result) => break result,
+            Poll::Pending => {
+                ticks += 1;
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+    };
+    assert_eq!(result.unwrap(), 9.0);
+    assert!(start.elapsed() >= Duration::from_millis(SLOW_SQUARE_DURATION_MS));
+    // The thread kept making progress while the computation ran.
+    assert!(ticks >= 10);
+}
+
+#[test]
+fn test_dropping_coroutines() {
+    // Make sure that coroutines get parented to the reaper so that destructors are called.
+    let _ = ffi::folly_drop_coroutine_wait();
+    drop(executor::block_on(ffi::folly_drop_coroutine_signal()));
+}
+
+// Test collapsing a future that resolves to another future.
+#[test]
+fn test_flatten() {
+    let future = RustFutureF64::flatten(ffi::folly_nested_dot_product());
+    assert_eq!(
+        executor::block_on(future).unwrap(),
+        75719554055754070000000.0
+    );
+
+    // Errors at either level propagate.
+    let future = RustFutureF64::flatten(ffi::folly_nested_not_product());
+    assert_eq!(executor::block_on(future).unwrap_err().what(), "kaboom");
+    let future = RustFutureF64::flatten(ffi::folly_not_nested_product());
+    assert_eq!(executor::block_on(future).unwrap_err().what(), "kaboom");
+}
+
+// Runs `f` and returns the log messages that it logged on this thread, for testing the features
+// that log.
+#[cfg(all(test, any(feature = "trace-boundary", feature = "pending-watchdog")))]
+fn capture_log_messages(f: impl FnOnce()) -> Vec<String> {
+    use log::{LevelFilter, Log, Metadata, Record};
+    use std::sync::Mutex;
+    use std::thread::{self, ThreadId};
+
+    static MESSAGES: Mutex<Vec<(ThreadId, String)>> = Mutex::new(vec![]);
+
+    struct CapturingLogger;
+
+    impl Log for CapturingLogger {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+        fn log(&self, record: &Record) {
+            let message = (thread::current().id(), record.args().to_string());
+            MESSAGES.lock().unwrap().push(message);
+        }
+        fn flush(&self) {}
+    }
+
+    drop(log::set_logger(&CapturingLogger));
+    log::set_max_level(LevelFilter::Trace);
+
+    // Other tests may be running concurrently, so only look at this thread's messages.
+    let thread = thread::current().id();
+    MESSAGES
+        .lock()
+        .unwrap()
+        .retain(|(other, _)| *other != thread);
+    f();
+    MESSAGES
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(other, _)| *other == thread)
+        .map(|(_, message)| message.clone())
+        .collect()
+}
+
+// Test that boundary crossings are logged when the `trace-boundary` feature is enabled.
+#[cfg(feature = "trace-boundary")]
+#[test]
+fn test_trace_boundary() {
+    let messages = capture_log_messages(|| {
+        ffi::folly_call_rust_hello();
+    });
+    let (id, _) = messages[0].split_once(": ").unwrap();
+    assert!(id.starts_with("future "));
+    assert_eq!(
+        messages,
+        [
+            format!("{}: poll called", id),
+            format!("{}: returned Complete", id),
+            format!("{}: dropped", id),
+        ]
+    );
+}
+
+// Test that a future polled over and over without being woken is reported when the
+// `pending-watchdog` feature is enabled.
+#[cfg(feature = "pending-watchdog")]
+#[test]
+fn test_pending_watchdog() {
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    // Stands in for a poller whose waker is mis-wired, so that it polls without waiting. The first
+    // poll doesn't count, because nothing could have woken the future before it.
+    let spin = |future: &mut RustFutureF64| {
+        let waker = futures::task::noop_waker();
+        for _ in 0..=cxx_async::watchdog::DEFAULT_POLL_THRESHOLD {
+            assert!(Pin::new(&mut *future)
+                .poll(&mut Context::from_waker(&waker))
+                .is_pending());
+        }
+    };
+
+    let mut future = RustFutureF64::builder()
+        .name("spinner")
+        .build(futures::future::pending());
+    let messages = capture_log_messages(|| spin(&mut future));
+    assert_eq!(messages.len(), 1);
+    assert!(messages[0].starts_with("Future `spinner` returned Pending to 1000 consecutive polls"));
+
+    // A future that wakes itself every time is yielding, not stuck.
+    let mut future = RustFutureF64::fallible(futures::future::poll_fn(|cx| {
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }));
+    assert!(capture_log_messages(|| spin(&mut future)).is_empty());
+}
+
+// Test that polls of Rust futures from C++ are recorded when the `poll-metrics` feature is enabled.
+#[cfg(feature = "poll-metrics")]
+#[test]
+fn test_poll_metrics() {
+    use cxx_async::metrics;
+
+    // Other tests may be polling the same future type concurrently, so only check for growth.
+    let before = metrics::poll_histogram::<RustFutureF64>().count();
+    for _ in 0..3 {
+        assert_eq!(
+            ffi::folly_call_rust_dot_product(),
+            75719554055754070000000.0
+        );
+    }
+    let after = metrics::poll_histogram::<RustFutureF64>();
+    assert!(after.count() >= before + 3);
+    assert_eq!(after.buckets().len(), metrics::BUCKET_COUNT);
+}
+
+// Test C++ futures that resolve synchronously while a Rust future is being polled.
+#[test]
+fn test_synchronously_ready_nested_await() {
+    assert_eq!(
+        executor::block_on(ffi::folly_ready_value(3.0)).unwrap(),
+        3.0
+    );
+    assert_eq!(executor::block_on(rust_sum_ready_values()).unwrap(), 3.0);
+    assert_eq!(ffi::folly_call_rust_sum_ready_values(), 3.0);
+}
+
+// Test converting a Rust future into the native folly future type.
+#[test]
+fn test_rust_future_as_native_future() {
+    assert_eq!(
+        ffi::folly_call_rust_dot_product_natively(),
+        75719554055754070000000.0
+    );
+}
+
+// Test that single-threaded futures work on the thread that created them.
+#[test]
+fn test_local_future() {
+    let value = std::rc::Rc::new(1.0);
+    let future = unsafe { RustFutureF64::infallible_local(async move { *value }) };
+    assert_eq!(executor::block_on(future).unwrap(), 1.0);
+}
+
+// Test that polling a single-threaded future on another thread fails loudly in debug builds.
+#[cfg(debug_assertions)]
+#[test]
+fn test_local_future_polled_on_wrong_thread() {
+    let value = std::rc::Rc::new(1.0);
+    let future = unsafe { RustFutureF64::infallible_local(async move { *value }) };
+    let error = std::thread::spawn(move || executor::block_on(future))
+        .join()
+        .unwrap_err();
+    let message = error.downcast_ref::<String>().unwrap();
+    assert!(message.contains("RustFutureF64"), "{}", message);
+    assert!(message.contains("single-threaded"), "{}", message);
+}
+
+// Test that a single-threaded future checks that it's dropped on the thread that created it.
+#[test]
+fn test_local_future_dropped_on_wrong_thread() {
+    let value = std::rc::Rc::new(1.0);
+    let future = unsafe { RustFutureF64::infallible_local(async move { *value }) };
+    let error = std::thread::spawn(move || drop(future)).join().unwrap_err();
+    let message = error.downcast_ref::<String>().unwrap();
+    assert!(message.contains("RustFutureF64"), "{}", message);
+    assert!(message.contains("dropped on thread"), "{}", message);
+}
+
+// Test aborting a future from another thread.
+#[test]
+fn test_abortable_future() {
+    use futures::future;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    struct SetOnDrop(Arc<AtomicBool>);
+    impl Drop for SetOnDrop {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    let dropped = Arc::new(AtomicBool::new(false));
+    let guard = SetOnDrop(dropped.clone());
+    let (future, handle) = RustFutureF64::infallible_abortable(async move {
+        let _guard = guard;
+        future::pending::<f64>().await
+    });
+    let aborter = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(10));
+        handle.abort();
+    });
+    assert_eq!(
+        executor::block_on(future).unwrap_err().what(),
+        "future aborted"
+    );
+    assert!(dropped.load(Ordering::SeqCst));
+    aborter.join().unwrap();
+}
+
+// Test that cleanup closures run exactly once on completion, on error, and on drop.
+#[test]
+fn test_finally() {
+    use futures::{future, poll};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    let counter = Arc::new(AtomicUsize::new(0));
+    let cleanup = {
+        let counter = counter.clone();
+        move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        }
+    };
+
+    let future = rust_dot_product().finally(cleanup.clone());
+    assert_eq!(
+        executor::block_on(future).unwrap(),
+        75719554055754070000000.0
+    );
+    assert_eq!(counter.load(Ordering::SeqCst), 1);
+
+    let future = ffi::folly_not_product().finally(cleanup.clone());
+    assert_eq!(executor::block_on(future).unwrap_err().what(), "kaboom");
+    assert_eq!(counter.load(Ordering::SeqCst), 2);
+
+    // Poll the future once so that it's in flight, and then have C++ drop it.
+    let mut future = RustFutureF64::infallible(future::pending()).finally(cleanup);
+    executor::block_on(async { assert!(poll!(&mut future).is_pending()) });
+    assert_eq!(counter.load(Ordering::SeqCst), 2);
+    ffi::folly_drop_future(future);
+    assert_eq!(counter.load(Ordering::SeqCst), 3);
+}
+
+// Test converting a future into another bridged future type.
+#[test]
+fn test_map_into() {
+    let future = ffi::folly_answer().map_into::<RustFutureF64, _>(|i| i as f64 / 2.0);
+    assert_eq!(executor::block_on(future).unwrap(), 21.0);
+
+    let future = ffi::folly_not_product().map_into::<RustFutureString, _>(|x| x.to_string());
+    assert_eq!(executor::block_on(future).unwrap_err().what(), "kaboom");
+}
+
+// Test taking the first successful result of several futures and cancelling the rest.
+#[test]
+fn test_select_ok() {
+    use futures::future;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let sibling = {
+        let cancelled = cancelled.clone();
+        RustFutureF64::infallible(future::pending())
+            .finally(move || cancelled.store(true, Ordering::SeqCst))
+    };
+    let futures = vec![
+        Box::new(ffi::folly_not_product()),
+        Box::new(ffi::folly_not_product()),
+        Box::new(rust_dot_product()),
+        Box::new(sibling),
+    ];
+    assert_eq!(
+        executor::block_on(cxx_async::select_ok(futures)).unwrap(),
+        75719554055754070000000.0
+    );
+    assert!(cancelled.load(Ordering::SeqCst));
+
+    let futures = vec![ffi::folly_not_product(), ffi::folly_not_product()];
+    let errors = executor::block_on(cxx_async::select_ok(futures)).unwrap_err();
+    assert_eq!(errors.len(), 2);
+    assert!(errors.iter().all(|error| error.what() == "kaboom"));
+}
+
+// Test falling back to a default value when a future fails.
+#[test]
+fn test_unwrap_or() {
+    let future = ffi::folly_not_product().unwrap_or(1.0);
+    assert_eq!(executor::block_on(future).unwrap(), 1.0);
+
+    let future = rust_dot_product().unwrap_or(1.0);
+    assert_eq!(
+        executor::block_on(future).unwrap(),
+        75719554055754070000000.0
+    );
+
+    let future = ffi::folly_not_product().unwrap_or_else(|error| error.what().len() as f64);
+    assert_eq!(executor::block_on(future).unwrap(), 6.0);
+}
+
+// Test recovering a Rust-side context value after awaiting a C++ future.
+#[test]
+fn test_with_context() {
+    let request_id: u64 = 1234;
+    let mut future = RustFutureF64::with_context(request_id, ffi::folly_dot_product_coro());
+    assert_eq!(
+        executor::block_on(&mut future).unwrap(),
+        75719554055754070000000.0
+    );
+    assert_eq!(future.take_context::<String>(), None);
+    assert_eq!(future.take_context::<u64>(), Some(1234));
+    assert_eq!(future.take_context::<u64>(), None);
+}
+
+// Test the poll bookkeeping that bridged futures keep for debugging.
+#[test]
+fn test_poll_count() {
+    use cxx_async::CxxAsyncPollStatus;
+    use futures::{future, poll};
+
+    let mut future = RustFutureF64::infallible(future::pending());
+    assert_eq!(future.poll_count(), 0);
+    assert_eq!(future.last_status(), None);
+    executor::block_on(async {
+        for _ in 0..3 {
+            assert!(poll!(&mut future).is_pending());
+        }
+    });
+    assert_eq!(future.poll_count(), 3);
+    assert_eq!(future.last_status(), Some(CxxAsyncPollStatus::Pending));
+
+    let mut future = ffi::folly_dot_product_coro();
+    executor::block_on(&mut future).unwrap();
+    assert!(future.poll_count() >= 1);
+    assert_eq!(future.last_status(), Some(CxxAsyncPollStatus::Complete));
+
+    let mut future = ffi::folly_not_product();
+    executor::block_on(&mut future).unwrap_err();
+    assert_eq!(future.last_status(), Some(CxxAsyncPollStatus::Error));
+}
+
+// Test running a bounded number of C++ coroutines at once.
+#[test]
+fn test_buffered() {
+    let inputs: Vec<f64> = (0..100).map(|i| i as f64).collect();
+    let results = cxx_async::buffered(inputs.clone(), 8, ffi::folly_ready_value);
+    let results: Vec<f64> = executor::block_on(results.try_collect()).unwrap();
+    assert_eq!(results, inputs);
+
+    let results = cxx_async::buffered(0..100, 8, |i| {
+        if i == 50 {
+            ffi::folly_not_product()
+        } else {
+            ffi::folly_ready_value(i as f64)
+        }
+    });
+    let error = executor::block_on(results.try_collect::<Vec<f64>>()).unwrap_err();
+    assert_eq!(error.what(), "kaboom");
+}
+
+// Test finishing a future from a nested helper, with a value and with an exception.
+#[test]
+fn test_early_exit() {
+    use cxx_async::{complete_early, fail_early, CxxAsyncExit};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    fn check(x: f64) -> Result<(), CxxAsyncExit<f64>> {
+        if x == 0.0 {
+            complete_early(0.0)
+        } else if x < 0.0 {
+            fail_early(format!("negative: {}", x))
+        } else {
+            Ok(())
+        }
+    }
+
+    let body = |x: f64, finished: Arc<AtomicBool>| async move {
+        check(x)?;
+        finished.store(true, Ordering::SeqCst);
+        Ok(x.sqrt())
+    };
+    for (x, expected, finishes) in [
+        (4.0, Ok(2.0), true),
+        (0.0, Ok(0.0), false),
+        (-1.0, Err(()), false),
+    ] {
+        let finished = Arc::new(AtomicBool::new(false));
+        let future = RustFutureF64::fallible_with_early_exit(body(x, finished.clone()));
+        let result = executor::block_on(ffi::folly_rethrow(future));
+        match expected {
+            Ok(expected) => assert_eq!(result.unwrap(), expected),
+            Err(()) => assert_eq!(result.unwrap_err().what(), "negative: -1"),
+        }
+        // The rest of the body doesn't run after an early exit.
+        assert_eq!(finished.load(Ordering::SeqCst), finishes);
+    }
+}
+
+// Test that waking a C++ coroutine that awaited a Rust future, after it has been resumed, does
+// nothing instead of resuming it again.
+#[test]
+fn test_wake_after_resume() {
+    assert_eq!(ffi::folly_call_rust_stash_waker(), 1.0);
+    let waker = STASHED_WAKER.lock().unwrap().take().unwrap();
+    // The coroutine has run to completion, so none of these may touch it.
+    let clone = waker.clone();
+    waker.wake_by_ref();
+    clone.wake();
+    waker.wake();
+}
+
+// Test that C++ can spin on a Rust future that's almost ready instead of suspending.
+#[test]
+fn test_await_spin() {
+    use std::time::Duration;
+
+    // With enough spins, the future finishes without anything having to wake it.
+    let start = Instant::now();
+    assert_eq!(
+        executor::block_on(ffi::folly_spin_on_rust(3, 4)).unwrap(),
+        3.0
+    );
+    assert!(start.elapsed() < Duration::from_millis(READY_AFTER_POLLS_WAKE_DELAY_MS));
+
+    // Otherwise, the coroutine suspends and is woken as usual.
+    for max_spins in [0, 2] {
+        let start = Instant::now();
+        assert_eq!(
+            executor::block_on(ffi::folly_spin_on_rust(3, max_spins)).unwrap(),
+            3.0
+        );
+        assert!(start.elapsed() >= Duration::from_millis(READY_AFTER_POLLS_WAKE_DELAY_MS));
+    }
+}
+
+// Test that writes that C++ makes on another thread before completing a future are visible to Rust
+// once the future resolves, without any other synchronization. Run this under ThreadSanitizer to
+// check for races as well.
+#[test]
+fn test_completion_publishes_prior_writes() {
+    for value in 1..=1000 {
+        let result = executor::block_on(ffi::folly_publish_from_thread_pool(value));
+        assert_eq!(result.unwrap(), value as f64);
+        assert_eq!(ffi::folly_published(), value);
+    }
+}
+
+// Test that the messages of huge C++ exceptions are truncated.
+#[test]
+fn test_exception_message_cap() {
+    use cxx_async::DEFAULT_MAX_EXCEPTION_MESSAGE_LEN;
+
+    let check = |cap: usize| {
+        let error = executor::block_on(ffi::folly_throw_long_message(1 << 20)).unwrap_err();
+        let (message, note) = error.what().split_at(cap);
+        assert!(message.bytes().all(|byte| byte == b'x'));
+        assert_eq!(note, "... (truncated from 1048576 bytes)");
+    };
+    check(DEFAULT_MAX_EXCEPTION_MESSAGE_LEN);
+
+    // Other tests only throw short messages, which this cap leaves intact.
+    cxx_async::set_max_exception_message_len(4096);
+    check(4096);
+    let error = executor::block_on(ffi::folly_throw_long_message(4096)).unwrap_err();
+    assert_eq!(error.what().len(), 4096);
+    cxx_async::set_max_exception_message_len(DEFAULT_MAX_EXCEPTION_MESSAGE_LEN);
+}
+
+// Test that a future built from a closure doesn't call the closure until it's first polled.
+#[test]
+fn test_from_fn_is_lazy() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    let calls = Arc::new(AtomicUsize::new(0));
+    let make_future = |x: f64| {
+        let calls = calls.clone();
+        RustFutureF64::fallible_from_fn(move || {
+            calls.fetch_add(1, Ordering::SeqCst);
+            async move {
+                if x < 0.0 {
+                    cxx_async::cxx_async_bail!("negative: {}", x);
+                }
+                Ok(x * 2.0)
+            }
+        })
+    };
+
+    // A future that's never polled never calls its closure.
+    drop(make_future(1.0).ignore());
+    assert_eq!(calls.load(Ordering::SeqCst), 0);
+
+    let future = make_future(3.0);
+    assert_eq!(calls.load(Ordering::SeqCst), 0);
+    assert_eq!(executor::block_on(ffi::folly_rethrow(future)).unwrap(), 6.0);
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+    let error = executor::block_on(ffi::folly_rethrow(make_future(-1.0))).unwrap_err();
+    assert_eq!(error.what(), "negative: -1");
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+    let future = RustFutureF64::from_fn(|| async { 1.5 });
+    assert_eq!(executor::block_on(future).unwrap(), 1.5);
+}
+
+// Test completing a group of futures separately from one C++ coroutine.
+#[test]
+fn test_future_group() {
+    let ffi::DivMod {
+        done,
+        quotient,
+        remainder,
+    } = ffi::folly_divmod(17, 5);
+    // Each future can be awaited on its own, in any order.
+    assert_eq!(executor::block_on(remainder).unwrap(), 2);
+    assert_eq!(executor::block_on(quotient).unwrap(), 3);
+    executor::block_on(done).unwrap();
+
+    let ffi::DivMod {
+        done,
+        quotient,
+        remainder,
+    } = ffi::folly_divmod(17, 0);
+    assert_eq!(
+        executor::block_on(quotient).unwrap_err().what(),
+        "division by zero"
+    );
+    // The coroutine doesn't complete the remainder at all in this case, so it fails when the
+    // coroutine finishes.
+    executor::block_on(done).unwrap();
+    assert_eq!(
+        executor::block_on(remainder).unwrap_err().what(),
+        "The out-parameter was destroyed before it was completed"
+    );
+}
+
+// Test that blocking closures and other spawned work run on the installed default executor.
+#[test]
+fn test_default_executor() {
+    install_default_executor();
+    // The default executor can't be replaced or resized once it has been chosen.
+    assert!(!cxx_async::set_default_executor(ThreadPool::new().unwrap()));
+    assert!(!cxx_async::blocking::set_blocking_pool_size(1));
+
+    let thread_name = || std::thread::current().name().unwrap_or_default().to_owned();
+    let name = executor::block_on(RustFutureString::from_blocking(thread_name)).unwrap();
+    assert!(name.starts_with("default-pool-"), "{}", name);
+    let name = executor::block_on(
+        cxx_async::default_executor()
+            .spawn_with_handle(async move { thread_name() })
+            .unwrap(),
+    );
+    assert!(name.starts_with("default-pool-"), "{}", name);
+}
+
+// Test that already-completed futures resolve on their first poll, even when awaited by C++.
+#[test]
+fn test_ready_and_failed() {
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    let waker = futures::task::noop_waker();
+    let mut cx = Context::from_waker(&waker);
+
+    let mut future = RustFutureF64::ready(3.0);
+    assert!(matches!(
+        Pin::new(&mut future).poll(&mut cx),
+        Poll::Ready(Ok(3.0))
+    ));
+    let mut future = RustFutureF64::failed("kapow");
+    match Pin::new(&mut future).poll(&mut cx) {
+        Poll::Ready(Err(error)) => assert_eq!(error.what(), "kapow"),
+        _ => panic!("expected the failed future to resolve immediately"),
+    }
+
+    // The C++ coroutine that awaits each one doesn't need to suspend.
+    let mut future = ffi::folly_rethrow(RustFutureF64::ready(4.0));
+    assert!(matches!(
+        Pin::new(&mut future).poll(&mut cx),
+        Poll::Ready(Ok(4.0))
+    ));
+    let mut future = ffi::folly_rethrow(RustFutureF64::failed("kaboom"));
+    match Pin::new(&mut future).poll(&mut cx) {
+        Poll::Ready(Err(error)) => assert_eq!(error.what(), "kaboom"),
+        _ => panic!("expected the rethrown failure to resolve immediately"),
+    }
+}
+
+// Test that task-local context survives a round trip through C++ and a hop to another thread.
+#[test]
+fn test_task_locals() {
+    install_default_executor();
+    REQUEST_ID.with(|id| id.set(42));
+    let future = RustFutureF64::with_task_locals::<RequestId, _>(async {
+        // Created while the request ID is established, so it captures the ID in turn.
+        ffi::folly_rethrow(rust_read_request_id()).await.unwrap()
+    });
+    REQUEST_ID.with(|id| id.set(0));
+
+    // Poll the future from a thread that has never seen the request ID.
+    let handle = THREAD_POOL.spawn_with_handle(future).unwrap();
+    assert_eq!(executor::block_on(handle).unwrap(), 42.0);
+}
+
+// Test awaiting a C++ event that another thread sets.
+#[test]
+fn test_event() {
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    let waker = futures::task::noop_waker();
+    let mut cx = Context::from_waker(&waker);
+
+    ffi::folly_event_reset();
+    let mut first = ffi::folly_event_wait();
+    let second = ffi::folly_event_wait();
+    assert!(Pin::new(&mut first).poll(&mut cx).is_pending());
+
+    ffi::folly_event_set_from_thread(50);
+    executor::block_on(first).unwrap();
+    executor::block_on(second).unwrap();
+
+    // Once the event is set, waiting on it completes right away.
+    let mut future = ffi::folly_event_wait();
+    assert!(matches!(
+        Pin::new(&mut future).poll(&mut cx),
+        Poll::Ready(Ok(()))
+    ));
+    ffi::folly_event_reset();
+}
+
+// Test that C++ can tell a cancelled Rust future from a failed one.
+#[test]
+fn test_cancellation_exception() {
+    let (future, handle) = RustFutureF64::infallible_abortable(futures::future::pending());
+    handle.abort();
+    assert_eq!(
+        ffi::folly_describe_failure(future),
+        "cancelled: future aborted"
+    );
+
+    let future = RustFutureF64::builder()
+        .timeout(std::time::Duration::from_millis(10))
+        .build(futures::future::pending());
+    assert_eq!(
+        ffi::folly_describe_failure(future),
+        "cancelled: Future timed out after 10ms"
+    );
+
+    assert_eq!(
+        ffi::folly_describe_failure(RustFutureF64::failed("kapow")),
+        "failed: kapow"
+    );
+    let error = cxx_async::CxxAsyncException::cancelled("stopped".into());
+    assert!(error.is_cancelled());
+    assert_eq!(
+        ffi::folly_describe_failure(RustFutureF64::failed(error)),
+        "cancelled: stopped"
+    );
+}
+
+// Test collecting a Rust stream into a vector that C++ awaits.
+#[test]
+fn test_collect_to_future() {
+    let stream = RustStreamF64::infallible(futures::stream::iter(vec![1.0, 2.0, 3.0]));
+    let future = ffi::folly_reverse_f64_vec(stream.collect_to_future());
+    assert_eq!(executor::block_on(future).unwrap(), vec![3.0, 2.0, 1.0]);
+
+    let stream = RustStreamF64::fallible(futures::stream::iter(vec![
+        Ok(1.0),
+        Err(cxx_async::CxxAsyncException::from("bad item")),
+        Ok(3.0),
+    ]));
+    let future = ffi::folly_reverse_f64_vec(stream.collect_to_future());
+    assert_eq!(executor::block_on(future).unwrap_err().what(), "bad item");
+}
+
+// Test that Rust and C++ agree on how values of each kind are handed over, and that a boxed Rust
+// value survives a round trip through C++.
+#[test]
+fn test_result_repr() {
+    assert_eq!(
+        <f64 as CxxAsyncResultRepr>::KIND,
+        CxxAsyncResultKind::InPlace
+    );
+    assert_eq!(
+        <String as CxxAsyncResultRepr>::KIND,
+        CxxAsyncResultKind::Moved
+    );
+    assert_eq!(
+        <Counter as CxxAsyncResultRepr>::KIND,
+        CxxAsyncResultKind::Boxed
+    );
+    assert!(ffi::folly_result_kinds_agree());
+
+    let future = ffi::folly_bump_counter(rust_counter(41));
+    assert_eq!(executor::block_on(future).unwrap().0, 42);
+}
+
+// Test that a C++ cleanup registered for an await runs exactly once if the await is cancelled, and
+// not at all if the awaited future completes.
+#[test]
+fn test_cleanup_on_cancel() {
+    use std::pin::Pin;
+    use std::task::Context;
+
+    let cleanups = ffi::folly_cleanups();
+    let future = ffi::folly_await_with_cleanup(RustFutureF64::ready(1.5));
+    assert_eq!(executor::block_on(future).unwrap(), 1.5);
+    let future = ffi::folly_await_with_cleanup(RustFutureF64::failed("kapow"));
+    assert!(executor::block_on(future).is_err());
+    assert_eq!(ffi::folly_cleanups(), cleanups);
+
+    let mut future =
+        ffi::folly_await_with_cleanup(RustFutureF64::infallible(futures::future::pending()));
+    let waker = futures::task::noop_waker();
+    assert!(Pin::new(&mut future)
+        .poll(&mut Context::from_waker(&waker))
+        .is_pending());
+    assert_eq!(ffi::folly_cleanups(), cleanups);
+    drop(future);
+    assert_eq!(ffi::folly_cleanups(), cleanups + 1);
+}
+
+// Test that debug builds report a Rust future that's dropped without ever being polled, unless it
+// was marked with `ignore()`.
+#[cfg(debug_assertions)]
+#[test]
+fn test_unawaited_futures() {
+    static REPORTS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+    cxx_async::discard::set_hook(|report| REPORTS.lock().unwrap().push(report.to_owned()));
+
+    drop(
+        RustFutureF64::builder()
+            .name("forgotten")
+            .build(async { 1.0 }),
+    );
+    drop(
+        RustFutureF64::builder()
+            .name("ignored")
+            .build(async { 1.0 })
+            .ignore(),
+    );
+    let future = RustFutureF64::builder()
+        .name("awaited")
+        .build(async { 1.0 });
+    assert_eq!(executor::block_on(future).unwrap(), 1.0);
+
+    let reports = REPORTS.lock().unwrap();
+    assert!(
+        reports.iter().any(|report| report
+            == "RustFutureF64 `forgotten` was dropped without ever being polled; call `ignore()` \
+                on it if that's intended"),
+        "{:?}",
+        reports
+    );
+    assert!(!reports
+        .iter()
+        .any(|report| report.contains("`ignored`") || report.contains("`awaited`")));
+}
+
+// Test converting C++ time points to and from `SystemTime`, including ones before the Unix epoch.
+#[test]
+fn test_time_point_payload() {
+    use std::time::Duration;
+
+    let time = executor::block_on(ffi::folly_time_point(1_700_000_000, 250).into_payload());
+    assert_eq!(
+        time.unwrap(),
+        SystemTime::UNIX_EPOCH + Duration::new(1_700_000_000, 250_000_000)
+    );
+    let time = executor::block_on(ffi::folly_time_point(-2, 500).into_payload());
+    assert_eq!(
+        time.unwrap(),
+        SystemTime::UNIX_EPOCH - Duration::from_millis(1500)
+    );
+
+    let future = ffi::folly_add_second(RustFutureTimePoint::from_payload(async {
+        Ok(SystemTime::UNIX_EPOCH - Duration::from_millis(250))
+    }));
+    assert_eq!(
+        executor::block_on(future.into_payload()).unwrap(),
+        SystemTime::UNIX_EPOCH + Duration::from_millis(750)
+    );
+
+    // Times too far from the epoch for the encoding saturate.
+    let far_future = SystemTime::UNIX_EPOCH + Duration::from_secs(400 * 365 * 24 * 60 * 60);
+    assert_eq!(far_future.encode(), i64::MAX);
+}
+
+// Test that started futures run before anything joins them, so that their results can be collected
+// in any order, and that dropping a started future cancels it.
+#[test]
+fn test_start_and_join() {
+    use futures::channel::oneshot;
+    use futures::future;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    fn wait_for(condition: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !condition() {
+            assert!(Instant::now() < deadline, "timed out");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    // Each future notes that it has begun, then waits to be released. All three begin before any
+    // of them is joined, so they're running concurrently.
+    let begun = Arc::new(AtomicUsize::new(0));
+    let mut releases = vec![];
+    let mut started = vec![];
+    for i in 1..=3 {
+        let (release, released) = oneshot::channel::<()>();
+        let begun = begun.clone();
+        releases.push(release);
+        started.push(
+            RustFutureF64::infallible(async move {
+                begun.fetch_add(1, Ordering::SeqCst);
+                let _ = released.await;
+                i as f64
+            })
+            .start(),
+        );
+    }
+    wait_for(|| begun.load(Ordering::SeqCst) == 3);
+    for release in releases {
+        let _ = release.send(());
+    }
+    let results: Vec<f64> = started
+        .into_iter()
+        .rev()
+        .map(|started| executor::block_on(started.join()).unwrap())
+        .collect();
+    assert_eq!(results, [3.0, 2.0, 1.0]);
+
+    // Starting a future twice runs it once.
+    let runs = Arc::new(AtomicUsize::new(0));
+    let started = {
+        let runs = runs.clone();
+        RustFutureF64::infallible(async move {
+            runs.fetch_add(1, Ordering::SeqCst);
+            4.0
+        })
+        .start()
+        .start()
+    };
+    assert_eq!(executor::block_on(started.join()).unwrap(), 4.0);
+    assert_eq!(runs.load(Ordering::SeqCst), 1);
+
+    // Dropping a started future that was never joined cancels it.
+    struct SetOnDrop(Arc<AtomicBool>);
+    impl Drop for SetOnDrop {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    let dropped = Arc::new(AtomicBool::new(false));
+    let guard = SetOnDrop(dropped.clone());
+    let started = RustFutureF64::infallible(async move {
+        let _guard = guard;
+        future::pending::<f64>().await
+    })
+    .start();
+    drop(started);
+    wait_for(|| dropped.load(Ordering::SeqCst));
+}
+
+// Test that synchronous C++ code can wait for a Rust future, and that it refuses to while a future
+// is being polled on the same thread.
+#[test]
+fn test_blocking_wait() {
+    use cxx_async::CxxAsyncException;
+
+    assert_eq!(
+        ffi::folly_blocking_wait(rust_slow_square(3.0)).unwrap(),
+        9.0
+    );
+    assert_eq!(
+        ffi::folly_blocking_wait(RustFutureF64::failed("kapow"))
+            .unwrap_err()
+            .what(),
+        "kapow"
+    );
+
+    let future = RustFutureF64::fallible(async {
+        ffi::folly_blocking_wait(RustFutureF64::ready(1.0).ignore())
+            .map_err(|error| CxxAsyncException::new(error.what().into()))
+    });
+    let message = executor::block_on(future).unwrap_err().what().to_owned();
+    assert!(message.contains("could deadlock"), "{}", message);
+}
+
+// Test that tiny values packed alongside the poll status arrive intact, whether or not C++ has to
+// suspend for them.
+#[test]
+fn test_packed_results() {
+    assert!(ffi::folly_packs_results());
+    for (a, b) in [(false, false), (false, true), (true, false), (true, true)] {
+        let future = ffi::folly_both(RustFutureBool::ready(a), RustFutureBool::ready(b));
+        assert_eq!(executor::block_on(future).unwrap(), a && b);
+    }
+    assert_eq!(
+        executor::block_on(ffi::folly_negate(RustFutureI32::ready(-7))).unwrap(),
+        7
+    );
+    assert_eq!(
+        executor::block_on(ffi::folly_negate(RustFutureI32::from_blocking(|| i32::MAX))).unwrap(),
+        -i32::MAX
+    );
+    assert_eq!(
+        executor::block_on(ffi::folly_negate(RustFutureI32::failed("kapow")))
+            .unwrap_err()
+            .what(),
+        "kapow"
+    );
+}
+
+// Test moving the deadline of a future while it runs, so that a slow future that would have timed
+// out finishes, and that a deadline can't be moved once it has passed.
+#[test]
+fn test_reset_timeout() {
+    use std::thread;
+    use std::time::Duration;
+
+    fn slow() -> RustFutureF64 {
+        RustFutureF64::builder()
+            .name("slow")
+            .timeout(Duration::from_millis(50))
+            .build(async { rust_slow_square(3.0).await.unwrap() })
+    }
+
+    assert_eq!(
+        executor::block_on(ffi::folly_rethrow(slow()))
+            .unwrap_err()
+            .what(),
+        "Future `slow` timed out after 50ms"
+    );
+
+    // A heartbeat extends the deadline while C++ awaits the future.
+    let future = slow();
+    let handle = future.timeout_handle().unwrap();
+    let heartbeat = {
+        let handle = handle.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            assert!(handle.reset(Duration::from_secs(10)));
+        })
+    };
+    assert_eq!(executor::block_on(ffi::folly_rethrow(future)).unwrap(), 9.0);
+    heartbeat.join().unwrap();
+    assert!(!handle.reset(Duration::from_secs(10)));
+    assert!(!handle.has_expired());
+
+    // Before the first poll, a reset replaces the timeout.
+    let future = slow();
+    assert!(future.reset_timeout(Duration::from_secs(10)));
+    assert_eq!(executor::block_on(future).unwrap(), 9.0);
+
+    // Once the deadline has passed, it stays passed.
+    let future = RustFutureF64::builder()
+        .timeout(Duration::from_millis(10))
+        .build(futures::future::pending());
+    let handle = future.timeout_handle().unwrap();
+    assert!(executor::block_on(future).unwrap_err().is_cancelled());
+    assert!(handle.has_expired());
+    assert!(!handle.reset(Duration::from_secs(10)));
+
+    // Futures without a timeout have no deadline to move.
+    let future = RustFutureF64::ready(1.0);
+    assert!(future.timeout_handle().is_none());
+    assert!(!future.reset_timeout(Duration::from_secs(10)));
+    assert_eq!(executor::block_on(future).unwrap(), 1.0);
+}
+
+// Test awaiting futures with different output types together, and recovering their values.
+#[test]
+fn test_dyn_futures() {
+    use cxx_async::DynCxxFuture;
+
+    let mut futures: Vec<Box<dyn DynCxxFuture>> = vec![
+        RustFutureF64::from_blocking(|| 1.5).into_dyn(),
+        ffi::folly_ping_pong(3).into_dyn(),
+        RustFutureF64::failed("kapow").into_dyn(),
+    ];
+    let results = executor::block_on(futures::future::join_all(futures.iter_mut()));
+    assert!(results[0].is_ok());
+    assert!(results[1].is_ok());
+    assert_eq!(results[2].as_ref().unwrap_err().what(), "kapow");
+
+    let value = futures[0].take_output().unwrap();
+    assert_eq!(*value.downcast::<f64>().unwrap(), 1.5);
+    let value = futures[1].take_output().unwrap();
+    assert_eq!(*value.downcast::<String>().unwrap(), "ping pong ping pong ");
+    assert!(futures[1].take_output().is_none());
+    assert!(futures[2].take_output().is_none());
+}
+
+// Test that the length check for vectors refuses lengths too long for C++ to address with a clean
+// error, and accepts large vectors that fit.
+#[test]
+fn test_payload_len_limit() {
+    use cxx_async::repr;
+
+    assert!(repr::check_payload_len::<f64>(1 << 20).is_ok());
+    assert!(repr::check_payload_len::<u8>(isize::MAX as usize).is_ok());
+    for error in [
+        repr::check_payload_len::<u8>(isize::MAX as usize + 1).unwrap_err(),
+        repr::check_payload_len::<f64>(isize::MAX as usize / 8 + 1).unwrap_err(),
+        repr::check_payload_len::<f64>(usize::MAX).unwrap_err(),
+    ] {
+        assert!(
+            error.what().starts_with("payload too large"),
+            "{}",
+            error.what()
+        );
+    }
+
+    let bytes = vec![7u8; 1 << 20];
+    assert!(bytes.check_result().is_ok());
+}
+
+// Test that a C++ coroutine can resume on an executor of its choosing after awaiting a Rust
+// future, whether the future completes on another thread, is already finished, or fails.
+#[test]
+fn test_resume_via() {
+    use std::thread;
+    use std::time::Duration;
+
+    let future = RustFutureF64::from_blocking(|| {
+        thread::sleep(Duration::from_millis(20));
+        2.0
+    });
+    assert_eq!(
+        executor::block_on(ffi::folly_add_one_on_loop(future)).unwrap(),
+        3.0
+    );
+    assert_eq!(
+        executor::block_on(ffi::folly_add_one_on_loop(RustFutureF64::ready(2.0))).unwrap(),
+        3.0
+    );
+    match executor::block_on(ffi::folly_add_one_on_loop(RustFutureF64::failed("kapow"))) {
+        Err(error) => assert_eq!(error.what(), "kapow"),
+        Ok(_) => panic!("should have failed"),
+    }
+}
+
+// Test that C++ checks that its types for future values have the layouts that Rust gives them.
+#[test]
+fn test_result_layouts_agree() {
+    assert!(ffi::folly_result_layouts_agree());
+}
+
+// Test that the items still buffered when a stream is dropped are handed to its drain closure.
+#[test]
+fn test_stream_on_drain() {
+    use std::sync::Arc;
+
+    let drained = Arc::new(Mutex::new(vec![]));
+    let record = |drained: &Arc<Mutex<Vec<f64>>>| {
+        let drained = drained.clone();
+        move |item| drained.lock().unwrap().push(item)
+    };
+
+    // The coroutine yields all three items in one batch before Rust ever polls.
+    let stream = ffi::folly_count_batched(3, 3).on_drain(record(&drained));
+    drop(stream);
+    assert_eq!(*drained.lock().unwrap(), vec![0.0, 1.0, 2.0]);
+
+    // Only the items that haven't been received are drained.
+    drained.lock().unwrap().clear();
+    let mut stream = ffi::folly_count_batched(4, 4).on_drain(record(&drained));
+    assert_eq!(executor::block_on(stream.next()).unwrap().unwrap(), 0.0);
+    drop(stream);
+    assert_eq!(*drained.lock().unwrap(), vec![1.0, 2.0, 3.0]);
+
+    // A stream with a Rust body buffers nothing.
+    drained.lock().unwrap().clear();
+    let stream =
+        RustStreamF64::infallible(futures::stream::iter(vec![1.0, 2.0])).on_drain(record(&drained));
+    drop(stream);
+    assert!(drained.lock().unwrap().is_empty());
+}
+
+// Test that a buffer that C++ allocated is freed by its own deleter exactly once, whether Rust
+// receives it or drops the future before receiving it.
+#[test]
+fn test_foreign_buffer() {
+    let freed = ffi::folly_foreign_buffers_freed();
+    let buffer = executor::block_on(ffi::folly_foreign_buffer(4)).unwrap();
+    assert_eq!(&*buffer, &[0, 1, 2, 3]);
+    assert_eq!(ffi::folly_foreign_buffers_freed(), freed);
+    drop(buffer);
+    assert_eq!(ffi::folly_foreign_buffers_freed(), freed + 1);
+
+    // The coroutine has already sent the buffer by the time the future is dropped.
+    drop(ffi::folly_foreign_buffer(4));
+    assert_eq!(ffi::folly_foreign_buffers_freed(), freed + 2);
+}
+
+// Test that a future whose executor shuts down before finishing its work fails as the shutdown
+// policy chooses, instead of hanging, whether the executor drops the work or refuses it.
+#[test]
+fn test_executor_shutdown() {
+    use cxx_async::blocking::{self, ShutdownPolicy, EXECUTOR_SHUTDOWN};
+    use futures::future::FutureObj;
+    use futures::task::{Spawn, SpawnError};
+
+    // Holds on to its tasks without running them until it shuts down, and then drops them.
+    struct HeldExecutor(Mutex<Option<Vec<FutureObj<'static, ()>>>>);
+
+    impl Spawn for HeldExecutor {
+        fn spawn_obj(&self, future: FutureObj<'static, ()>) -> Result<(), SpawnError> {
+            match *self.0.lock().unwrap() {
+                Some(ref mut tasks) => {
+                    tasks.push(future);
+                    Ok(())
+                }
+                None => Err(SpawnError::shutdown()),
+            }
+        }
+    }
+
+    // The C++ coroutine is suspended on the spawned work when the executor shuts down.
+    let executor = HeldExecutor(Mutex::new(Some(vec![])));
+    let future = ffi::folly_rethrow(RustFutureF64::spawn_on(&executor, async { 1.0 }));
+    drop(executor.0.lock().unwrap().take());
+    let error = executor::block_on(future).unwrap_err();
+    assert_eq!(error.what(), EXECUTOR_SHUTDOWN);
+    assert!(!error.is_cancelled());
+
+    // Once shut down, the executor refuses new work.
+    blocking::set_shutdown_policy(ShutdownPolicy::Cancel);
+    let error = executor::block_on(RustFutureF64::spawn_on(&executor, async { 1.0 })).unwrap_err();
+    let description =
+        ffi::folly_describe_failure(RustFutureF64::spawn_on(&executor, async { 1.0 }));
+    blocking::set_shutdown_policy(ShutdownPolicy::Fail);
+    assert_eq!(error.what(), EXECUTOR_SHUTDOWN);
+    assert!(error.is_cancelled());
+    assert_eq!(description, format!("cancelled: {}", EXECUTOR_SHUTDOWN));
+
+    // An executor that stays up runs the work.
+    let future = RustFutureF64::spawn_on(&ThreadPool::new().unwrap(), async { 1.0 });
+    assert_eq!(executor::block_on(ffi::folly_rethrow(future)).unwrap(), 1.0);
+}
+
+// Test that requesting stop through a C++ `std::stop_token` drops the body of a Rust future and
+// cancels it.
+#[test]
+fn test_stop_token() {
+    use futures::{future, poll};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    // Sets its flag when the body holding it is dropped.
+    struct SetOnDrop(Arc<AtomicBool>);
+    impl Drop for SetOnDrop {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    let dropped = Arc::new(AtomicBool::new(false));
+    let guard = SetOnDrop(dropped.clone());
+    let mut future =
+        RustFutureF64::fallible_until_stopped(ffi::folly_stop_requested(), async move {
+            let _guard = guard;
+            future::pending::<()>().await;
+            Ok(1.0)
+        });
+    executor::block_on(async { assert!(poll!(&mut future).is_pending()) });
+    assert!(!dropped.load(Ordering::SeqCst));
+    ffi::folly_request_stop();
+    let error = executor::block_on(future).unwrap_err();
+    assert!(dropped.load(Ordering::SeqCst));
+    assert_eq!(error.what(), "stop requested");
+    assert!(error.is_cancelled());
+
+    // A token that was stopped before the future was first polled stops it right away.
+    let stop = ffi::folly_stop_requested();
+    ffi::folly_request_stop();
+    let future = RustFutureF64::fallible_until_stopped(stop, future::pending());
+    assert_eq!(
+        ffi::folly_describe_failure(future),
+        "cancelled: stop requested"
+    );
+
+    // A body that finishes first isn't interrupted, and dropping the stop future deregisters it.
+    let future =
+        RustFutureF64::fallible_until_stopped(ffi::folly_stop_requested(), async { Ok(2.0) });
+    assert_eq!(executor::block_on(future).unwrap(), 2.0);
+    ffi::folly_request_stop();
+}
+
+// Test that a value whose check fails doesn't reach C++, which sees the error instead, whether the
+// value comes back packed into the poll status or through memory.
+#[test]
+fn test_check_result_fails_future() {
+    let future = ffi::folly_add_guarded(
+        RustFutureGuardedI32::infallible(async { Guarded(1) }),
+        RustFutureGuardedF64::infallible(async { Guarded(0.5) }),
+    );
+    assert_eq!(executor::block_on(future).unwrap(), 1.5);
+
+    for future in [
+        ffi::folly_add_guarded(
+            RustFutureGuardedI32::infallible(async { Guarded(-1) }),
+            RustFutureGuardedF64::infallible(async { Guarded(0.5) }),
+        ),
+        ffi::folly_add_guarded(
+            RustFutureGuardedI32::infallible(async { Guarded(1) }),
+            RustFutureGuardedF64::infallible(async { Guarded(-0.5) }),
+        ),
+    ] {
+        let error = executor::block_on(future).unwrap_err();
+        assert_eq!(error.what(), "refusing a negative value");
+    }
+}
+
+// Test that completing an out-parameter from a C++ lvalue copies it, leaving the original intact.
+#[test]
+fn test_complete_out_param_from_lvalue() {
+    assert_eq!(
+        executor::block_on(ffi::folly_complete_from_lvalue()).unwrap(),
+        "hello, hello"
+    );
+}
+
+// Test that an error with a codec survives being rethrown by C++.
+#[test]
+fn test_error_codec() {
+    use cxx_async::CxxAsyncErrorCodec;
+
+    #[derive(Clone, Debug, PartialEq)]
+    enum LookupError {
+        NotFound(String),
+        PermissionDenied,
+    }
+
+    impl CxxAsyncErrorCodec for LookupError {
+        fn encode(&self) -> String {
+            match self {
+                LookupError::NotFound(key) => format!("not found: {}", key),
+                LookupError::PermissionDenied => "permission denied".to_owned(),
+            }
+        }
+
+        fn decode(what: &str) -> Option<Self> {
+            match what.strip_prefix("not found: ") {
+                Some(key) => Some(LookupError::NotFound(key.to_owned())),
+                None if what == "permission denied" => Some(LookupError::PermissionDenied),
+                None => None,
+            }
+        }
+    }
+
+    for error in [
+        LookupError::NotFound("answer".to_owned()),
+        LookupError::PermissionDenied,
+    ] {
+        let future = RustFutureF64::fallible_with_codec({
+            let error = error.clone();
+            async move { Err(error) }
+        });
+        let exception = executor::block_on(ffi::folly_rethrow(future)).unwrap_err();
+        assert_eq!(exception.downcast(), Some(error));
+    }
+
+    let exception = executor::block_on(ffi::folly_rethrow(ffi::folly_not_product())).unwrap_err();
+    assert_eq!(exception.downcast::<LookupError>(), None);
+}
+
+// Test that concurrent requests for the same key share one C++ computation.
+#[test]
+fn test_future_cache() {
+    use cxx_async::cache::{EvictionPolicy, FutureCache};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    let calls = Arc::new(AtomicUsize::new(0));
+    let factory = || {
+        let calls = calls.clone();
+        move || {
+            calls.fetch_add(1, Ordering::SeqCst);
+            ffi::folly_dot_product_coro()
+        }
+    };
+
+    let cache = FutureCache::new(EvictionPolicy::EvictCompleted);
+    let (a, b) = executor::block_on(async {
+        join!(
+            cache.get_or_compute("dot", factory()),
+            cache.get_or_compute("dot", factory())
+        )
+    });
+    assert_eq!(a.unwrap(), 75719554055754070000000.0);
+    assert_eq!(b.unwrap(), 75719554055754070000000.0);
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+    // The finished computation was evicted, so this starts a new one.
+    executor::block_on(cache.get_or_compute("dot", factory())).unwrap();
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+    // Successful results are memoized, and failures are retried.
+    let cache = FutureCache::new(EvictionPolicy::KeepSuccesses);
+    executor::block_on(cache.get_or_compute("dot", factory())).unwrap();
+    executor::block_on(cache.get_or_compute("dot", factory())).unwrap();
+    assert_eq!(calls.load(Ordering::SeqCst), 3);
+    executor::block_on(cache.get_or_compute("fail", ffi::folly_not_product)).unwrap_err();
+    executor::block_on(cache.get_or_compute("fail", ffi::folly_not_product)).unwrap_err();
+    cache.purge();
+}
+
+// Test completing overlapping requests with responses that arrive out of order.
+#[test]
+fn test_response_router() {
+    let requests = vec![
+        RESPONSE_ROUTER.request(1),
+        RESPONSE_ROUTER.request(2),
+        RESPONSE_ROUTER.request(3),
+    ];
+    assert_eq!(RESPONSE_ROUTER.len(), 3);
+    ffi::folly_route_responses_out_of_order();
+    assert!(RESPONSE_ROUTER.is_empty());
+    let responses = executor::block_on(futures::future::try_join_all(requests)).unwrap();
+    assert_eq!(responses, vec![10.0, 20.0, 30.0]);
+
+    // Dropping a request removes it, so a late response goes nowhere.
+    drop(RESPONSE_ROUTER.request(4));
+    assert!(RESPONSE_ROUTER.is_empty());
+    assert!(!rust_route_response(4, 40.0));
+}
+
+// Test that an exception from C++ can be cloned and shared between threads.
+#[test]
+fn test_cloning_exceptions() {
+    let exception = executor::block_on(ffi::folly_not_product()).unwrap_err();
+    let clone = exception.clone();
+    assert_eq!(clone, exception);
+    assert_eq!(clone.what(), exception.what());
+    let what = std::thread::spawn(move || clone.what().to_owned())
+        .join()
+        .unwrap();
+    assert_eq!(what, exception.what());
+}
+
+// Test that the same Rust future body spawns its subtasks onto the executor that its caller chose.
+#[test]
+fn test_current_executor() {
+    let name = executor::block_on(ffi::folly_subtask_thread_name(0)).unwrap();
+    assert!(name.starts_with("subtask-pool-a-"));
+    let name = executor::block_on(ffi::folly_subtask_thread_name(1)).unwrap();
+    assert!(name.starts_with("subtask-pool-b-"));
+    assert!(cxx_async::current_executor().is_none());
+}
+
+// Test configuring a future with every builder option at once, and with none.
+#[test]
+fn test_future_builder() {
+    use cxx_async::builder::{self, CxxAsyncPriority};
+    use std::thread;
+    use std::time::Duration;
+
+    let future = RustFutureF64::builder()
+        .name("answer")
+        .on(SUBTASK_POOLS[0].clone())
+        .priority(CxxAsyncPriority::High)
+        .timeout(Duration::from_secs(10))
+        .build(async {
+            assert_eq!(builder::current_priority(), CxxAsyncPriority::High);
+            let executor = cxx_async::current_executor().unwrap();
+            let name = executor
+                .spawn_with_handle(async { thread::current().name().unwrap().to_owned() })
+                .unwrap()
+                .await;
+            assert!(name.starts_with("subtask-pool-a-"));
+            42.0
+        });
+    assert!(format!("{:?}", future).contains(r#"name: "answer""#));
+    assert_eq!(
+        executor::block_on(ffi::folly_rethrow(future)).unwrap(),
+        42.0
+    );
+
+    let future = RustFutureF64::builder()
+        .name("stuck")
+        .timeout(Duration::from_millis(50))
+        .build(futures::future::pending());
+    assert_eq!(
+        executor::block_on(ffi::folly_rethrow(future))
+            .unwrap_err()
+            .what(),
+        "Future `stuck` timed out after 50ms"
+    );
+
+    // With no options set, the builder behaves like `infallible()`.
+    let future = RustFutureF64::builder().build(async {
+        assert_eq!(builder::current_priority(), CxxAsyncPriority::Normal);
+        assert!(cxx_async::current_executor().is_none());
+        1.0
+    });
+    assert_eq!(
+        format!("{:?}", future),
+        "RustFutureF64 { last_status: None, poll_count: 0 }"
+    );
+    assert_eq!(executor::block_on(future).unwrap(), 1.0);
+}
+
+// Test that a future's priority reaches its body and the executors that the body spawns onto,
+// and that executors without priorities run the work all the same.
+#[test]
+fn test_with_priority() {
+    use cxx_async::builder::{self, CxxAsyncPriority};
+    use futures::future::FutureObj;
+    use futures::task::{Spawn, SpawnError};
+    use std::sync::Arc;
+
+    // Runs tasks on a thread pool, recording the priority that each was spawned with.
+    struct RecordingExecutor {
+        pool: ThreadPool,
+        priorities: Mutex<Vec<CxxAsyncPriority>>,
+    }
+
+    impl Spawn for RecordingExecutor {
+        fn spawn_obj(&self, future: FutureObj<'static, ()>) -> Result<(), SpawnError> {
+            self.priorities
+                .lock()
+                .unwrap()
+                .push(builder::current_priority());
+            self.pool.spawn_obj(future)
+        }
+    }
+
+    let executor = Arc::new(RecordingExecutor {
+        pool: ThreadPool::new().unwrap(),
+        priorities: Mutex::new(vec![]),
+    });
+    let spawner = executor.clone();
+    let future = RustFutureF64::infallible(async move {
+        assert_eq!(builder::current_priority(), CxxAsyncPriority::High);
+        spawner.spawn_with_handle(async { 1.0 }).unwrap().await + 1.0
+    })
+    .with_priority(CxxAsyncPriority::High);
+    assert_eq!(executor::block_on(ffi::folly_rethrow(future)).unwrap(), 2.0);
+    assert_eq!(
+        *executor.priorities.lock().unwrap(),
+        [CxxAsyncPriority::High]
+    );
+
+    let pool = ThreadPool::new().unwrap();
+    let future = RustFutureF64::infallible(async move {
+        assert_eq!(builder::current_priority(), CxxAsyncPriority::Low);
+        pool.spawn_with_handle(async { 3.0 }).unwrap().await
+    })
+    .with_priority(CxxAsyncPriority::Low);
+    assert_eq!(executor::block_on(future).unwrap(), 3.0);
+    assert_eq!(builder::current_priority(), CxxAsyncPriority::Normal);
+}
+
+// Test driving futures entirely through the plain C interface.
+#[test]
+fn test_c_abi() {
+    use cxx_async::c_abi;
+
+    let handle = c_abi::into_handle(rust_dot_product());
+    assert_eq!(
+        ffi::folly_poll_c_abi_future(handle),
+        75719554055754070000000.0
+    );
+    // Completing the future consumed the handle.
+    assert!(ffi::folly_poll_c_abi_future(handle).is_nan());
+
+    let handle = c_abi::into_handle(rust_not_product());
+    assert_eq!(ffi::folly_poll_c_abi_error(handle), "kapow");
+
+    // Dropped handles and handles to other future types are rejected.
+    let handle = c_abi::into_handle(rust_dot_product().ignore());
+    assert!(rust_future_f64_drop(handle));
+    assert!(!rust_future_f64_drop(handle));
+    assert!(ffi::folly_poll_c_abi_future(handle).is_nan());
+    let handle = c_abi::into_handle(rust_reply(0));
+    assert!(ffi::folly_poll_c_abi_future(handle).is_nan());
+
+    let (future, sender) = c_abi::channel::<RustFutureF64>();
+    assert!(ffi::folly_send_c_abi(sender, 2.5));
+    assert_eq!(executor::block_on(future).unwrap(), 2.5);
+    assert!(!ffi::folly_send_c_abi(sender, 1.0));
+}
+
+// Test that a C++ coroutine asked to finish early can do so within the grace period, and that one
+// that doesn't is dropped once it expires.
+#[test]
+fn test_cancel_with_grace() {
+    use std::time::Duration;
+
+    let result =
+        executor::block_on(ffi::folly_cooperative().cancel_with_grace(Duration::from_secs(10)));
+    assert_eq!(result.unwrap().unwrap(), -1.0);
+
+    let grace = Duration::from_millis(50);
+    let start = Instant::now();
+    let result = executor::block_on(ffi::folly_uncooperative().cancel_with_grace(grace));
+    assert!(result.is_none());
+    assert!(start.elapsed() >= grace);
+    ffi::folly_release_uncooperative();
+}
+
+// Test that bridged types can be debug-printed without being consumed.
+#[test]
+fn test_debug_formatting() {
+    use futures::{future, poll};
+
+    let mut future = RustFutureF64::infallible(future::pending());
+    assert_eq!(
+        format!("{:?}", future),
+        "RustFutureF64 { last_status: None, poll_count: 0 }"
+    );
+    executor::block_on(async { assert!(poll!(&mut future).is_pending()) });
+    assert_eq!(
+        format!("{:?}", future),
+        "RustFutureF64 { last_status: Some(Pending), poll_count: 1 }"
+    );
+
+    let mut future = ffi::folly_not_product();
+    executor::block_on(&mut future).unwrap_err();
+    assert!(format!("{:?}", future).contains("Some(Error)"));
+
+    assert_eq!(
+        format!("{:?}", ffi::folly_fizzbuzz()),
+        "RustStreamString { .. }"
+    );
+    assert_eq!(
+        format!("{:?}", cxx_async::CxxAsyncException::from("kaboom")),
+        r#"CxxAsyncException { what: "kaboom" }"#
+    );
+}
+
+// Test sending a large variable-length value across the language barrier in both directions.
+#[test]
+fn test_large_bytes() {
+    const LEN: usize = 1 << 20;
+    let bytes = executor::block_on(ffi::folly_large_bytes(LEN)).unwrap();
+    assert_eq!(bytes.len(), LEN);
+    assert!(bytes
+        .iter()
+        .enumerate()
+        .all(|(i, &byte)| byte == (i % 251) as u8));
+    assert!(ffi::folly_call_rust_large_bytes(LEN));
+}
+
+// Test that C++ takes ownership of the allocation behind a `Vec<u8>` produced by Rust, without
+// copying it or losing its spare capacity.
+#[test]
+fn test_owned_buffer() {
+    let received = ffi::folly_take_owned_buffer(10 << 20);
+    assert_eq!(Some(received), *OWNED_BUFFER.lock().unwrap());
+    assert_eq!(received.capacity, 15 << 20);
+}
+
+// Test sending a fixed-size array across the language barrier in both directions.
+#[test]
+fn test_matrix_row() {
+    let row = executor::block_on(ffi::folly_matrix_row()).unwrap();
+    for (i, element) in row.iter().enumerate() {
+        assert_eq!(*element, i as f64 * 0.5);
+    }
+    assert!(ffi::folly_call_rust_matrix_row());
+}
+
+fn main() {
+    // Test Rust calling C++ async functions, both synchronously and via a scheduler.
+    for fun in &[ffi::folly_dot_product_coro, ffi::folly_dot_product_futures] {
+        let future = fun();
+        println!("{}", executor::block_on(future).unwrap());
+        let future = fun();
+        println!(
+            "{}",
+            executor::block_on(THREAD_POOL.spawn_with_handle(future).unwrap()).unwrap()
+        );
+    }
+    let future = ffi::folly_get_namespaced_string();
+    println!("{:?}", executor::block_on(future).unwrap());
+
+    // Test C++ calling Rust async functions.
+    ffi::folly_call_rust_hello();
+    ffi::folly_call_rust_dot_product();
+    ffi::folly_schedule_rust_dot_product();
+
+    // Test exceptions being thrown by C++ async functions.
+    let future = ffi::folly_not_product();
+    match executor::block_on(future) {
+        Ok(_) => panic!("shouldn't have succeeded!"),
+        Err(err) => println!("{}", err.what()),
+    }
+
+    // Test errors being thrown by Rust async functions.
+    ffi::folly_call_rust_not_product();
+
+    // Test yielding across the boundary repeatedly.
+    let future = ffi::folly_ping_pong(0);
+    println!("{}", executor::block_on(future).unwrap());
+
+    // Test returning void.
+    let future = ffi::folly_complete();
+    executor::block_on(future).unwrap();
+
+    // Test dropping futures.
+    ffi::folly_send_to_dropped_future();
+    ffi::folly_send_to_dropped_future_go();
+
+    // Test Rust calling C++ streams.
+    let vector = executor::block_on(
+        ffi::folly_fizzbuzz()
+            .map(|result| result.unwrap())
+            .collect::<Vec<String>>(),
+    );
+    println!("{}", vector.join(", "));
+    let vector = executor::block_on(
+        ffi::folly_indirect_fizzbuzz()
+            .map(|result| result.unwrap())
+            .collect::<Vec<String>>(),
+    );
+    println!("{}", vector.join(", "));
+
+    // Test Rust calling C++ streams that throw exceptions partway through.
+    let vector = executor::block_on(
+        ffi::folly_not_fizzbuzz()
+            .map(|result| format!("{:?}", result))
+            .collect::<Vec<String>>(),
+    );
+    println!("{}", vector.join(", "));
+    let vector = executor::block_on(
+        ffi::folly_two_then_fail()
+            .map(|result| format!("{:?}", result))
+            .collect::<Vec<String>>(),
+    );
+    println!("{}", vector.join(", "));
+
+    // Test collapsing nested futures.
+    let future = RustFutureF64::flatten(ffi::folly_nested_dot_product());
+    println!("{}", executor::block_on(future).unwrap());
+    let future = RustFutureF64::flatten(ffi::folly_nested_not_product());
+    println!("{:?}", executor::block_on(future));
+    let future = RustFutureF64::flatten(ffi::folly_not_nested_product());
+    println!("{:?}", executor::block_on(future));
+
+    // Test passing a Rust future to C++ as a native future.
+    println!("{}", ffi::folly_call_rust_dot_product_natively());
+
+    // Test awaiting synchronously-ready futures inside a nested await.
+    println!("{}", ffi::folly_call_rust_sum_ready_values());
+
+    // Test running a blocking computation off of the awaiting thread.
+    let square = executor::block_on(ffi::folly_slow_square(3.0)).unwrap();
+    println!("{}", square);
+
+    // Test returning an enum with data.
+    let reply = executor::block_on(ffi::folly_reply(1)).unwrap();
+    println!("{:?}", reply);
+    let reply = executor::block_on(ffi::folly_forward_reply(2)).unwrap();
+    println!("{:?}", reply);
+
+    // Test returning a shared struct.
+    let record = executor::block_on(ffi::folly_record()).unwrap();
+    println!("{:?}", record);
+    let record = executor::block_on(ffi::folly_forward_record()).unwrap();
+    println!("{:?}", record);
+
+    // Test handing a buffer to C++.
+    let received = ffi::folly_take_owned_buffer(1 << 20);
+    println!("{:?} {:?}", received, OWNED_BUFFER.lock().unwrap());
+
+    // Test collecting a stream until a deadline.
+    let stream = ffi::folly_count_slowly(1000, 20);
+    let items = executor::block_on(stream.collect_until(std::time::Duration::from_millis(100)));
+    println!("{:?}", items);
+
+    // Test awaiting the same C++ function several times at once.
+    let futures = (0..4).map(|i| ffi::folly_scaled_dot_product(i as f64));
+    let results = executor::block_on(futures::future::join_all(futures));
+    println!("{:?}", results);
+
+    // Test building a future with options.
+    let future = RustFutureF64::builder()
+        .name("example")
+        .timeout(std::time::Duration::from_secs(10))
+        .build(async { 2.0 });
+    println!("{:?}", future);
+    println!(
+        "{}",
+        executor::block_on(ffi::folly_rethrow(future)).unwrap()
+    );
+
+    // Test taking the first few items of an infinite stream.
+    let items: Vec<f64> =
+        executor::block_on(ffi::folly_count_forever().take(3).try_collect()).unwrap();
+    println!("{:?} {}", items, ffi::folly_live_count_forever());
+
+    // Test sending a serialized payload through C++.
+    let tagged = Tagged {
+        tag: 1,
+        text: "hello".to_owned(),
+    };
+    let future = ffi::folly_bump_tagged(RustFutureTagged::from_payload(async { Ok(tagged) }));
+    println!("{:?}", executor::block_on(future.into_payload()));
+
+    // Test awaiting the last item of a stream.
+    println!("{:?}", executor::block_on(ffi::folly_count(3).last()));
+
+    // Test waking a coroutine again after it has been resumed.
+    println!("{}", ffi::folly_call_rust_stash_waker());
+    if let Some(waker) = STASHED_WAKER.lock().unwrap().take() {
+        waker.wake();
+    }
+
+    // Compare spinning on a future that's almost ready with suspending on it.
+    for max_spins in [0, 4] {
+        let start = Instant::now();
+        let result = executor::block_on(ffi::folly_spin_on_rust(3, max_spins));
+        println!(
+            "{:?} with {} spins took {:?}",
+            result,
+            max_spins,
+            start.elapsed()
+        );
+    }
+
+    // Test splitting a stream into its items and how it ended.
+    let (values, completion) = ffi::folly_two_then_fail().split_error();
+    let items = executor::block_on(values.collect::<Vec<f64>>());
+    println!("{:?} {:?}", items, executor::block_on(completion));
+
+    // Test observing a write that C++ made on another thread before completing a future.
+    let value = executor::block_on(ffi::folly_publish_from_thread_pool(7));
+    println!("{:?} {}", value, ffi::folly_published());
+
+    // Test truncating a long exception message.
+    let error = executor::block_on(ffi::folly_throw_long_message(1 << 20)).unwrap_err();
+    println!("{}", error.what().len());
+
+    // Test building a future from a closure.
+    let x = 3.0;
+    let future = RustFutureF64::from_fn(move || async move { x * x });
+    println!("{:?}", executor::block_on(ffi::folly_rethrow(future)));
+
+    // Test completing a group of futures separately.
+    let group = ffi::folly_divmod(17, 5);
+    println!(
+        "{:?} {:?}",
+        executor::block_on(group.quotient),
+        executor::block_on(group.remainder)
+    );
+
+    // Test carrying task-local context through C++.
+    REQUEST_ID.with(|id| id.set(7));
+    let future = ffi::folly_rethrow(rust_read_request_id());
+    REQUEST_ID.with(|id| id.set(0));
+    println!("{:?}", executor::block_on(future));
+
+    // Test awaiting a C++ event.
+    let future = ffi::folly_event_wait();
+    ffi::folly_event_set_from_thread(10);
+    println!("{:?}", executor::block_on(future));
+    ffi::folly_event_reset();
+
+    // Test catching a cancellation in C++.
+    let (future, handle) = RustFutureF64::infallible_abortable(futures::future::pending());
+    handle.abort();
+    println!("{}", ffi::folly_describe_failure(future));
+
+    // Test collecting a Rust stream into a vector for C++.
+    let stream = RustStreamF64::infallible(futures::stream::iter(vec![1.0, 2.0, 3.0]));
+    let future = ffi::folly_reverse_f64_vec(stream.collect_to_future());
+    println!("{:?}", executor::block_on(future));
+
+    // Test passing a boxed Rust value through C++.
+    let future = ffi::folly_bump_counter(rust_counter(41));
+    println!("{}", executor::block_on(future).unwrap().0);
+    println!("{}", ffi::folly_result_kinds_agree());
+
+    // Test cleaning up after a cancelled await in C++.
+    let mut future =
+        ffi::folly_await_with_cleanup(RustFutureF64::infallible(futures::future::pending()));
+    println!("{:?}", futures::FutureExt::now_or_never(&mut future));
+    drop(future);
+    println!("{}", ffi::folly_cleanups());
+
+    // Test receiving a C++ time point.
+    let time = executor::block_on(ffi::folly_time_point(1_700_000_000, 250).into_payload());
+    println!("{:?}", time);
+    let future = ffi::folly_add_second(RustFutureTimePoint::from_payload(async {
+        Ok(SystemTime::UNIX_EPOCH)
+    }));
+    println!("{:?}", executor::block_on(future.into_payload()));
+
+    // Test waiting for a Rust future from synchronous C++ code.
+    println!(
+        "{}",
+        ffi::folly_blocking_wait(rust_slow_square(3.0)).unwrap()
+    );
+
+    // Test awaiting futures whose values are packed alongside the poll status.
+    println!(
+        "{}",
+        executor::block_on(ffi::folly_both(
+            RustFutureBool::ready(true),
+            RustFutureBool::ready(false)
+        ))
+        .unwrap()
+    );
+    println!(
+        "{} {}",
+        ffi::folly_packs_results(),
+        executor::block_on(ffi::folly_negate(RustFutureI32::ready(-7))).unwrap()
+    );
+
+    // Test resuming a C++ coroutine on an executor of its choosing.
+    println!(
+        "{}",
+        executor::block_on(ffi::folly_add_one_on_loop(RustFutureF64::ready(41.0))).unwrap()
+    );
+
+    // Test checking the layouts of future values.
+    println!("{}", ffi::folly_result_layouts_agree());
+
+    // Test draining the items still buffered when a stream is dropped.
+    drop(ffi::folly_count_batched(3, 3).on_drain(|item| println!("drained {}", item)));
+
+    // Test receiving a buffer that C++ frees with its own deleter.
+    let buffer = executor::block_on(ffi::folly_foreign_buffer(4)).unwrap();
+    println!("{:?}", &*buffer);
+    drop(buffer);
+    println!("{}", ffi::folly_foreign_buffers_freed());
+
+    // Test running a future's work as a task on another executor.
+    let future = RustFutureF64::spawn_on(&ThreadPool::new().unwrap(), async { 1.0 });
+    println!(
+        "{}",
+        executor::block_on(ffi::folly_rethrow(future)).unwrap()
+    );
+
+    // Test stopping a Rust future through a C++ `std::stop_token`.
+    let future = RustFutureF64::fallible_until_stopped(
+        ffi::folly_stop_requested(),
+        futures::future::pending(),
+    );
+    ffi::folly_request_stop();
+    println!("{}", ffi::folly_describe_failure(future));
+
+    // Test a value that refuses to cross the bridge.
+    let future = ffi::folly_add_guarded(
+        RustFutureGuardedI32::infallible(async { Guarded(-1) }),
+        RustFutureGuardedF64::infallible(async { Guarded(0.5) }),
+    );
+    println!("{}", executor::block_on(future).unwrap_err().what());
+
+    // Test completing an out-parameter from a C++ lvalue.
+    println!(
+        "{}",
+        executor::block_on(ffi::folly_complete_from_lvalue()).unwrap()
+    );
+
+    // Test peeking at completed futures.
+    let mut future = ffi::folly_answer();
+    println!("{:?}", future.try_peek());
+
+    // Test tagging await sites.
+    let error = executor::block_on(ffi::folly_not_product_at_await_site()).unwrap_err();
+    println!("{}", error.what());
+
+    // Test converting between bridged future types.
+    let future = ffi::folly_answer().map_into::<RustFutureF64, _>(|i| i as f64);
+    println!("{}", executor::block_on(future).unwrap());
+
+    // Test running cleanup when C++ drops a future.
+    ffi::folly_drop_future(
+        rust_dot_product()
+            .finally(|| println!("cleaned up"))
+            .ignore(),
+    );
+
+    // Test falling back to a default value when a future fails.
+    let future = ffi::folly_not_product().unwrap_or(0.0);
+    println!("{}", executor::block_on(future).unwrap());
+
+    // Test attaching a context to a future.
+    let mut future = RustFutureF64::with_context("request", ffi::folly_dot_product_coro());
+    println!(
+        "{} {:?}",
+        executor::block_on(&mut future).unwrap(),
+        future.take_context::<&str>()
+    );
+
+    // Test merging streams.
+    let streams = vec![ffi::folly_count(3), ffi::folly_count(3)];
+    let items: Vec<_> = executor::block_on(cxx_async::stream::merge(streams).collect());
+    println!("{:?}", items);
+
+    // Test rethrowing errors from Rust futures in C++.
+    let future = ffi::folly_rethrow(rust_not_product());
+    println!("{}", executor::block_on(future).unwrap_err());
+
+    // Test coalescing requests for the same computation.
+    let cache = cxx_async::cache::FutureCache::new(cxx_async::cache::EvictionPolicy::KeepSuccesses);
+    println!(
+        "{}",
+        executor::block_on(cache.get_or_compute("dot", ffi::folly_dot_product_coro)).unwrap()
+    );
+
+    // Test routing responses to outstanding requests.
+    let requests = vec![
+        RESPONSE_ROUTER.request(1),
+        RESPONSE_ROUTER.request(2),
+        RESPONSE_ROUTER.request(3),
+    ];
+    ffi::folly_route_responses_out_of_order();
+    let responses = executor::block_on(futures::future::join_all(requests));
+    println!("{:?}", responses);
+
+    // Test choosing the executor for a future's subtasks.
+    let name = executor::block_on(ffi::folly_subtask_thread_name(1)).unwrap();
+    println!("{}", name);
+
+    // Test polling a future through the plain C interface.
+    let handle = cxx_async::c_abi::into_handle(rust_dot_product());
+    println!("{}", ffi::folly_poll_c_abi_future(handle));
+    let handle = cxx_async::c_abi::into_handle(rust_not_product());
+    println!("{}", ffi::folly_poll_c_abi_error(handle));
+    let (future, sender) = cxx_async::c_abi::channel::<RustFutureF64>();
+    ffi::folly_send_c_abi(sender, 2.5);
+    println!("{}", executor::block_on(future).unwrap());
+
+    // Test cancelling futures with a grace period.
+    let grace = std::time::Duration::from_millis(50);
+    let result = executor::block_on(ffi::folly_cooperative().cancel_with_grace(grace));
+    println!("{:?}", result);
+    let result = executor::block_on(ffi::folly_uncooperative().cancel_with_grace(grace));
+    println!("{:?}", result);
+    ffi::folly_release_uncooperative();
+
+    // Test debug-printing futures.
+    println!("{:?}", ffi::folly_not_product());
+
+    // Test taking the first successful result of several futures.
+    let futures = vec![ffi::folly_not_product(), rust_dot_product()];
+    println!(
+        "{}",
+        executor::block_on(cxx_async::select_ok(futures)).unwrap()
+    );
+
+    // Test sending large variable-length values.
+    println!(
+        "{}",
+        executor::block_on(ffi::folly_large_bytes(1 << 20))
+            .unwrap()
+            .len()
+    );
+    println!("{}", ffi::folly_call_rust_large_bytes(1 << 20));
+
+    // Test sending fixed-size arrays.
+    let row = executor::block_on(ffi::folly_matrix_row()).unwrap();
+    println!("{}", row.iter().sum::<f64>());
+    println!("{}", ffi::folly_call_rust_matrix_row());
+
+    // Compare the throughput of sending stream items one at a time and in batches.
+    const STREAM_LENGTH: usize = 1_000_000;
+    let start = Instant::now();
+    let count = executor::block_on(ffi::folly_count(STREAM_LENGTH).count());
+    println!("{} items one at a time: {:?}", count, start.elapsed());
+    let start = Instant::now();
+    let count = executor::block_on(ffi::folly_count_batched(STREAM_LENGTH, 1024).count());
+    println!("{} items in batches: {:?}", count, start.elapsed());
+
+    // Test that cancelling a stream frees its items.
+    drop(ffi::folly_drop_counted_stream(5));
+    println!("{}", ffi::folly_live_drop_counted());
+
+    // Test that dropping a future cancels the C++ coroutine.
+    drop(ffi::folly_cancellable());
+    ffi::folly_wait_for_cancellation();
 
     // Test that destructors are called when dropping a future.
     let _ = ffi::folly_drop_coroutine_wait();
@@ -49,6 +49,11 @@ use syn::Visibility;
 /// `CxxAsyncResult`. On the C++ side it will be converted to the appropriate type, following the
 /// `cxx` rules. Err returns are translated into C++ exceptions.
 ///
+/// Fixed-size arrays such as `[f64; 256]` are supported as `Output` types without any heap
+/// allocation; on the C++ side, use the corresponding `std::array` (here,
+/// `std::array<double, 256>`). The value is moved into the result buffer that C++ provides with a
+/// single `memcpy`.
+///
 /// If the future is inside a C++ namespace, add a `namespace = ...` attribute to the
 /// `#[cxx_async::bridge]` attribute like so:
 ///
@@ -67,13 +72,24 @@ use syn::Visibility;
 /// }
 /// ```
 ///
+/// To also expose the future to plain C callers that can't use `cxx`, add a `c_abi = prefix`
+/// attribute, which generates `#[no_mangle]` functions named `prefix_poll`, `prefix_drop`,
+/// `prefix_send`, and `prefix_send_error`. See `cxx_async::c_abi` for their signatures.
+///
+/// A payload type that `cxx` can't share may cross the bridge encoded instead: declare the
+/// future's `Output` as the encoded type, usually `Vec<u8>`, and add a `payload = Type` attribute,
+/// where `Type` implements `cxx_async::CxxAsyncPayload<Output>`. This generates `from_payload()`
+/// and `into_payload()` methods that encode and decode the value.
+///
 /// ## Safety
 ///
 /// It's the programmer's responsibility to ensure that the specified `Output` type correctly
-/// reflects the type of the value that any returned C++ future resolves to. `cxx_async` can't
-/// currently check to ensure that these types match. If the types don't match, undefined behavior
-/// can result. See the [cxx documentation] for information on the mapping between Rust types and
-/// C++ types.
+/// reflects the type of the value that any returned C++ future resolves to. `cxx_async` checks
+/// what it can: when the first future or stream of each type is created, the C++ side aborts
+/// unless it agrees with the Rust side on how values are handed over (their
+/// `CxxAsyncResultKind`) and on their size and alignment. Two different types with the same
+/// layout still get through, though, and if the types don't match, undefined behavior can result.
+/// See the [cxx documentation] for information on the mapping between Rust types and C++ types.
 ///
 /// [cxx documentation]: https://cxx.rs/bindings.html
 #[proc_macro_attribute]
@@ -104,12 +120,16 @@ fn bridge_future(pieces: AstPieces) -> TokenStream {
         trait_path,
         vtable_glue_ident,
         vtable_glue_link_name,
+        c_abi,
+        payload,
     } = pieces;
+    let c_abi_shim = c_abi.map(|prefix| c_abi_shim(&future, &output, &prefix));
+    let payload_methods = payload.map(|payload| payload_methods(&future, &output, &payload));
     (quote! {
         /// A future shared between Rust and C++.
         #[repr(transparent)]
         pub struct #future {
-            future: ::cxx_async::private::BoxFuture<'static, ::cxx_async::CxxAsyncResult<#output>>,
+            future: ::cxx_async::private::TrackedBoxFuture<::cxx_async::CxxAsyncResult<#output>>,
         }
 
         impl #future {
@@ -120,20 +140,41 @@ fn bridge_future(pieces: AstPieces) -> TokenStream {
             //    method.
             // 3. The struct isn't `repr(packed)`. We define the struct and don't have this
             //    attribute.
-            ::cxx_async::unsafe_pinned!(future: ::cxx_async::private::BoxFuture<'static,
+            ::cxx_async::unsafe_pinned!(future: ::cxx_async::private::TrackedBoxFuture<
                 ::cxx_async::CxxAsyncResult<#output>>);
 
             #[doc(hidden)]
             fn assert_field_is_unpin() {
                 fn check<T>() where T: Unpin {}
-                check::<#output>()
+                check::<::cxx_async::private::TrackedBoxFuture<
+                    ::cxx_async::CxxAsyncResult<#output>>>()
             }
         }
 
         // Define a Drop implementation so that end users don't. If end users are allowed to define
         // Drop, that could make our use of `unsafe_pinned!` unsafe.
         impl Drop for #future {
-            fn drop(&mut self) {}
+            fn drop(&mut self) {
+                // Report futures that are dropped by accident. See `cxx_async::discard`.
+                #[cfg(debug_assertions)]
+                if self.future.is_discarded() {
+                    ::cxx_async::discard::report(stringify!(#future), self.future.name());
+                }
+            }
+        }
+
+        // Show the state of the future without polling it.
+        impl ::std::fmt::Debug for #future {
+            fn fmt(&self, formatter: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+                let mut debug_struct = formatter.debug_struct(stringify!(#future));
+                if let Some(name) = self.future.name() {
+                    debug_struct.field("name", &name);
+                }
+                debug_struct
+                    .field("last_status", &self.future.last_status())
+                    .field("poll_count", &self.future.poll_count())
+                    .finish()
+            }
         }
 
         // Define how to box up a future.
@@ -142,9 +183,18 @@ fn bridge_future(pieces: AstPieces) -> TokenStream {
             fn fallible<Fut>(future: Fut) -> Self where Fut: ::std::future::Future<Output =
                     ::cxx_async::CxxAsyncResult<#output>> + Send + 'static {
                 #future {
-                    future: Box::pin(future),
+                    future: ::cxx_async::private::box_future(future),
                 }
             }
+            fn set_name(&mut self, name: String) {
+                self.future.as_mut().set_name(name.into_boxed_str())
+            }
+            fn set_ignored(&mut self) {
+                self.future.as_mut().set_ignored()
+            }
+            fn set_timeout_handle(&mut self, handle: ::cxx_async::builder::TimeoutHandle) {
+                self.future.as_mut().set_timeout_handle(handle)
+            }
         }
 
         // Implement the Rust Future trait.
@@ -160,7 +210,7 @@ fn bridge_future(pieces: AstPieces) -> TokenStream {
         impl ::std::convert::From<::cxx_async::CxxAsyncReceiver<#output>> for #future {
             fn from(receiver: ::cxx_async::CxxAsyncReceiver<#output>) -> Self {
                 Self {
-                    future: Box::pin(receiver),
+                    future: ::cxx_async::private::box_receiver(receiver),
                 }
             }
         }
@@ -184,8 +234,205 @@ fn bridge_future(pieces: AstPieces) -> TokenStream {
                         ::cxx_async::CxxAsyncResult<#output>> + Send + 'static {
                 <#future as ::cxx_async::IntoCxxAsyncFuture>::fallible(future)
             }
+
+            pub fn with_task_locals<L, Fut>(future: Fut) -> Self
+                    where L: ::cxx_async::task_local::TaskLocals,
+                    Fut: ::std::future::Future<Output = #output> + Send + 'static {
+                <#future as ::cxx_async::IntoCxxAsyncFuture>::with_task_locals::<L, Fut>(future)
+            }
+
+            pub fn fallible_with_task_locals<L, Fut>(future: Fut) -> Self
+                    where L: ::cxx_async::task_local::TaskLocals,
+                    Fut: ::std::future::Future<Output =
+                        ::cxx_async::CxxAsyncResult<#output>> + Send + 'static {
+                <#future as ::cxx_async::IntoCxxAsyncFuture>::fallible_with_task_locals::<L, Fut>(
+                    future)
+            }
+
+            pub fn ready(value: #output) -> Self {
+                <#future as ::cxx_async::IntoCxxAsyncFuture>::ready(value)
+            }
+
+            pub fn failed<E>(error: E) -> Self where E: Into<::cxx_async::CxxAsyncException> {
+                <#future as ::cxx_async::IntoCxxAsyncFuture>::failed(error)
+            }
+
+            pub fn from_fn<F, Fut>(f: F) -> Self
+                    where F: FnOnce() -> Fut + Send + 'static,
+                    Fut: ::std::future::Future<Output = #output> + Send + 'static {
+                <#future as ::cxx_async::IntoCxxAsyncFuture>::from_fn(f)
+            }
+
+            pub fn fallible_from_fn<F, Fut>(f: F) -> Self
+                    where F: FnOnce() -> Fut + Send + 'static,
+                    Fut: ::std::future::Future<Output =
+                        ::cxx_async::CxxAsyncResult<#output>> + Send + 'static {
+                <#future as ::cxx_async::IntoCxxAsyncFuture>::fallible_from_fn(f)
+            }
+
+            pub fn fallible_with_codec<Fut, E>(future: Fut) -> Self
+                    where Fut: ::std::future::Future<Output = Result<#output, E>> + Send + 'static,
+                    E: ::cxx_async::CxxAsyncErrorCodec {
+                <#future as ::cxx_async::IntoCxxAsyncFuture>::fallible_with_codec(future)
+            }
+
+            pub fn fallible_with_early_exit<Fut>(future: Fut) -> Self
+                    where Fut: ::std::future::Future<Output = Result<#output,
+                        ::cxx_async::CxxAsyncExit<#output>>> + Send + 'static {
+                <#future as ::cxx_async::IntoCxxAsyncFuture>::fallible_with_early_exit(future)
+            }
+
+            pub fn flatten<Fut, Inner>(future: Fut) -> Self
+                    where Fut: ::std::future::Future<Output =
+                        ::cxx_async::CxxAsyncResult<Inner>> + Send + 'static,
+                    Inner: ::std::future::Future<Output =
+                        ::cxx_async::CxxAsyncResult<#output>> + Send + 'static {
+                <#future as ::cxx_async::IntoCxxAsyncFuture>::flatten(future)
+            }
+
+            /// Returns the result of this future if it has completed, without consuming it.
+            ///
+            /// Returns `None` if the future isn't ready yet. Otherwise, the result is held until
+            /// the future is next polled, which yields it as usual. Checking for the result polls
+            /// the future, with the waker from the last poll if there was one, so that the task
+            /// that last polled it is still woken.
+            pub fn try_peek(&mut self) -> ::std::option::Option<::std::result::Result<&#output,
+                    &::cxx_async::CxxAsyncException>> {
+                self.future.as_mut().try_peek()
+            }
+
+            /// Asks the computation behind this future to finish early, then waits up to `grace` for
+            /// it to do so. If it hasn't by then, the future is dropped.
+            ///
+            /// Resolves to the future's result if it finished within the grace period, or `None` if
+            /// it was dropped. If the future is a C++ coroutine, the request to finish calls the
+            /// callbacks that it registered with `rust::async::on_cancel()`, and dropping the future
+            /// is what cancelling it normally means. Rust futures have no way to be asked, so they
+            /// just get the grace period.
+            pub fn cancel_with_grace(mut self, grace: ::std::time::Duration)
+                    -> impl ::std::future::Future<Output =
+                        ::std::option::Option<::cxx_async::CxxAsyncResult<#output>>> {
+                self.future.as_mut().signal_cancel();
+                ::cxx_async::private::finish_within(self, grace)
+            }
+
+            pub fn from_on<E, Fut>(executor: E, future: Fut) -> Self
+                    where E: ::cxx_async::private::Spawn + Send + Sync + 'static,
+                    Fut: ::std::future::Future<Output = #output> + Send + 'static {
+                <#future as ::cxx_async::IntoCxxAsyncFuture>::from_on(executor, future)
+            }
+
+            pub fn spawn_on<E, Fut>(executor: &E, future: Fut) -> Self
+                    where E: ::cxx_async::private::Spawn + ?Sized,
+                    Fut: ::std::future::Future<Output = #output> + Send + 'static,
+                    #output: Send + 'static {
+                <#future as ::cxx_async::IntoCxxAsyncFuture>::spawn_on(executor, future)
+            }
+
+            pub fn builder() -> ::cxx_async::builder::FutureBuilder<Self> {
+                <#future as ::cxx_async::IntoCxxAsyncFuture>::builder()
+            }
+
+            pub fn from_blocking<F>(f: F) -> Self
+                    where F: FnOnce() -> #output + Send + 'static {
+                <#future as ::cxx_async::IntoCxxAsyncFuture>::from_blocking(f)
+            }
+
+            /// # Safety
+            ///
+            /// See `cxx_async::IntoCxxAsyncFuture::infallible_local`.
+            pub unsafe fn infallible_local<Fut>(future: Fut) -> Self
+                    where Fut: ::std::future::Future<Output = #output> + 'static {
+                <#future as ::cxx_async::IntoCxxAsyncFuture>::infallible_local(future)
+            }
+
+            /// # Safety
+            ///
+            /// See `cxx_async::IntoCxxAsyncFuture::fallible_local`.
+            pub unsafe fn fallible_local<Fut>(future: Fut) -> Self
+                    where Fut: ::std::future::Future<Output =
+                        ::cxx_async::CxxAsyncResult<#output>> + 'static {
+                <#future as ::cxx_async::IntoCxxAsyncFuture>::fallible_local(future)
+            }
+
+            pub fn infallible_abortable<Fut>(future: Fut) -> (Self, ::cxx_async::AbortHandle)
+                    where Fut: ::std::future::Future<Output = #output> + Send + 'static {
+                <#future as ::cxx_async::IntoCxxAsyncFuture>::infallible_abortable(future)
+            }
+
+            pub fn fallible_abortable<Fut>(future: Fut) -> (Self, ::cxx_async::AbortHandle)
+                    where Fut: ::std::future::Future<Output =
+                        ::cxx_async::CxxAsyncResult<#output>> + Send + 'static {
+                <#future as ::cxx_async::IntoCxxAsyncFuture>::fallible_abortable(future)
+            }
+
+            pub fn fallible_until_stopped<S, Fut>(stop: S, future: Fut) -> Self
+                    where S: ::std::future::Future + Send + 'static,
+                    Fut: ::std::future::Future<Output =
+                        ::cxx_async::CxxAsyncResult<#output>> + Send + 'static {
+                <#future as ::cxx_async::IntoCxxAsyncFuture>::fallible_until_stopped(stop, future)
+            }
+
+            pub fn ignore(self) -> Self {
+                <#future as ::cxx_async::IntoCxxAsyncFuture>::ignore(self)
+            }
+
+            pub fn finally<F>(self, cleanup: F) -> Self where F: FnOnce() + Send + 'static {
+                <#future as ::cxx_async::IntoCxxAsyncFuture>::finally(self, cleanup)
+            }
+
+            pub fn with_priority(self, priority: ::cxx_async::builder::CxxAsyncPriority) -> Self {
+                <#future as ::cxx_async::IntoCxxAsyncFuture>::with_priority(self, priority)
+            }
+
+            pub fn map_into<Target, F>(self, f: F) -> Target
+                    where Target: ::cxx_async::IntoCxxAsyncFuture,
+                    F: FnOnce(#output) -> Target::Output + Send + 'static {
+                <#future as ::cxx_async::IntoCxxAsyncFuture>::map_into(self, f)
+            }
+
+            pub fn poll_count(&self) -> u64 {
+                self.future.poll_count()
+            }
+
+            pub fn last_status(&self) -> Option<::cxx_async::CxxAsyncPollStatus> {
+                self.future.last_status()
+            }
+
+            pub fn timeout_handle(&self) -> Option<::cxx_async::builder::TimeoutHandle> {
+                self.future.timeout_handle().cloned()
+            }
+
+            pub fn reset_timeout(&self, timeout: ::std::time::Duration) -> bool {
+                self.future.timeout_handle().is_some_and(|handle| handle.reset(timeout))
+            }
+
+            pub fn with_context<C>(context: C, future: Self) -> ::cxx_async::WithContext<Self>
+                    where C: Send + 'static {
+                <#future as ::cxx_async::IntoCxxAsyncFuture>::with_context(context, future)
+            }
+
+            pub fn into_dyn(self) -> Box<dyn ::cxx_async::DynCxxFuture> {
+                <#future as ::cxx_async::IntoCxxAsyncFuture>::into_dyn(self)
+            }
+
+            pub fn start(self) -> ::cxx_async::Started<#output> {
+                <#future as ::cxx_async::IntoCxxAsyncFuture>::start(self)
+            }
+
+            pub fn unwrap_or(self, default: #output) -> Self {
+                <#future as ::cxx_async::IntoCxxAsyncFuture>::unwrap_or(self, default)
+            }
+
+            pub fn unwrap_or_else<F>(self, f: F) -> Self
+                    where F: FnOnce(::cxx_async::CxxAsyncException) -> #output + Send + 'static {
+                <#future as ::cxx_async::IntoCxxAsyncFuture>::unwrap_or_else(self, f)
+            }
         }
 
+        // Rejects a `CxxAsyncResultRepr` implementation that contradicts itself at compile time.
+        const _: () = ::cxx_async::repr::assert_result_repr::<#output>();
+
         #[doc(hidden)]
         #[allow(non_snake_case)]
         #[export_name = #vtable_glue_link_name]
@@ -196,13 +443,105 @@ fn bridge_future(pieces: AstPieces) -> TokenStream {
                 sender_drop: ::cxx_async::sender_drop::<#output> as *mut u8,
                 future_poll: ::cxx_async::future_poll::<#future, #output> as *mut u8,
                 future_drop: ::cxx_async::future_drop::<#future> as *mut u8,
+                sender_send_batch: ::std::ptr::null_mut(),
+                sender_on_cancel: ::cxx_async::sender_on_cancel::<#output> as *mut u8,
+                result_kind: ::cxx_async::repr::result_kind::<#output> as *mut u8,
+                future_poll_packed: if <#output as ::cxx_async::CxxAsyncResultRepr>::PACKED {
+                    ::cxx_async::future_poll_packed::<#future, #output> as *mut u8
+                } else {
+                    ::std::ptr::null_mut()
+                },
+                result_layout: ::cxx_async::repr::result_layout::<#output> as *mut u8,
             };
             return &VTABLE;
         }
+
+        // C++ holds a future by value and moves it when it hands it over.
+        unsafe impl ::cxx_async::CxxAsyncResultRepr for #future {
+            const KIND: ::cxx_async::CxxAsyncResultKind = ::cxx_async::CxxAsyncResultKind::Moved;
+        }
+
+        #c_abi_shim
+
+        #payload_methods
     })
     .into()
 }
 
+// Generates the methods that convert to and from the serialized payload type for a future with a
+// `payload = ...` attribute. See `cxx_async::CxxAsyncPayload`.
+fn payload_methods(future: &Ident, output: &Type, payload: &Type) -> proc_macro2::TokenStream {
+    quote! {
+        impl #future {
+            /// Wraps a Rust future that returns the payload type, encoding its value with
+            /// `CxxAsyncPayload::encode()`.
+            pub fn from_payload<Fut>(future: Fut) -> Self
+                    where Fut: ::std::future::Future<Output =
+                        ::cxx_async::CxxAsyncResult<#payload>> + Send + 'static {
+                Self::fallible(async move {
+                    future.await.map(<#payload as ::cxx_async::CxxAsyncPayload<#output>>::encode)
+                })
+            }
+
+            /// Awaits this future and decodes its value with `CxxAsyncPayload::decode()`.
+            pub fn into_payload(self)
+                    -> impl ::std::future::Future<Output = ::cxx_async::CxxAsyncResult<#payload>> {
+                async move {
+                    self.await.and_then(<#payload as ::cxx_async::CxxAsyncPayload<#output>>::decode)
+                }
+            }
+        }
+    }
+}
+
+// Generates the plain C functions for a future with a `c_abi = ...` attribute. See the
+// `cxx_async::c_abi` module.
+fn c_abi_shim(future: &Ident, output: &Type, prefix: &Ident) -> proc_macro2::TokenStream {
+    let ident = |suffix: &str| Ident::new(&format!("{}_{}", prefix, suffix), prefix.span());
+    let (poll, drop, send, send_error) = (
+        ident("poll"),
+        ident("drop"),
+        ident("send"),
+        ident("send_error"),
+    );
+    quote! {
+        #[no_mangle]
+        #[doc(hidden)]
+        pub unsafe extern "C" fn #poll(
+            handle: ::cxx_async::c_abi::CxxAsyncCHandle,
+            result: *mut #output,
+            error: *mut *mut ::std::os::raw::c_char,
+            waker: ::cxx_async::c_abi::CxxAsyncCWaker,
+        ) -> u32 {
+            ::cxx_async::c_abi::poll::<#future, #output>(handle, result, error, waker)
+        }
+
+        #[no_mangle]
+        #[doc(hidden)]
+        pub extern "C" fn #drop(handle: ::cxx_async::c_abi::CxxAsyncCHandle) -> bool {
+            ::cxx_async::c_abi::drop_handle::<#future>(handle)
+        }
+
+        #[no_mangle]
+        #[doc(hidden)]
+        pub unsafe extern "C" fn #send(
+            sender: ::cxx_async::c_abi::CxxAsyncCHandle,
+            value: *mut #output,
+        ) -> bool {
+            ::cxx_async::c_abi::send::<#output>(sender, value)
+        }
+
+        #[no_mangle]
+        #[doc(hidden)]
+        pub unsafe extern "C" fn #send_error(
+            sender: ::cxx_async::c_abi::CxxAsyncCHandle,
+            what: *const ::std::os::raw::c_char,
+        ) -> bool {
+            ::cxx_async::c_abi::send_error::<#output>(sender, what)
+        }
+    }
+}
+
 /// Defines a C++ stream type that can be awaited from Rust.
 ///
 /// The syntax to use is:
@@ -220,6 +559,13 @@ fn bridge_future(pieces: AstPieces) -> TokenStream {
 /// `CxxAsyncResult`. On the C++ side it will be converted to the appropriate type, following the
 /// `cxx` rules. Err returns are translated into C++ exceptions.
 ///
+/// If the C++ coroutine throws an exception partway through, the Rust side sees every item that
+/// was yielded before the exception, followed by exactly one `Err`, after which the stream
+/// terminates. This is distinct from normal end-of-stream, which yields no `Err` at all.
+///
+/// The stream type implements `Stream` and `Unpin`, so it can be polled with any `Context`, by any
+/// executor, and combined with the adapters in `futures::StreamExt` without pinning it first.
+///
 /// If the stream is inside a C++ namespace, add a `namespace = ...` attribute to the
 /// `#[cxx_async::bridge_stream]` attribute like so:
 ///
@@ -241,10 +587,12 @@ fn bridge_future(pieces: AstPieces) -> TokenStream {
 /// ## Safety
 ///
 /// It's the programmer's responsibility to ensure that the specified `Item` type correctly
-/// reflects the type of the values that any returned C++ streams resolves to. `cxx_async` can't
-/// currently check to ensure that these types match. If the types don't match, undefined behavior
-/// can result. See the [cxx documentation] for information on the mapping between Rust types and
-/// C++ types.
+/// reflects the type of the values that any returned C++ stream yields. `cxx_async` checks what it
+/// can: when the first future or stream of each type is created, the C++ side aborts unless it
+/// agrees with the Rust side on how items are handed over (their `CxxAsyncResultKind`) and on
+/// their size and alignment. Two different types with the same layout still get through, though,
+/// and if the types don't match, undefined behavior can result. See the [cxx documentation] for
+/// information on the mapping between Rust types and C++ types.
 ///
 /// [cxx documentation]: https://cxx.rs/bindings.html
 fn bridge_stream(pieces: AstPieces) -> TokenStream {
@@ -256,12 +604,14 @@ fn bridge_stream(pieces: AstPieces) -> TokenStream {
         trait_path,
         vtable_glue_ident,
         vtable_glue_link_name,
+        c_abi: _,
+        payload: _,
     } = pieces;
     (quote! {
         /// A multi-shot stream shared between Rust and C++.
         #[repr(transparent)]
         pub struct #stream {
-            stream: ::cxx_async::private::BoxStream<'static, ::cxx_async::CxxAsyncResult<#item>>,
+            stream: ::cxx_async::private::BoxStreamBody<#item>,
         }
 
         impl #stream {
@@ -272,13 +622,12 @@ fn bridge_stream(pieces: AstPieces) -> TokenStream {
             //    method.
             // 3. The struct isn't `repr(packed)`. We define the struct and don't have this
             //    attribute.
-            ::cxx_async::unsafe_pinned!(stream: ::cxx_async::private::BoxStream<'static,
-                ::cxx_async::CxxAsyncResult<#item>>);
+            ::cxx_async::unsafe_pinned!(stream: ::cxx_async::private::BoxStreamBody<#item>);
 
             #[doc(hidden)]
             fn assert_field_is_unpin() {
                 fn check<T>() where T: Unpin {}
-                check::<#item>()
+                check::<::cxx_async::private::BoxStreamBody<#item>>()
             }
         }
 
@@ -288,15 +637,24 @@ fn bridge_stream(pieces: AstPieces) -> TokenStream {
             fn drop(&mut self) {}
         }
 
+        impl ::std::fmt::Debug for #stream {
+            fn fmt(&self, formatter: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+                formatter.debug_struct(stringify!(#stream)).finish_non_exhaustive()
+            }
+        }
+
         // Define how to box up a future.
         impl ::cxx_async::IntoCxxAsyncStream for #stream {
             type Item = #item;
             fn fallible<Stm>(stream: Stm) -> Self where Stm: #trait_path<Item =
                     ::cxx_async::CxxAsyncResult<#item>> + Send + 'static {
                 #stream {
-                    stream: Box::pin(stream),
+                    stream: ::cxx_async::private::box_stream(stream),
                 }
             }
+            fn set_drain(&mut self, drain: Box<dyn FnMut(#item) + Send>) {
+                self.stream.as_mut().set_drain(drain)
+            }
         }
 
         // Implement the Rust Stream trait.
@@ -336,8 +694,51 @@ fn bridge_stream(pieces: AstPieces) -> TokenStream {
                         ::cxx_async::CxxAsyncResult<#item>> + Send + 'static {
                 <#stream as ::cxx_async::IntoCxxAsyncStream>::fallible(stream)
             }
+
+            /// Collects items until the stream ends or `timeout` elapses, and then cancels the
+            /// stream. See `cxx_async::stream::collect_until()`.
+            pub fn collect_until(self, timeout: ::std::time::Duration)
+                    -> impl ::std::future::Future<Output = ::std::vec::Vec<#item>> {
+                ::cxx_async::stream::collect_until(self, timeout)
+            }
+
+            /// Drives the stream to its end and resolves to its last item. See
+            /// `cxx_async::stream::last()`.
+            pub fn last(self) -> impl ::std::future::Future<Output =
+                    ::cxx_async::CxxAsyncResult<::std::option::Option<#item>>> {
+                ::cxx_async::stream::last(self)
+            }
+
+            /// Collects all items into a future that C++ can await to receive them as a
+            /// `rust::Vec`. See `cxx_async::stream::collect_to_future()`.
+            pub fn collect_to_future<Fut>(self) -> Fut
+                    where Fut: ::cxx_async::IntoCxxAsyncFuture<Output = ::std::vec::Vec<#item>> {
+                ::cxx_async::stream::collect_to_future(self)
+            }
+
+            /// Splits the stream into its successful items and a future that resolves to how it
+            /// ended. See `cxx_async::stream::split_error()`.
+            pub fn split_error(self) -> (::cxx_async::stream::Values<Self>,
+                    ::cxx_async::stream::Completion) {
+                ::cxx_async::stream::split_error(self)
+            }
+
+            /// Yields at most `count` items and then cancels the stream, right after the last
+            /// item. See `cxx_async::stream::take()`.
+            pub fn take(self, count: usize) -> ::cxx_async::stream::Take<Self> {
+                ::cxx_async::stream::take(self, count)
+            }
+
+            /// Hands the items still buffered when the stream is dropped to `drain`. See
+            /// `cxx_async::IntoCxxAsyncStream::on_drain()`.
+            pub fn on_drain<F>(self, drain: F) -> Self where F: FnMut(#item) + Send + 'static {
+                <#stream as ::cxx_async::IntoCxxAsyncStream>::on_drain(self, drain)
+            }
         }
 
+        // Rejects a `CxxAsyncResultRepr` implementation that contradicts itself at compile time.
+        const _: () = ::cxx_async::repr::assert_result_repr::<#item>();
+
         #[doc(hidden)]
         #[allow(non_snake_case)]
         #[export_name = #vtable_glue_link_name]
@@ -349,6 +750,11 @@ fn bridge_stream(pieces: AstPieces) -> TokenStream {
                 sender_drop: ::cxx_async::sender_drop::<#item> as *mut u8,
                 future_poll: ::std::ptr::null_mut(),
                 future_drop: ::cxx_async::future_drop::<#stream> as *mut u8,
+                sender_send_batch: ::cxx_async::sender_stream_send_batch::<#item> as *mut u8,
+                sender_on_cancel: ::cxx_async::sender_on_cancel::<#item> as *mut u8,
+                result_kind: ::cxx_async::repr::result_kind::<#item> as *mut u8,
+                future_poll_packed: ::std::ptr::null_mut(),
+                result_layout: ::cxx_async::repr::result_layout::<#item> as *mut u8,
             };
             return &VTABLE;
         }
@@ -380,13 +786,25 @@ struct AstPieces {
     vtable_glue_ident: Ident,
     // The external C++ link name of the future/stream vtable.
     vtable_glue_link_name: String,
+    // The prefix of the plain C functions to generate for the future, if any. See `c_abi`.
+    c_abi: Option<Ident>,
+    // The type that the future's `Vec<u8>` output is a serialization of, if any. See
+    // `CxxAsyncPayload`.
+    payload: Option<Type>,
 }
 
 impl AstPieces {
     // Parses the macro arguments and returns the pieces, returning a `syn::Error` on error.
     fn from_token_streams(attribute: TokenStream, item: TokenStream) -> SynResult<AstPieces> {
-        let namespace: NamespaceAttribute = syn::parse(attribute).map_err(|error| {
-            SynError::new(error.span(), "expected possible namespace attribute")
+        let BridgeAttributes {
+            namespace,
+            c_abi,
+            payload,
+        } = syn::parse(attribute).map_err(|error| {
+            SynError::new(
+                error.span(),
+                "expected possible namespace, c_abi, or payload attribute",
+            )
         })?;
 
         let impl_item: ItemImpl = syn::parse(item).map_err(|error| {
@@ -525,7 +943,6 @@ impl AstPieces {
             &format!(
                 "{}{}",
                 namespace
-                    .0
                     .iter()
                     .map(|piece| format!("{}::", piece))
                     .collect::<String>(),
@@ -538,7 +955,6 @@ impl AstPieces {
             &format!(
                 "cxxasync_{}{}_vtable",
                 namespace
-                    .0
                     .iter()
                     .map(|piece| format!("{}_", piece))
                     .collect::<String>(),
@@ -549,13 +965,25 @@ impl AstPieces {
         let vtable_glue_link_name = format!(
             "cxxasync_{}{}_vtable",
             namespace
-                .0
                 .iter()
                 .map(|piece| format!("{}$", piece))
                 .collect::<String>(),
             future
         );
 
+        if let (Some(c_abi), BridgeTrait::Stream) = (&c_abi, &bridge_trait) {
+            return Err(SynError::new(
+                c_abi.span(),
+                "the `c_abi` attribute is only supported for futures",
+            ));
+        }
+        if let (Some(payload), BridgeTrait::Stream) = (&payload, &bridge_trait) {
+            return Err(SynError::new(
+                payload.span(),
+                "the `payload` attribute is only supported for futures",
+            ));
+        }
+
         Ok(AstPieces {
             bridge_trait,
             future,
@@ -564,6 +992,8 @@ impl AstPieces {
             trait_path,
             vtable_glue_ident,
             vtable_glue_link_name,
+            c_abi,
+            payload,
         })
     }
 }
@@ -571,23 +1001,48 @@ impl AstPieces {
 mod keywords {
     use syn::custom_keyword;
     custom_keyword!(namespace);
+    custom_keyword!(c_abi);
+    custom_keyword!(payload);
 }
 
-struct NamespaceAttribute(Vec<String>);
+// The arguments to the `#[bridge]` attribute: `namespace = ...`, `c_abi = ...`, and/or
+// `payload = ...`, separated by commas.
+struct BridgeAttributes {
+    namespace: Vec<String>,
+    c_abi: Option<Ident>,
+    payload: Option<Type>,
+}
 
-impl Parse for NamespaceAttribute {
+impl Parse for BridgeAttributes {
     fn parse(input: ParseStream) -> ParseResult<Self> {
-        if input.is_empty() {
-            return Ok(NamespaceAttribute(vec![]));
-        }
-        input.parse::<keywords::namespace>()?;
-        input.parse::<Token![=]>()?;
-        let path = input.call(Path::parse_mod_style)?;
-        Ok(NamespaceAttribute(
-            path.segments
-                .iter()
-                .map(|segment| segment.ident.to_string())
-                .collect(),
-        ))
+        let mut attributes = BridgeAttributes {
+            namespace: vec![],
+            c_abi: None,
+            payload: None,
+        };
+        while !input.is_empty() {
+            if input.peek(keywords::c_abi) {
+                input.parse::<keywords::c_abi>()?;
+                input.parse::<Token![=]>()?;
+                attributes.c_abi = Some(input.parse()?);
+            } else if input.peek(keywords::payload) {
+                input.parse::<keywords::payload>()?;
+                input.parse::<Token![=]>()?;
+                attributes.payload = Some(input.parse()?);
+            } else {
+                input.parse::<keywords::namespace>()?;
+                input.parse::<Token![=]>()?;
+                let path = input.call(Path::parse_mod_style)?;
+                attributes.namespace = path
+                    .segments
+                    .iter()
+                    .map(|segment| segment.ident.to_string())
+                    .collect();
+            }
+            if !input.is_empty() {
+                input.parse::<Token![,]>()?;
+            }
+        }
+        Ok(attributes)
     }
 }